@@ -1,7 +1,8 @@
 #![feature(test)]
 extern crate test;
 use compiler::compiler::compiler::Compiler;
-use compiler::utils::compile;
+use compiler::register::vm::run_program;
+use compiler::utils::{compile, compile_register};
 use compiler::vm::vm::{run_vm, VM};
 use test::Bencher;
 
@@ -11,39 +12,165 @@ fn run_benchmark(b: &mut Bencher, input: &str) {
     b.iter(|| run_vm(&bytecode));
 }
 
+/// The same program on the register vm.
+fn run_register_benchmark(b: &mut Bencher, input: &str) {
+    let program = compile_register(&input).unwrap();
+    b.iter(|| run_program(&program));
+}
+
+fn addition() -> &'static str {
+    "1 + 2"
+}
+
 #[bench]
 fn bench_addition(b: &mut Bencher) {
-    let input = "1 + 2";
-    run_benchmark(b, &input);
+    run_benchmark(b, addition());
+}
+
+#[bench]
+fn bench_register_addition(b: &mut Bencher) {
+    run_register_benchmark(b, addition());
+}
+
+fn conditional() -> &'static str {
+    "if (1 > 2) { 10 } else { 20 }"
 }
 
 #[bench]
 fn bench_conditional(b: &mut Bencher) {
-    let input = "if (1 > 2) { 10 } else { 20 }";
-    run_benchmark(b, &input);
+    run_benchmark(b, conditional());
 }
 
 #[bench]
-fn bench_constant_stacking(b: &mut Bencher) {
+fn bench_register_conditional(b: &mut Bencher) {
+    run_register_benchmark(b, conditional());
+}
+
+fn constant_stacking() -> String {
     let mut input = "".to_string();
     for i in 0..100 {
         input.push_str(&format!("{};", i))
     }
-    run_benchmark(b, &input);
+    input
 }
 
 #[bench]
-fn bench_array_literal(b: &mut Bencher) {
+fn bench_constant_stacking(b: &mut Bencher) {
+    run_benchmark(b, &constant_stacking());
+}
+
+#[bench]
+fn bench_register_constant_stacking(b: &mut Bencher) {
+    run_register_benchmark(b, &constant_stacking());
+}
+
+fn array_literal() -> String {
     let mut input = "[".to_string();
     for i in 0..100 {
         input.push_str(&format!("{}, ", i))
     }
     input.push_str("1]");
-    run_benchmark(b, &input);
+    input
+}
+
+#[bench]
+fn bench_array_literal(b: &mut Bencher) {
+    run_benchmark(b, &array_literal());
+}
+
+#[bench]
+fn bench_register_array_literal(b: &mut Bencher) {
+    run_register_benchmark(b, &array_literal());
+}
+
+fn assignment() -> &'static str {
+    "let one = 1; let two = one + one; one + two"
 }
 
 #[bench]
 fn bench_assignment(b: &mut Bencher) {
-    let input = "let one = 1; let two = one + one; one + two";
-    run_benchmark(b, &input)
+    run_benchmark(b, assignment());
+}
+
+#[bench]
+fn bench_register_assignment(b: &mut Bencher) {
+    run_register_benchmark(b, assignment());
+}
+
+fn fibonacci() -> &'static str {
+    "\
+    let fibonacci = fn(x) {
+  if (x == 0) {
+    0
+  } else {
+    if (x == 1) {
+      return 1;
+    } else {
+      fibonacci(x - 1) + fibonacci(x - 2);
+    }
+  }
+}; fibonacci(20);
+    "
+}
+
+#[bench]
+fn bench_fibonacci(b: &mut Bencher) {
+    run_benchmark(b, fibonacci());
+}
+
+#[bench]
+fn bench_register_fibonacci(b: &mut Bencher) {
+    run_register_benchmark(b, fibonacci());
+}
+
+fn fibonacci_25() -> &'static str {
+    "let fibonacci = fn(x) { if (x < 2) { x } else { fibonacci(x - 1) + fibonacci(x - 2) } };
+fibonacci(25)"
+}
+
+#[bench]
+fn bench_fibonacci_25(b: &mut Bencher) {
+    run_benchmark(b, fibonacci_25());
+}
+
+#[bench]
+fn bench_register_fibonacci_25(b: &mut Bencher) {
+    run_register_benchmark(b, fibonacci_25());
+}
+
+fn method_calls() -> &'static str {
+    "let h = {};
+let f = fn(n) { if (n == 0) { 0 } else { h.insert(n, n); f(n - 1) } };
+f(200)"
+}
+
+#[bench]
+fn bench_method_calls(b: &mut Bencher) {
+    run_benchmark(b, method_calls());
+}
+
+#[bench]
+fn bench_register_method_calls(b: &mut Bencher) {
+    run_register_benchmark(b, method_calls());
+}
+
+fn large_function_calls() -> String {
+    let mut body = "".to_string();
+    for i in 0..100 {
+        body.push_str(&format!("{};", i))
+    }
+    format!(
+        "let f = fn(n) {{ if (n == 0) {{ 0 }} else {{ {} f(n - 1) }} }}; f(500)",
+        body
+    )
+}
+
+#[bench]
+fn bench_large_function_calls(b: &mut Bencher) {
+    run_benchmark(b, &large_function_calls());
+}
+
+#[bench]
+fn bench_register_large_function_calls(b: &mut Bencher) {
+    run_register_benchmark(b, &large_function_calls());
 }
@@ -1,7 +1,8 @@
+use crate::err::VerifyError;
 use num_enum::{TryFromPrimitive, UnsafeFromPrimitive};
 use std::collections::HashMap;
 use std::convert::From;
-use std::convert::TryInto;
+use std::convert::{TryFrom, TryInto};
 use std::fmt::Write;
 
 pub type Instructions = Vec<u8>;
@@ -12,30 +13,41 @@ pub type Operand = usize;
 )]
 #[repr(u8)]
 pub enum OpCode {
-    Constant,      // 0 Operand: constants pool location
-    Add,           // 1 No operand. Take two values from the stack.
-    Pop,           // 2 Pop last element from stack. No operands.
-    Sub,           // 3 No operand. Take two values from the stack.
-    Mul,           // 4 No operand. Take two values from the stack.
-    Div,           // 5 No operand. Take two values from the stack.
-    True,          // 6
-    False,         // 7
-    Equal,         // 8 No operand. Take two values from the stack.
-    NotEqual,      // 9 No operand. Take two values from the stack.
-    GT,            // 10 No operand. Take two values from the stack.
-    Minus,         // 11 '-' prefix. No operands. One value from the stack.
-    Bang,          // 12 '!' prefix. No operands. One value from the stack.
-    JumpNotTruthy, // 13 Operand: jump offset.
-    Jump,          // 14 Operand: jump offset.
-    Null,          // 15 No operand.
-    SetGlobal,     // 16 Operand: index. One value from the stack.
-    GetGlobal,     // 17 Operand: index.
-    Array,         // 18 Operand: Number of elements in the array.
-    Call,          // 19 No operand. Function is on the stack.
-    ReturnVal,     // 20 No operand. One value from the stack.
-    Return,        // 21 No operand.
-    SetLocal,      // 22 Operand: index. One value from the stack.
-    GetLocal,      // 23 Operand: index.
+    Constant,        // 0 Operand: constants pool location
+    Add,             // 1 No operand. Take two values from the stack.
+    Pop,             // 2 Pop last element from stack. No operands.
+    Sub,             // 3 No operand. Take two values from the stack.
+    Mul,             // 4 No operand. Take two values from the stack.
+    Div,             // 5 No operand. Take two values from the stack.
+    True,            // 6
+    False,           // 7
+    Equal,           // 8 No operand. Take two values from the stack.
+    NotEqual,        // 9 No operand. Take two values from the stack.
+    GT,              // 10 No operand. Take two values from the stack.
+    Minus,           // 11 '-' prefix. No operands. One value from the stack.
+    Bang,            // 12 '!' prefix. No operands. One value from the stack.
+    JumpNotTruthy,   // 13 Operand: jump offset.
+    Jump,            // 14 Operand: jump offset.
+    Null,            // 15 No operand.
+    SetGlobal,       // 16 Operand: index. One value from the stack.
+    GetGlobal,       // 17 Operand: index.
+    Array,           // 18 Operand: Number of elements in the array.
+    Call,            // 19 Operand: number of arguments. Function and args on the stack.
+    ReturnVal,       // 20 No operand. One value from the stack.
+    Return,          // 21 No operand.
+    SetLocal,        // 22 Operand: index. One value from the stack.
+    GetLocal,        // 23 Operand: index.
+    CurrentFunction, // 24 No operand. Pushes the function that is being executed.
+    GetBuiltin,      // 25 Operand: index of the builtin.
+    Hash,            // 26 Operand: Number of keys and values in the hash.
+    Index,           // 27 No operand. Take the object and the index from the stack.
+    CallMethod,      // 28 Operands: constants pool location of the name, number of args.
+    Zero,            // 29 No operand. Pushes the int 0.
+    One,             // 30 No operand. Pushes the int 1.
+    SmallInt,        // 31 Operand: int from 0 to 255.
+    AddLocals,       // 32 Operands: index of two locals. GetLocal, GetLocal, Add in one.
+    Wide,            // 33 Prefix. The operands of the next opcode are twice as wide.
+    Ignore,          // 34 No operand. Pushes the value of a let statement.
 }
 
 impl OpCode {
@@ -45,64 +57,221 @@ impl OpCode {
     pub fn definition(&self) -> &'static [usize] {
         use OpCode::*;
         match self {
-            Constant | JumpNotTruthy | Jump | SetGlobal | GetGlobal | Array => &[2],
-            SetLocal | GetLocal => &[1],
+            Constant | JumpNotTruthy | Jump | SetGlobal | GetGlobal | Array | Hash => {
+                &[2]
+            }
+            CallMethod => &[2, 1],
+            AddLocals => &[1, 1],
+            SetLocal | GetLocal | Call | GetBuiltin | SmallInt => &[1],
             _ => &[], // all opcodes wo/ operands
         }
     }
 
+    /// Operator in the source that compiles to this opcode. Used in error messages.
+    pub fn symbol(&self) -> &'static str {
+        use OpCode::*;
+        match self {
+            Add | AddLocals => "+",
+            Sub | Minus => "-",
+            Mul => "*",
+            Div => "/",
+            Equal => "==",
+            NotEqual => "!=",
+            GT => ">",
+            Bang => "!",
+            Index => "[]",
+            _ => "",
+        }
+    }
+
+    /// Whether an operand does not fit its width, such that the instruction is encoded
+    /// behind a `Wide` prefix.
+    pub fn needs_wide(&self, operands: &[Operand]) -> bool {
+        self.definition()
+            .iter()
+            .zip(operands)
+            .any(|(width, operand)| !fits(*operand, *width))
+    }
+
+    /// Largest value of the operand at `index`, which is encoded behind a `Wide` prefix.
+    pub fn max_operand(&self, index: usize) -> Operand {
+        let width = 2 * self.definition()[index];
+        if width >= std::mem::size_of::<Operand>() {
+            Operand::MAX
+        } else {
+            (1 << (8 * width)) - 1
+        }
+    }
+
+    /// Width of the instruction, including the `Wide` prefix.
+    pub fn width(&self, wide: bool) -> usize {
+        let operands = self.definition().iter().sum::<usize>();
+        if wide {
+            2 + 2 * operands
+        } else {
+            1 + operands
+        }
+    }
+
+    /// Encode the instruction. If an operand does not fit its width, the instruction
+    /// gets a `Wide` prefix, which doubles the width of all its operands.
     pub fn make(&self, operands: &[Operand]) -> Instructions {
-        let mut instr = self.as_byte().to_be_bytes().to_vec();
+        let wide = self.needs_wide(operands);
+        let mut instr = Vec::with_capacity(self.width(wide));
+        if wide {
+            instr.push(OpCode::Wide.as_byte());
+        }
+        instr.push(self.as_byte());
 
         let op_width = self.definition();
         for (i, operand) in operands.iter().enumerate() {
-            let width = op_width[i];
-            match width {
-                2 => instr.extend_from_slice(&(*operand as u16).to_be_bytes()),
-                1 => instr.push(*operand as u8),
-                _ => panic!("not impl"),
-            }
+            let width = if wide { 2 * op_width[i] } else { op_width[i] };
+            assert!(
+                fits(*operand, width),
+                "operand {} of {:?} does not fit in {} bytes",
+                operand,
+                self,
+                width
+            );
+            let bytes = operand.to_be_bytes();
+            instr.extend_from_slice(&bytes[bytes.len() - width..]);
         }
         instr
     }
 
     /// instructions slice starting just behind the OpCode
     /// returns operand and width of operand
-    pub fn read_operand(&self, instructions: &[u8]) -> (Operand, usize) {
-        use OpCode::*;
-        match self {
-            Constant | JumpNotTruthy | Jump | SetGlobal | GetGlobal | Array => {
-                (read_be_u16(&instructions[..2]) as usize, 2)
-            }
-            SetLocal | GetLocal => (instructions[0] as usize, 1),
-            _ => panic!("no operand after opcode!"),
-        }
+    pub fn read_operand(&self, instructions: &[u8], wide: bool) -> (Operand, usize) {
+        let width = match self.definition().first() {
+            Some(width) if wide => 2 * width,
+            Some(width) => *width,
+            None => panic!("no operand after opcode!"),
+        };
+        (read_be(&instructions[..width]), width)
     }
 }
 
-pub fn read_operands(op_width: &[usize], ins: &[u8]) -> (Vec<Operand>, usize) {
+fn fits(operand: Operand, width: usize) -> bool {
+    width >= std::mem::size_of::<Operand>() || operand >> (8 * width) == 0
+}
+
+fn read_be(bytes: &[u8]) -> Operand {
+    bytes.iter().fold(0, |n, byte| n << 8 | *byte as Operand)
+}
+
+/// ins slice starting at the OpCode, behind the `Wide` prefix of a wide instruction
+/// returns the operands and the number of bytes read, including the OpCode
+pub fn read_operands(
+    op_width: &[usize],
+    ins: &[u8],
+    wide: bool,
+) -> (Vec<Operand>, usize) {
     let mut operands = vec![];
     let mut offset = 1; // first one is opcode
-    for (i, width) in op_width.iter().enumerate() {
-        match width {
-            2 => operands.push(read_be_u16(&ins[offset..]) as usize),
-            1 => operands.push(ins[offset] as usize),
-            _ => panic!("not impl"),
-        }
-        offset += *width;
+    for width in op_width.iter() {
+        let width = if wide { 2 * width } else { *width };
+        operands.push(read_be(&ins[offset..offset + width]));
+        offset += width;
     }
     (operands, offset)
 }
 
+/// A decoded instruction and its offset in the instructions.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Instruction {
+    pub offset: usize,
+    pub oc: OpCode,
+    pub operands: Vec<Operand>,
+}
+
+impl Instruction {
+    /// Width of the opcode and its operands, including a `Wide` prefix.
+    pub fn width(&self) -> usize {
+        self.oc.width(self.is_wide())
+    }
+
+    /// Whether the instruction is encoded behind a `Wide` prefix.
+    pub fn is_wide(&self) -> bool {
+        self.oc.needs_wide(&self.operands)
+    }
+
+    /// Number of objects the instruction pops from and pushes on the stack.
+    pub fn stack_effect(&self) -> (usize, usize) {
+        use OpCode::*;
+        match self.oc {
+            Constant | True | False | Null | GetGlobal | GetLocal | CurrentFunction
+            | GetBuiltin | Zero | One | SmallInt | AddLocals | Ignore => (0, 1),
+            Add | Sub | Mul | Div | Equal | NotEqual | GT | Index => (2, 1),
+            Minus | Bang => (1, 1),
+            Pop | JumpNotTruthy | SetGlobal | SetLocal => (1, 0),
+            Jump | Return => (0, 0),
+            ReturnVal => (1, 0),
+            Wide => (0, 0), // never decoded on its own
+            Array | Hash => (self.operands[0], 1),
+            Call => (self.operands[0] + 1, 1),
+            CallMethod => (self.operands[1] + 1, 1),
+        }
+    }
+}
+
+/// Decode the instructions one by one. Unknown opcodes, missing operands and `Wide`
+/// prefixes that are not needed end the iteration with an error.
+pub fn decode(
+    instructions: &[u8],
+) -> impl Iterator<Item = Result<Instruction, (usize, VerifyError)>> + '_ {
+    let mut offset = 0;
+    std::iter::from_fn(move || {
+        if offset >= instructions.len() {
+            return None;
+        }
+        let wide = instructions[offset] == OpCode::Wide.as_byte();
+        let start = if wide { offset + 1 } else { offset };
+        let result = match instructions.get(start).map(|b| (*b, OpCode::try_from(*b))) {
+            None => Err((offset, VerifyError::TruncatedInstruction(OpCode::Wide))),
+            Some((byte, Err(_))) => Err((offset, VerifyError::InvalidOpCode(byte))),
+            Some((_, Ok(oc))) if wide && oc.definition().is_empty() => {
+                Err((offset, VerifyError::UnexpectedWide(oc)))
+            }
+            Some((_, Ok(oc))) if offset + oc.width(wide) > instructions.len() => {
+                Err((offset, VerifyError::TruncatedInstruction(oc)))
+            }
+            Some((_, Ok(oc))) => {
+                let (operands, n_read) =
+                    read_operands(oc.definition(), &instructions[start..], wide);
+                if wide && !oc.needs_wide(&operands) {
+                    Err((offset, VerifyError::UnexpectedWide(oc)))
+                } else {
+                    let ins = Instruction {
+                        offset,
+                        oc,
+                        operands,
+                    };
+                    offset = start + n_read;
+                    return Some(Ok(ins));
+                }
+            }
+        };
+        offset = instructions.len(); // stop after the first error
+        Some(result)
+    })
+}
+
 fn fmt_disassemble(ins: &[u8]) -> String {
     let mut s = "".to_string();
-    let mut c = 0;
-    while c < ins.len() {
-        let opcode = unsafe { OpCode::from_unchecked(ins[c]) };
-        let (operands, n_read) = read_operands(&opcode.definition(), &ins[c..]);
-        writeln!(&mut s, "{:04} opcode: {:?} {:?}", c, opcode, operands);
-
-        c += n_read;
+    for ins in decode(ins) {
+        match ins {
+            Ok(ins) if ins.is_wide() => writeln!(
+                &mut s,
+                "{:04} opcode: Wide {:?} {:?}",
+                ins.offset, ins.oc, ins.operands
+            ),
+            Ok(ins) => writeln!(
+                &mut s,
+                "{:04} opcode: {:?} {:?}",
+                ins.offset, ins.oc, ins.operands
+            ),
+            Err((offset, e)) => writeln!(&mut s, "{:04} error: {}", offset, e),
+        };
     }
     s
 }
@@ -131,7 +300,7 @@ mod test {
         assert_eq!([0, 255, 254], OpCode::Constant.make(&[operand])[..]);
 
         let ins = OpCode::Constant.make(&[operand]);
-        let r = read_operands(&OpCode::Constant.definition(), &ins);
+        let r = read_operands(&OpCode::Constant.definition(), &ins, false);
         assert_eq!(operand, r.0[0]);
 
         let s = fmt_instructions(
@@ -162,4 +331,65 @@ mod test {
             s
         )
     }
+
+    #[test]
+    fn test_wide_operands() {
+        let ins = OpCode::Constant.make(&[65536]);
+        assert_eq!(
+            [OpCode::Wide as u8, OpCode::Constant as u8, 0, 1, 0, 0],
+            ins[..]
+        );
+        assert_eq!((65536, 4), OpCode::Constant.read_operand(&ins[2..], true));
+
+        let ins = OpCode::CallMethod.make(&[70000, 300]);
+        assert_eq!(
+            (vec![70000, 300], 7),
+            read_operands(OpCode::CallMethod.definition(), &ins[1..], true)
+        );
+
+        let s = fmt_instructions(
+            &[
+                OpCode::GetLocal,
+                OpCode::GetLocal,
+                OpCode::Jump,
+                OpCode::Pop,
+            ],
+            &[&[255], &[256], &[65535], &[]],
+        );
+        assert_eq!(
+            "0000 opcode: GetLocal [255]
+0002 opcode: Wide GetLocal [256]
+0006 opcode: Jump [65535]
+0009 opcode: Pop []
+",
+            s
+        );
+    }
+
+    #[test]
+    fn test_unexpected_wide() {
+        let wide = OpCode::Wide as u8;
+        let cases: Vec<(Vec<u8>, VerifyError)> = vec![
+            (vec![wide], VerifyError::TruncatedInstruction(OpCode::Wide)),
+            (vec![wide, 255], VerifyError::InvalidOpCode(255)),
+            (
+                vec![wide, OpCode::Pop as u8],
+                VerifyError::UnexpectedWide(OpCode::Pop),
+            ),
+            (vec![wide, wide], VerifyError::UnexpectedWide(OpCode::Wide)),
+            (
+                vec![wide, OpCode::Constant as u8, 0, 0],
+                VerifyError::TruncatedInstruction(OpCode::Constant),
+            ),
+            // a wide prefix is only used for operands that do not fit otherwise
+            (
+                vec![wide, OpCode::Constant as u8, 0, 0, 0, 1],
+                VerifyError::UnexpectedWide(OpCode::Constant),
+            ),
+        ];
+        for (instructions, error) in cases {
+            let decoded: Vec<_> = decode(&instructions).collect();
+            assert_eq!(decoded, vec![Err((0, error))]);
+        }
+    }
 }
@@ -1,8 +1,16 @@
-use crate::code::{Instructions, OpCode, Operand};
-use crate::compiler::symbol_table::{Scope, SymbolTable};
-use monkey::eval::object::Object;
+use crate::code::{decode, Instructions, OpCode, Operand};
+use crate::compiler::fold::{fold_program, literal_truthiness};
+use crate::compiler::interner::Interner;
+use crate::compiler::peephole::{optimize, relocate};
+use crate::compiler::symbol_table::{Scope, Symbol, SymbolTable};
+use crate::err::CompileError;
+use crate::vm::vm::MAX_GLOBALS;
+use monkey::eval::builtins::BUILTIN_LIST;
+use monkey::eval::object::{CompiledFunction, LineTable, Object};
+use monkey::lexer::token::Span;
 use monkey::parser::ast::{Expression, Statement};
 use std::cell::{Ref, RefCell, RefMut};
+use std::collections::HashMap;
 use std::convert::TryFrom;
 use std::rc::Rc;
 use std::str::Bytes;
@@ -11,6 +19,16 @@ use std::str::Bytes;
 pub struct Bytecode<'cmpl> {
     pub instructions: &'cmpl [u8],
     pub constants: &'cmpl [Object],
+    pub line_table: &'cmpl LineTable,
+    pub num_globals: usize,
+}
+
+/// How much the compiler optimizes the program.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum OptLevel {
+    O0, // compile the program as written
+    O1, // fold constant expressions and drop branches that are never taken
+    O2, // O1 and rewrite the instructions with the peephole optimizer
 }
 
 #[derive(Debug)]
@@ -21,34 +39,90 @@ struct EmittedInstruction {
 
 struct CompilationScope {
     instructions: Instructions,
+    line_table: LineTable,
     last_instruction: Option<EmittedInstruction>,
     before_last_instruction: Option<EmittedInstruction>,
+    // Position and target of jumps whose target does not fit the placeholder operand.
+    long_jumps: Vec<(usize, Operand)>,
 }
 
 impl CompilationScope {
     pub fn new() -> CompilationScope {
         CompilationScope {
             instructions: vec![],
+            line_table: LineTable::new(),
             last_instruction: None,
             before_last_instruction: None,
+            long_jumps: vec![],
+        }
+    }
+
+    /// Patch in the targets of the long jumps. Their wide operands move the
+    /// instructions behind them, so the other jumps are patched as well.
+    fn place_long_jumps(&mut self) {
+        if self.long_jumps.is_empty() {
+            return;
         }
+        let mut code = decode(&self.instructions)
+            .collect::<Result<Vec<_>, _>>()
+            .expect("the compiler emits valid instructions");
+        for (position, target) in self.long_jumps.drain(..) {
+            let i = code
+                .binary_search_by_key(&position, |ins| ins.offset)
+                .expect("a long jump is an instruction");
+            code[i].operands[0] = target;
+        }
+        let (instructions, line_table) = relocate(&code, &self.line_table);
+        self.instructions = instructions;
+        self.line_table = line_table;
+        // The emitted instructions have moved.
+        self.last_instruction = None;
+        self.before_last_instruction = None;
     }
 }
 
+/// Wide constant operands are four bytes wide.
+pub const MAX_CONSTANTS: usize = u32::MAX as usize + 1;
+
 pub struct Compiler {
     scopes: Vec<CompilationScope>,
     scope_index: usize,
     constants: Vec<Object>,
+    // Ints and strings are added to the constants pool only once.
+    interned: HashMap<Object, usize>,
+    // Identifiers and strings are stored once.
+    interner: Interner,
     symbol_table: Option<Rc<RefCell<SymbolTable>>>,
+    span: Span, // location of the statement that is being compiled
+    opt_level: OptLevel,
 }
 
 impl Compiler {
     pub fn new() -> Compiler {
+        let symbol_table = SymbolTable::new();
+        let mut interner = Interner::new();
+        {
+            let mut tbl = symbol_table.borrow_mut();
+            for (i, builtin) in BUILTIN_LIST.iter().enumerate() {
+                tbl.define_builtin(i, interner.intern(&builtin.identifier));
+            }
+        }
         Compiler {
             scopes: vec![CompilationScope::new()],
             scope_index: 0,
             constants: vec![],
-            symbol_table: Some(SymbolTable::new()),
+            interned: HashMap::new(),
+            interner,
+            symbol_table: Some(symbol_table),
+            span: Span::default(),
+            opt_level: OptLevel::O0,
+        }
+    }
+
+    pub fn with_opt_level(opt_level: OptLevel) -> Compiler {
+        Compiler {
+            opt_level,
+            ..Compiler::new()
         }
     }
 
@@ -65,58 +139,124 @@ impl Compiler {
         self.symbol_table = Some(tmp);
     }
 
-    fn leave_scope(&mut self) -> Vec<u8> {
+    fn leave_scope(&mut self) -> CompilationScope {
         // Todo: instead of rc clone take ownership
         let tmp = self.symbol_table.take().unwrap();
         let outer = tmp.borrow().outer.as_ref().unwrap().clone();
         self.symbol_table = Some(outer);
         self.scope_index -= 1;
-        self.scopes.pop().unwrap().instructions
+        self.scopes.pop().unwrap()
     }
 
-    pub fn bytecode(&self) -> Bytecode {
+    pub fn bytecode(&self) -> Bytecode<'_> {
         Bytecode {
             instructions: self.current_instructions(),
             constants: &self.constants,
+            line_table: &self.scopes[self.scope_index].line_table,
+            num_globals: self.get_symbol_table().num_definitions(),
         }
     }
 
-    pub fn compile_program(&mut self, program: &[Statement]) {
+    /// Compile all statements. Compilation continues after a statement that fails to
+    /// compile, such that all errors are reported.
+    pub fn compile_program(
+        &mut self,
+        program: &[Statement],
+    ) -> Result<Bytecode<'_>, Vec<CompileError>> {
+        let folded;
+        let program = if self.opt_level >= OptLevel::O1 {
+            folded = fold_program(program);
+            &folded[..]
+        } else {
+            program
+        };
+        // Predeclare the globals so that functions can use the ones that are defined
+        // behind them, like in the evaluator. Reading a global before its let statement
+        // ran is an error when the program runs.
+        for stmt in program {
+            if let Statement::Let(identifier, ..) = stmt {
+                self.define(identifier);
+            }
+        }
+        let mut errors = vec![];
         for stmt in program {
-            self.compile_stmt(stmt)
+            if let Err(e) = self.compile_stmt(stmt) {
+                errors.push(e)
+            }
+        }
+        if program.last().is_some_and(ends_with_let) {
+            self.emit(OpCode::Ignore, &[]);
+            self.emit(OpCode::Pop, &[]);
+        }
+        if errors.is_empty() {
+            self.scopes[self.scope_index].place_long_jumps();
+            if self.opt_level >= OptLevel::O2 {
+                let scope = &mut self.scopes[self.scope_index];
+                let (instructions, line_table) = optimize(
+                    &scope.instructions,
+                    &scope.line_table,
+                    &self.constants,
+                    false,
+                );
+                scope.instructions = instructions;
+                scope.line_table = line_table;
+                // The emitted instructions have moved.
+                scope.last_instruction = None;
+                scope.before_last_instruction = None;
+            }
+            Ok(self.bytecode())
+        } else {
+            Err(errors)
         }
     }
 
-    fn compile_stmt(&mut self, stmt: &Statement) {
+    fn compile_stmt(&mut self, stmt: &Statement) -> Result<(), CompileError> {
+        // Expressions are reported at the location of the statement they are part of.
+        let outer_span = self.span;
+        if let Some(span) = stmt.span() {
+            self.span = span;
+        }
+        let result = self.compile_stmt_inner(stmt);
+        self.span = outer_span;
+        result
+    }
+
+    fn compile_stmt_inner(&mut self, stmt: &Statement) -> Result<(), CompileError> {
         match stmt {
-            Statement::Expr(expr) => {
-                self.compile_expr(expr);
+            Statement::Expr(expr, _) => {
+                self.compile_expr(expr)?;
                 self.emit(OpCode::Pop, &[]);
             }
             Statement::Block(stmts) => {
                 for stmt in stmts.iter() {
-                    self.compile_stmt(stmt);
+                    self.compile_stmt(stmt)?;
                 }
             }
-            Statement::Let(identifier, expr) => {
-                self.compile_expr(expr);
-                let smbl = self.get_symbol_table_mut().define(identifier.to_string());
+            Statement::Let(identifier, expr, _) => {
+                match expr {
+                    Expression::FunctionLiteral { parameters, body } => {
+                        self.compile_function_literal(parameters, body, Some(identifier))?
+                    }
+                    _ => self.compile_expr(expr)?,
+                }
+                let smbl = self.define(identifier);
 
                 let index = smbl.index;
                 match smbl.scope {
-                    Scope::Global => self.emit(OpCode::SetGlobal, &[index]),
-                    Scope::Local => self.emit(OpCode::SetLocal, &[index]),
+                    Scope::Global => self.emit_global(OpCode::SetGlobal, index)?,
+                    Scope::Local => self.emit_sized(OpCode::SetLocal, &[index])?,
+                    Scope::Function | Scope::Builtin | Scope::Captured => unreachable!(),
                 };
             }
-            Statement::Return(expr) => {
-                self.compile_expr(expr);
+            Statement::Return(expr, _) => {
+                self.compile_expr(expr)?;
                 self.emit(OpCode::ReturnVal, &[]);
             }
-            _ => panic!(format!("{:?} not catched", stmt)),
         }
+        Ok(())
     }
 
-    fn compile_expr(&mut self, expr: &Expression) {
+    fn compile_expr(&mut self, expr: &Expression) -> Result<(), CompileError> {
         match expr {
             Expression::Infix {
                 left,
@@ -125,11 +265,11 @@ impl Compiler {
             } => {
                 // Reverse the constants to flip GT behavior to LT
                 if operator == "<" {
-                    self.compile_expr(right);
-                    self.compile_expr(left);
+                    self.compile_expr(right)?;
+                    self.compile_expr(left)?;
                 } else {
-                    self.compile_expr(left);
-                    self.compile_expr(right);
+                    self.compile_expr(left)?;
+                    self.compile_expr(right)?;
                 }
                 match &operator[..] {
                     "+" => {
@@ -156,12 +296,17 @@ impl Compiler {
                     "!=" => {
                         self.emit(OpCode::NotEqual, &[]);
                     }
-                    _ => panic!("Operand not known"),
+                    op => {
+                        return Err(CompileError::UnknownOperator(
+                            op.to_string(),
+                            self.span,
+                        ))
+                    }
                 }
             }
             Expression::IntegerLiteral(v) => {
                 let int = Object::Int(*v);
-                let op = self.add_constant(int);
+                let op = self.add_constant(int)?;
                 self.emit(OpCode::Constant, &[op]);
             }
             Expression::Bool(v) => {
@@ -172,7 +317,7 @@ impl Compiler {
                 }
             }
             Expression::Prefix { operator, expr } => {
-                self.compile_expr(expr);
+                self.compile_expr(expr)?;
                 match &operator[..] {
                     "-" => {
                         self.emit(OpCode::Minus, &[]);
@@ -180,7 +325,12 @@ impl Compiler {
                     "!" => {
                         self.emit(OpCode::Bang, &[]);
                     }
-                    _ => panic!(),
+                    op => {
+                        return Err(CompileError::UnknownOperator(
+                            op.to_string(),
+                            self.span,
+                        ))
+                    }
                 }
             }
             Expression::IfExpression {
@@ -188,16 +338,28 @@ impl Compiler {
                 consequence,
                 alternative,
             } => {
-                self.compile_expr(condition);
+                if self.opt_level >= OptLevel::O1 {
+                    if let Some(truthy) = literal_truthiness(condition) {
+                        // Only compile the branch that is taken.
+                        match (truthy, alternative) {
+                            (true, _) => self.compile_branch(consequence)?,
+                            (false, Some(alternative)) => {
+                                self.compile_branch(alternative)?
+                            }
+                            (false, None) => {
+                                self.emit(OpCode::Null, &[]);
+                            }
+                        }
+                        return Ok(());
+                    }
+                }
+                self.compile_expr(condition)?;
                 // start w/ a made jump position 9999
                 // jump if value on the stack is false
                 let pos_jump_not_truthy = self.emit(OpCode::JumpNotTruthy, &[9999]);
 
                 // if true stmt
-                self.compile_stmt(consequence);
-                if self.last_instruction_eq(OpCode::Pop) {
-                    self.remove_last_pop()
-                }
+                self.compile_branch(consequence)?;
 
                 // jump if consequence is executed
                 let pos_jump = self.emit(OpCode::Jump, &[9999]);
@@ -210,10 +372,7 @@ impl Compiler {
                     self.emit(OpCode::Null, &[]);
                 } else {
                     let alternative = alternative.as_ref().unwrap();
-                    self.compile_stmt(&alternative);
-                    if self.last_instruction_eq(OpCode::Pop) {
-                        self.remove_last_pop()
-                    }
+                    self.compile_branch(alternative)?;
                 }
                 let pos_after_alternative = self.current_instructions().len();
                 self.change_operand(pos_jump, pos_after_alternative);
@@ -221,48 +380,185 @@ impl Compiler {
             Expression::Identifier(ident) => {
                 let opt = self.get_symbol_table().resolve(&ident);
                 match opt {
-                    None => panic!(format!("undefined variable: {}", ident)),
+                    None => {
+                        return Err(CompileError::UndefinedVariable(
+                            ident.to_string(),
+                            self.span,
+                        ))
+                    }
                     Some(smbl) => {
                         let index = smbl.index;
                         match smbl.scope {
-                            Scope::Local => self.emit(OpCode::GetLocal, &[index]),
-                            Scope::Global => self.emit(OpCode::GetGlobal, &[index]),
+                            Scope::Local => {
+                                self.emit_sized(OpCode::GetLocal, &[index])?
+                            }
+                            Scope::Global => {
+                                self.emit_global(OpCode::GetGlobal, index)?
+                            }
+                            Scope::Function => self.emit(OpCode::CurrentFunction, &[]),
+                            Scope::Builtin => self.emit(OpCode::GetBuiltin, &[index]),
+                            Scope::Captured => {
+                                return Err(CompileError::Unsupported(
+                                    format!("captured variable: {}", ident),
+                                    self.span,
+                                ))
+                            }
                         };
                     }
                 }
             }
             Expression::StringLiteral(s) => {
-                let obj = Object::from(&s[..]);
-                let op = self.add_constant(obj);
+                let obj = Object::String(self.interner.intern(s));
+                let op = self.add_constant(obj)?;
                 self.emit(OpCode::Constant, &[op]);
             }
             Expression::ArrayLiteral(exprs) => {
                 for expr in exprs.iter() {
-                    self.compile_expr(expr)
+                    self.compile_expr(expr)?;
                 }
-                self.emit(OpCode::Array, &[exprs.len()]);
+                self.emit_sized(OpCode::Array, &[exprs.len()])?;
             }
             Expression::FunctionLiteral { parameters, body } => {
-                self.enter_scope();
-                self.compile_stmt(body);
-                if self.last_instruction_eq(OpCode::Pop) {
-                    self.replace_last_pop_with_return()
+                self.compile_function_literal(parameters, body, None)?;
+            }
+            Expression::CallExpr { function, args } => {
+                self.compile_expr(function)?;
+                for arg in args.iter() {
+                    self.compile_expr(arg)?;
                 }
-                // TODO: Maybe use only if
-                else if !self.last_instruction_eq(OpCode::ReturnVal) {
-                    self.emit(OpCode::Return, &[]);
+                self.emit_sized(OpCode::Call, &[args.len()])?;
+            }
+            Expression::HashLiteral { keys, values } => {
+                for (key, value) in keys.iter().zip(values.iter()) {
+                    self.compile_expr(key)?;
+                    self.compile_expr(value)?;
                 }
-                let instructions = self.leave_scope();
-                let compiled_fn = Object::CompiledFunction(instructions);
-                let pos = self.add_constant(compiled_fn);
-                self.emit(OpCode::Constant, &[pos]);
+                self.emit_sized(OpCode::Hash, &[keys.len() * 2])?;
             }
-            Expression::CallExpr { function, args } => {
-                self.compile_expr(function);
-                self.emit(OpCode::Call, &[]);
+            Expression::IndexExpr { left, index } => {
+                self.compile_expr(left)?;
+                self.compile_expr(index)?;
+                self.emit(OpCode::Index, &[]);
             }
-            _ => panic!(),
+            Expression::Method {
+                left,
+                identifier,
+                args,
+            } => {
+                self.compile_expr(left)?;
+                for arg in args.iter() {
+                    self.compile_expr(arg)?;
+                }
+                let method_name = match &**identifier {
+                    Expression::Identifier(s) => Object::String(self.interner.intern(s)),
+                    ident => {
+                        return Err(CompileError::Unsupported(
+                            format!("method name: {}", ident),
+                            self.span,
+                        ))
+                    }
+                };
+                let pos = self.add_constant(method_name)?;
+                self.emit_sized(OpCode::CallMethod, &[pos, args.len()])?;
+            }
+            _ => {
+                return Err(CompileError::Unsupported(
+                    format!("expression: {}", expr),
+                    self.span,
+                ))
+            }
+        };
+        Ok(())
+    }
+
+    /// Compile a branch of an if expression. The value of its last expression is left
+    /// on the stack, or null if the branch doesn't end with an expression.
+    fn compile_branch(&mut self, block: &Statement) -> Result<(), CompileError> {
+        let start = self.current_instructions().len();
+        self.compile_stmt(block)?;
+        if self.last_instruction_eq(OpCode::Pop)
+            && self.current_instructions().len() > start
+        {
+            self.remove_last_pop()
+        } else if ends_with_let(block) {
+            self.emit(OpCode::Ignore, &[]);
+        } else if !self.last_instruction_eq(OpCode::ReturnVal) {
+            self.emit(OpCode::Null, &[]);
+        }
+        Ok(())
+    }
+
+    /// Compile a function literal. If the function is bound by a let statement, `name`
+    /// is available in its body, so that it can call itself.
+    fn compile_function_literal(
+        &mut self,
+        parameters: &[Expression],
+        body: &Statement,
+        name: Option<&str>,
+    ) -> Result<(), CompileError> {
+        self.enter_scope();
+        if let Some(name) = name {
+            let name = self.interner.intern(name);
+            self.get_symbol_table_mut().define_function_name(name);
+        }
+        for param in parameters.iter() {
+            if let Expression::Identifier(ident) = param {
+                let name = self.interner.intern(ident);
+                self.get_symbol_table_mut().define_parameter(name);
+            }
+        }
+        // The local functions are predeclared like the global ones. A local function
+        // that calls another one then resolves it as a captured variable, which is
+        // reported instead of being undefined.
+        if let Statement::Block(stmts) = body {
+            self.predeclare_functions(stmts);
+        }
+        let result = self.compile_stmt(body);
+        if self.last_instruction_eq(OpCode::Pop) {
+            self.replace_last_pop_with_return()
+        } else if ends_with_let(body) {
+            self.emit(OpCode::Ignore, &[]);
+            self.emit(OpCode::ReturnVal, &[]);
+        }
+        // TODO: Maybe use only if
+        else if !self.last_instruction_eq(OpCode::ReturnVal) {
+            self.emit(OpCode::Return, &[]);
+        }
+        let num_locals = self.get_symbol_table().num_definitions();
+        // Always leave the scope, also on errors. Otherwise the next statements are
+        // compiled in the wrong scope.
+        let mut scope = self.leave_scope();
+        result?;
+        scope.place_long_jumps();
+        let (instructions, line_table) = if self.opt_level >= OptLevel::O2 {
+            optimize(
+                &scope.instructions,
+                &scope.line_table,
+                &self.constants,
+                true,
+            )
+        } else {
+            (scope.instructions, scope.line_table)
         };
+        let compiled_fn = Object::CompiledFunction(Rc::new(CompiledFunction {
+            instructions,
+            num_locals,
+            num_parameters: parameters.len(),
+            line_table,
+        }));
+        let pos = self.add_constant(compiled_fn)?;
+        self.emit(OpCode::Constant, &[pos]);
+        Ok(())
+    }
+
+    fn predeclare_functions(&mut self, stmts: &[Statement]) {
+        for stmt in stmts {
+            if let Statement::Let(identifier, Expression::FunctionLiteral { .. }, _) =
+                stmt
+            {
+                self.define(identifier);
+            }
+        }
     }
 
     fn replace_last_pop_with_return(&mut self) {
@@ -280,16 +576,31 @@ impl Compiler {
             .oc = OpCode::ReturnVal;
     }
 
-    /// returns memory location
-    fn add_constant(&mut self, obj: Object) -> usize {
+    /// returns memory location. Equal ints and strings share one location.
+    fn add_constant(&mut self, obj: Object) -> Result<usize, CompileError> {
+        let internable = matches!(obj, Object::Int(_) | Object::String(_));
+        if internable {
+            if let Some(index) = self.interned.get(&obj) {
+                return Ok(*index);
+            }
+        }
+        if self.constants.len() >= MAX_CONSTANTS {
+            return Err(CompileError::TooManyConstants(self.span));
+        }
+        let index = self.constants.len();
+        if internable {
+            self.interned.insert(obj.clone(), index);
+        }
         self.constants.push(obj);
-        self.constants.len() - 1
+        Ok(index)
     }
 
     fn emit(&mut self, oc: OpCode, operands: &[Operand]) -> usize {
         let ins = oc.make(operands);
         let pos = self.add_instruction(&ins);
 
+        self.scopes[self.scope_index].line_table.add(pos, self.span);
+
         // Keep track of previous instructions
         let last = EmittedInstruction { oc, position: pos };
         self.scopes[self.scope_index].before_last_instruction =
@@ -297,6 +608,41 @@ impl Compiler {
         pos
     }
 
+    /// Emit an instruction whose operands grow with the program, like the index of a
+    /// local or the number of arguments. An operand that does not fit behind a `Wide`
+    /// prefix is an error.
+    fn emit_sized(
+        &mut self,
+        oc: OpCode,
+        operands: &[Operand],
+    ) -> Result<usize, CompileError> {
+        for (i, operand) in operands.iter().enumerate() {
+            let max = oc.max_operand(i);
+            if *operand > max {
+                return Err(CompileError::OperandTooLarge {
+                    op: oc,
+                    operand: *operand,
+                    max,
+                    span: self.span,
+                });
+            }
+        }
+        Ok(self.emit(oc, operands))
+    }
+
+    /// Emit an instruction on a global. The vm has room for `MAX_GLOBALS` globals.
+    fn emit_global(&mut self, oc: OpCode, index: usize) -> Result<usize, CompileError> {
+        if index >= MAX_GLOBALS {
+            return Err(CompileError::OperandTooLarge {
+                op: oc,
+                operand: index,
+                max: MAX_GLOBALS - 1,
+                span: self.span,
+            });
+        }
+        self.emit_sized(oc, &[index])
+    }
+
     fn add_instruction(&mut self, instructions: &[u8]) -> usize {
         // position of start new instructions
         let pos = self.current_instructions().len();
@@ -326,6 +672,7 @@ impl Compiler {
         };
 
         self.scopes[self.scope_index].instructions.drain(pos..);
+        self.scopes[self.scope_index].line_table.truncate(pos);
         let new_last = self.scopes[self.scope_index].before_last_instruction.take();
         self.scopes[self.scope_index]
             .last_instruction
@@ -335,6 +682,13 @@ impl Compiler {
     fn change_operand(&mut self, position: usize, operand: Operand) {
         let oc = OpCode::try_from(self.scopes[self.scope_index].instructions[position])
             .expect("Could not parse opcode");
+        if oc.needs_wide(&[operand]) {
+            // A wide jump does not fit in place, it is patched in when the scope is done.
+            self.scopes[self.scope_index]
+                .long_jumps
+                .push((position, operand));
+            return;
+        }
         let new_instr = oc.make(&[operand]);
         self.replace_instruction(position, new_instr)
     }
@@ -345,6 +699,12 @@ impl Compiler {
         }
     }
 
+    /// Define the name in the current scope.
+    fn define(&mut self, name: &str) -> Symbol {
+        let name = self.interner.intern(name);
+        self.get_symbol_table_mut().define(name)
+    }
+
     fn get_symbol_table_mut(&self) -> RefMut<SymbolTable> {
         self.symbol_table.as_ref().unwrap().borrow_mut()
     }
@@ -352,3 +712,13 @@ impl Compiler {
         self.symbol_table.as_ref().unwrap().borrow()
     }
 }
+
+/// Whether the statement ends with a let statement. The evaluator gives its value,
+/// which is ignored when it is printed, for such a block or program.
+pub fn ends_with_let(stmt: &Statement) -> bool {
+    match stmt {
+        Statement::Let(..) => true,
+        Statement::Block(stmts) => stmts.last().is_some_and(ends_with_let),
+        _ => false,
+    }
+}
@@ -0,0 +1,144 @@
+use monkey::parser::ast::{Expression, Statement};
+use std::rc::Rc;
+
+/// Replace operations on literals by their result, e.g. `1 + 2` by `3` and
+/// `"a" + "b"` by `"ab"`.
+///
+/// Only operations that give the same result as on the vm are folded. Operations that
+/// fail at runtime, like a division by zero, are left for the vm to report.
+pub fn fold_program(program: &[Statement]) -> Vec<Statement> {
+    program.iter().map(fold_stmt).collect()
+}
+
+fn fold_stmt(stmt: &Statement) -> Statement {
+    match stmt {
+        Statement::Let(identifier, expr, span) => {
+            Statement::Let(identifier.clone(), fold_expr(expr), *span)
+        }
+        Statement::Return(expr, span) => Statement::Return(fold_expr(expr), *span),
+        Statement::Expr(expr, span) => Statement::Expr(fold_expr(expr), *span),
+        Statement::Block(stmts) => {
+            Statement::Block(Box::new(stmts.iter().map(fold_stmt).collect()))
+        }
+    }
+}
+
+fn fold_exprs(exprs: &[Expression]) -> Box<Vec<Expression>> {
+    Box::new(exprs.iter().map(fold_expr).collect())
+}
+
+fn fold_expr(expr: &Expression) -> Expression {
+    match expr {
+        Expression::Infix {
+            left,
+            operator,
+            right,
+        } => {
+            let left = fold_expr(left);
+            let right = fold_expr(right);
+            fold_infix(&left, operator, &right).unwrap_or_else(|| Expression::Infix {
+                left: Box::new(left),
+                operator: operator.to_string(),
+                right: Box::new(right),
+            })
+        }
+        Expression::Prefix { operator, expr } => {
+            let expr = fold_expr(expr);
+            fold_prefix(operator, &expr).unwrap_or_else(|| Expression::Prefix {
+                operator: operator.to_string(),
+                expr: Box::new(expr),
+            })
+        }
+        Expression::IfExpression {
+            condition,
+            consequence,
+            alternative,
+        } => Expression::IfExpression {
+            condition: Box::new(fold_expr(condition)),
+            consequence: Box::new(fold_stmt(consequence)),
+            alternative: alternative.as_ref().map(|alt| Box::new(fold_stmt(alt))),
+        },
+        Expression::FunctionLiteral { parameters, body } => Expression::FunctionLiteral {
+            parameters: parameters.clone(),
+            body: Rc::new(fold_stmt(body)),
+        },
+        Expression::CallExpr { function, args } => Expression::CallExpr {
+            function: Box::new(fold_expr(function)),
+            args: fold_exprs(args),
+        },
+        Expression::ArrayLiteral(exprs) => Expression::ArrayLiteral(fold_exprs(exprs)),
+        Expression::IndexExpr { left, index } => Expression::IndexExpr {
+            left: Box::new(fold_expr(left)),
+            index: Box::new(fold_expr(index)),
+        },
+        Expression::HashLiteral { keys, values } => Expression::HashLiteral {
+            keys: fold_exprs(keys),
+            values: fold_exprs(values),
+        },
+        Expression::Method {
+            left,
+            identifier,
+            args,
+        } => Expression::Method {
+            left: Box::new(fold_expr(left)),
+            identifier: identifier.clone(),
+            args: fold_exprs(args),
+        },
+        expr => expr.clone(),
+    }
+}
+
+fn fold_infix(
+    left: &Expression,
+    operator: &str,
+    right: &Expression,
+) -> Option<Expression> {
+    let folded = match (left, right) {
+        (Expression::IntegerLiteral(l), Expression::IntegerLiteral(r)) => {
+            match operator {
+                "+" => Expression::IntegerLiteral(l.checked_add(*r)?),
+                "-" => Expression::IntegerLiteral(l.checked_sub(*r)?),
+                "*" => Expression::IntegerLiteral(l.checked_mul(*r)?),
+                "/" => Expression::IntegerLiteral(l.checked_div(*r)?), // None on a division by zero
+                "<" => Expression::Bool(l < r),
+                ">" => Expression::Bool(l > r),
+                "==" => Expression::Bool(l == r),
+                "!=" => Expression::Bool(l != r),
+                _ => return None,
+            }
+        }
+        (Expression::Bool(l), Expression::Bool(r)) => match operator {
+            "==" => Expression::Bool(l == r),
+            "!=" => Expression::Bool(l != r),
+            _ => return None,
+        },
+        (Expression::StringLiteral(l), Expression::StringLiteral(r))
+            if operator == "+" =>
+        {
+            Expression::StringLiteral(format!("{}{}", l, r))
+        }
+        _ => return None,
+    };
+    Some(folded)
+}
+
+fn fold_prefix(operator: &str, expr: &Expression) -> Option<Expression> {
+    let folded = match (operator, expr) {
+        ("-", Expression::IntegerLiteral(v)) => {
+            Expression::IntegerLiteral(v.checked_neg()?)
+        }
+        ("!", Expression::Bool(v)) => Expression::Bool(!v),
+        _ => return None,
+    };
+    Some(folded)
+}
+
+/// Truthiness of a literal condition, or None if the condition is evaluated at runtime.
+pub fn literal_truthiness(condition: &Expression) -> Option<bool> {
+    match condition {
+        Expression::Bool(v) => Some(*v),
+        Expression::IntegerLiteral(v) => Some(*v != 0),
+        Expression::StringLiteral(_) => Some(true),
+        _ => None,
+    }
+}
@@ -0,0 +1,49 @@
+//! Identifiers and string data are interned, such that the compiler keeps one copy of
+//! every name and string, which the symbol tables and the constants pool share.
+use fnv::FnvHashSet as HashSet;
+use std::rc::Rc;
+
+#[derive(Debug, Default)]
+pub struct Interner {
+    strings: HashSet<Rc<str>>,
+}
+
+impl Interner {
+    pub fn new() -> Interner {
+        Interner::default()
+    }
+
+    /// The shared copy of the string.
+    pub fn intern(&mut self, s: &str) -> Rc<str> {
+        if let Some(interned) = self.strings.get(s) {
+            return Rc::clone(interned);
+        }
+        let interned: Rc<str> = Rc::from(s);
+        self.strings.insert(Rc::clone(&interned));
+        interned
+    }
+
+    pub fn len(&self) -> usize {
+        self.strings.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.strings.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_intern() {
+        let mut interner = Interner::new();
+        let a = interner.intern("insert");
+        let b = interner.intern(&String::from("insert"));
+        assert!(Rc::ptr_eq(&a, &b));
+        let c = interner.intern("len");
+        assert!(!Rc::ptr_eq(&a, &c));
+        assert_eq!(interner.len(), 2);
+    }
+}
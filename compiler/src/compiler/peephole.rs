@@ -0,0 +1,174 @@
+use crate::code::{decode, Instruction, OpCode};
+use monkey::eval::object::{LineTable, Object};
+use std::collections::HashSet;
+
+/// Rewrite short sequences of instructions into shorter or faster ones:
+///
+/// - jumps to a `Jump` go directly to its target
+/// - a `Constant` that is popped right away is removed
+/// - `GetLocal`, `GetLocal`, `Add` becomes `AddLocals`
+/// - constants 0 and 1 become `Zero` and `One`, other small ints `SmallInt`
+///
+/// Instructions that are jumped to are never fused with the instructions before them.
+/// The jump targets and the line table are updated to the new offsets.
+pub fn optimize(
+    instructions: &[u8],
+    line_table: &LineTable,
+    constants: &[Object],
+    in_function: bool,
+) -> (Vec<u8>, LineTable) {
+    let mut code = decode(instructions)
+        .collect::<Result<Vec<Instruction>, _>>()
+        .expect("the compiler emits valid instructions");
+    collapse_jumps(&mut code);
+
+    let targets: HashSet<usize> = code
+        .iter()
+        .filter(|ins| is_jump(ins.oc))
+        .map(|ins| ins.operands[0])
+        .collect();
+    // An instruction that can be fused with the ones before it.
+    let fusable = |i: usize, oc: OpCode| match code.get(i) {
+        Some(ins) => ins.oc == oc && !targets.contains(&ins.offset),
+        None => false,
+    };
+
+    // Rewritten instructions keep the offset of the first instruction they replace.
+    let mut optimized = vec![];
+    let mut i = 0;
+    while i < code.len() {
+        let ins = &code[i];
+        match ins.oc {
+            OpCode::Constant if fusable(i + 1, OpCode::Pop) => {
+                // The main program returns the last popped object, so the constant can
+                // only be dropped there if the next instruction overwrites it.
+                let overwritten = code
+                    .get(i + 2)
+                    .is_some_and(|next| next.stack_effect() == (0, 1));
+                if in_function || overwritten {
+                    i += 2;
+                    continue;
+                }
+                optimized.push(ins.clone());
+            }
+            OpCode::GetLocal
+                if fusable(i + 1, OpCode::GetLocal) && fusable(i + 2, OpCode::Add) =>
+            {
+                optimized.push(Instruction {
+                    offset: ins.offset,
+                    oc: OpCode::AddLocals,
+                    operands: vec![ins.operands[0], code[i + 1].operands[0]],
+                });
+                i += 3;
+                continue;
+            }
+            OpCode::Constant => {
+                let small_int = match constants[ins.operands[0]] {
+                    Object::Int(0) => Some((OpCode::Zero, vec![])),
+                    Object::Int(1) => Some((OpCode::One, vec![])),
+                    Object::Int(v) if (2..=255).contains(&v) => {
+                        Some((OpCode::SmallInt, vec![v as usize]))
+                    }
+                    _ => None,
+                };
+                match small_int {
+                    Some((oc, operands)) => optimized.push(Instruction {
+                        offset: ins.offset,
+                        oc,
+                        operands,
+                    }),
+                    None => optimized.push(ins.clone()),
+                }
+            }
+            _ => optimized.push(ins.clone()),
+        }
+        i += 1;
+    }
+    relocate(&optimized, line_table)
+}
+
+fn is_jump(oc: OpCode) -> bool {
+    matches!(oc, OpCode::Jump | OpCode::JumpNotTruthy)
+}
+
+/// Let jumps to a `Jump` go to its target instead.
+fn collapse_jumps(code: &mut [Instruction]) {
+    let index_of = |code: &[Instruction], offset: usize| {
+        code.binary_search_by_key(&offset, |ins| ins.offset).ok()
+    };
+    for i in 0..code.len() {
+        if !is_jump(code[i].oc) {
+            continue;
+        }
+        let mut target = code[i].operands[0];
+        // Bounded by the number of instructions, in case the jumps form a loop.
+        for _ in 0..code.len() {
+            match index_of(code, target) {
+                Some(t) if code[t].oc == OpCode::Jump && t != i => {
+                    target = code[t].operands[0]
+                }
+                _ => break,
+            }
+        }
+        code[i].operands[0] = target;
+    }
+}
+
+/// Encode the instructions at their new offsets. A jump to a removed instruction
+/// continues at the next remaining instruction.
+///
+/// Jumps get a `Wide` prefix if their new target does not fit a narrow operand. That
+/// moves the instructions behind them, so the offsets are computed again until no more
+/// jumps need to be widened.
+pub fn relocate(code: &[Instruction], line_table: &LineTable) -> (Vec<u8>, LineTable) {
+    let mut wide: Vec<bool> = code
+        .iter()
+        .map(|ins| !is_jump(ins.oc) && ins.is_wide())
+        .collect();
+    let (new_offsets, new_len) = loop {
+        let mut new_offsets = Vec::with_capacity(code.len());
+        let mut offset = 0;
+        for (ins, wide) in code.iter().zip(wide.iter()) {
+            new_offsets.push(offset);
+            offset += ins.oc.width(*wide);
+        }
+        let mut widened = false;
+        for (i, ins) in code.iter().enumerate().filter(|(_, ins)| is_jump(ins.oc)) {
+            let target = new_offset(code, &new_offsets, offset, ins.operands[0]);
+            if !wide[i] && ins.oc.needs_wide(&[target]) {
+                wide[i] = true;
+                widened = true;
+            }
+        }
+        if !widened {
+            break (new_offsets, offset);
+        }
+    };
+
+    let mut instructions = Vec::with_capacity(new_len);
+    let mut new_line_table = LineTable::new();
+    for (ins, offset) in code.iter().zip(new_offsets.iter()) {
+        if let Some(span) = line_table.span_at(ins.offset) {
+            new_line_table.add(*offset, span);
+        }
+        if is_jump(ins.oc) {
+            let target = new_offset(code, &new_offsets, new_len, ins.operands[0]);
+            instructions.extend(ins.oc.make(&[target]));
+        } else {
+            instructions.extend(ins.oc.make(&ins.operands));
+        }
+    }
+    debug_assert_eq!(instructions.len(), new_len);
+    (instructions, new_line_table)
+}
+
+/// New offset of the instruction at the old offset, or of the next remaining one.
+fn new_offset(
+    code: &[Instruction],
+    new_offsets: &[usize],
+    new_len: usize,
+    old: usize,
+) -> usize {
+    let idx = code.partition_point(|ins| ins.offset < old);
+    new_offsets.get(idx).copied().unwrap_or(new_len)
+}
@@ -9,6 +9,9 @@ use std::rc::Rc;
 pub enum Scope {
     Global,
     Local,
+    Function, // the function that is being compiled, used for recursion.
+    Builtin,
+    Captured, // a local of an enclosing function, which the compiled code cannot reach
 }
 
 #[derive(Clone)]
@@ -19,7 +22,7 @@ pub struct Symbol {
 
 pub struct SymbolTable {
     pub outer: Option<Rc<RefCell<SymbolTable>>>,
-    store: HashMap<String, Symbol>,
+    store: HashMap<Rc<str>, Symbol>, // the names are interned
     num_definitions: usize,
 }
 
@@ -42,7 +45,14 @@ impl SymbolTable {
         s
     }
 
-    pub fn define(&mut self, name: String) -> Symbol {
+    pub fn define(&mut self, name: Rc<str>) -> Symbol {
+        // Symbols that are already defined (or predeclared) in this scope keep their index.
+        if let Some(smbl) = self.store.get(&name) {
+            match smbl.scope {
+                Scope::Global | Scope::Local => return smbl.clone(),
+                _ => {}
+            }
+        }
         let scope;
         if self.outer.is_none() {
             scope = Scope::Global;
@@ -60,6 +70,42 @@ impl SymbolTable {
         smbl
     }
 
+    /// Define a parameter. Every parameter has the slot of its argument, and of
+    /// parameters with the same name the last one is used, like in the evaluator.
+    pub fn define_parameter(&mut self, name: Rc<str>) -> Symbol {
+        let smbl = Symbol {
+            scope: Scope::Local,
+            index: self.num_definitions,
+        };
+        self.store.insert(name, smbl.clone());
+        self.num_definitions += 1;
+        smbl
+    }
+
+    /// Define the name of the function that is being compiled.
+    /// This doesn't take a slot, as the function is already on the stack.
+    pub fn define_function_name(&mut self, name: Rc<str>) -> Symbol {
+        let smbl = Symbol {
+            scope: Scope::Function,
+            index: 0,
+        };
+        self.store.insert(name, smbl.clone());
+        smbl
+    }
+
+    pub fn define_builtin(&mut self, index: usize, name: Rc<str>) -> Symbol {
+        let smbl = Symbol {
+            scope: Scope::Builtin,
+            index,
+        };
+        self.store.insert(name, smbl.clone());
+        smbl
+    }
+
+    pub fn num_definitions(&self) -> usize {
+        self.num_definitions
+    }
+
     pub fn resolve(&self, name: &str) -> Option<Symbol> {
         if let Some(smbl) = self.store.get(name) {
             return Some(smbl.clone());
@@ -68,9 +114,37 @@ impl SymbolTable {
         match &self.outer {
             Some(smbl_table) => {
                 let tbl = RefCell::borrow(smbl_table);
-                tbl.resolve(name)
+                let smbl = tbl.resolve(name)?;
+                let captured = Symbol {
+                    scope: Scope::Captured,
+                    index: smbl.index,
+                };
+                match smbl.scope {
+                    // The slot of a local belongs to the frame of the enclosing function.
+                    Scope::Local => Some(captured),
+                    // Only the function that is being executed is on the stack, but a
+                    // function that is bound by a global let statement is also a global.
+                    Scope::Function => {
+                        Some(tbl.global_function(name).unwrap_or(captured))
+                    }
+                    _ => Some(smbl),
+                }
             }
             None => None,
         }
     }
+
+    /// The global that the function `name` of this table is bound to, if the function
+    /// is defined in the global scope.
+    fn global_function(&self, name: &str) -> Option<Symbol> {
+        let outer = RefCell::borrow(self.outer.as_ref()?);
+        if outer.outer.is_some() {
+            return None;
+        }
+        outer
+            .store
+            .get(name)
+            .filter(|smbl| matches!(smbl.scope, Scope::Global))
+            .cloned()
+    }
 }
@@ -1,9 +1,12 @@
 use super::compiler::Compiler;
-use crate::code::{read_operands, OpCode, Operand};
-use crate::compiler::compiler::Bytecode;
-use crate::utils::{compile, parse};
-use monkey::eval::object::Object;
+use crate::code::{decode, OpCode, Operand};
+use crate::compiler::compiler::{Bytecode, OptLevel};
+use crate::err::CompileError;
+use crate::utils::{compile, compile_with_opt_level, parse};
+use monkey::eval::object::{LineTable, Object};
+use monkey::lexer::token::Span;
 use std::convert::TryFrom;
+use std::rc::Rc;
 use OpCode::*;
 
 fn make_instructions(opcodes: &[OpCode], operands: &[&[Operand]]) -> Vec<u8> {
@@ -41,24 +44,29 @@ fn assert_constants<T: Into<Object> + Clone>(input: &str, check: &[T]) {
     assert_eq!(bc.constants, &check[..]);
 }
 
+/// Line tables of compiled functions are not compared.
 fn assert_constant_literals(input: &str, check: &[Object]) {
     let com = compile(input).unwrap();
     let bc = com.bytecode();
-    assert_eq!(format!("{:?}", bc.constants), format!("{:?}", check));
+    let constants = bc
+        .constants
+        .iter()
+        .cloned()
+        .map(|o| match o {
+            Object::CompiledFunction(mut cf) => {
+                Rc::make_mut(&mut cf).line_table = LineTable::new();
+                Object::CompiledFunction(cf)
+            }
+            o => o,
+        })
+        .collect::<Vec<Object>>();
+    assert_eq!(format!("{:?}", constants), format!("{:?}", check));
 }
 
 fn write_human_readable(instr: &[u8]) {
-    let mut i = 0;
-    while i < instr.len() {
-        let start_ptr = i;
-        let b = instr[i];
-        let oc = OpCode::try_from(b).unwrap();
-        let operands = read_operands(oc.definition(), &instr[i..]).0;
-        for width in oc.definition() {
-            i += *width
-        }
-        i += 1;
-        println!("{:04}\t{:?}\t\t{:?}", start_ptr, oc, operands)
+    for ins in decode(instr) {
+        let ins = ins.unwrap();
+        println!("{:04}\t{:?}\t\t{:?}", ins.offset, ins.oc, ins.operands)
     }
 }
 
@@ -183,6 +191,31 @@ fn test_condition_if_else() {
     );
 }
 
+#[test]
+fn test_conditional_without_value() {
+    // A branch that doesn't end with an expression leaves null, like a missing else,
+    // or the value of a let if it ends with one.
+    assert_equal_instr(
+        "1; if (true) { }",
+        &[Constant, Pop, True, JumpNotTruthy, Null, Jump, Null, Pop],
+        &[&[0], &[], &[], &[12], &[], &[13], &[], &[]],
+    );
+    assert_equal_instr(
+        "if (true) { let x = 1; }",
+        &[
+            True,
+            JumpNotTruthy,
+            Constant,
+            SetGlobal,
+            Ignore,
+            Jump,
+            Null,
+            Pop,
+        ],
+        &[&[], &[14], &[0], &[0], &[], &[15], &[], &[]],
+    );
+}
+
 #[test]
 fn test_global_let_stmt() {
     let input = "let one = 1; let two = 2; one";
@@ -226,10 +259,14 @@ fn test_fn_explicit_and_implicit_return() {
             &[
                 Object::Int(5),
                 Object::Int(10),
-                Object::CompiledFunction(make_instructions(
-                    &[Constant, Constant, Add, ReturnVal],
-                    &[&[0], &[1], &[], &[]],
-                )),
+                Object::new_compiled_function(
+                    make_instructions(
+                        &[Constant, Constant, Add, ReturnVal],
+                        &[&[0], &[1], &[], &[]],
+                    ),
+                    0,
+                    0,
+                ),
             ],
         );
     }
@@ -240,10 +277,11 @@ fn test_fn_no_return() {
     let input = "fn() {}";
     assert_constant_literals(
         &input,
-        &[Object::CompiledFunction(make_instructions_tpl(&[(
-            OpCode::Return,
-            None,
-        )]))],
+        &[Object::new_compiled_function(
+            make_instructions_tpl(&[(OpCode::Return, None)]),
+            0,
+            0,
+        )],
     );
     assert_equal_instr(&input, &[Constant, Pop], &[&[0], &[]])
 }
@@ -251,13 +289,65 @@ fn test_fn_no_return() {
 #[test]
 fn test_fn_calls() {
     let input = "fn() { 24 }()";
-    assert_equal_instr(&input, &[Constant, Call, Pop], &[&[1], &[], &[]]);
+    assert_equal_instr(&input, &[Constant, Call, Pop], &[&[1], &[0], &[]]);
     let input = "let noArg = fn() { 24 };
 noArg();";
     assert_equal_instr(
         &input,
         &[Constant, SetGlobal, GetGlobal, Call, Pop],
-        &[&[1], &[0], &[0], &[], &[]],
+        &[&[1], &[0], &[0], &[0], &[]],
+    );
+}
+
+#[test]
+fn test_fn_call_args() {
+    let input = "let oneArg = fn(a) { a };
+oneArg(24);";
+    assert_constant_literals(
+        &input,
+        &[
+            Object::new_compiled_function(
+                make_instructions_tpl(&[(GetLocal, Some(0)), (ReturnVal, None)]),
+                1,
+                1,
+            ),
+            Object::Int(24),
+        ],
+    );
+    assert_equal_instr(
+        &input,
+        &[Constant, SetGlobal, GetGlobal, Constant, Call, Pop],
+        &[&[0], &[0], &[0], &[1], &[1], &[]],
+    );
+
+    let input = "let manyArg = fn(a, b, c) { a; b; c };
+manyArg(24, 25, 26);";
+    assert_constant_literals(
+        &input,
+        &[
+            Object::new_compiled_function(
+                make_instructions_tpl(&[
+                    (GetLocal, Some(0)),
+                    (Pop, None),
+                    (GetLocal, Some(1)),
+                    (Pop, None),
+                    (GetLocal, Some(2)),
+                    (ReturnVal, None),
+                ]),
+                3,
+                3,
+            ),
+            Object::Int(24),
+            Object::Int(25),
+            Object::Int(26),
+        ],
+    );
+    assert_equal_instr(
+        &input,
+        &[
+            Constant, SetGlobal, GetGlobal, Constant, Constant, Constant, Call, Pop,
+        ],
+        &[&[0], &[0], &[0], &[1], &[2], &[3], &[3], &[]],
     );
 }
 
@@ -270,10 +360,11 @@ fn() { num }";
         &input,
         &[
             Object::Int(55),
-            Object::CompiledFunction(make_instructions_tpl(&[
-                (GetGlobal, Some(0)),
-                (ReturnVal, None),
-            ])),
+            Object::new_compiled_function(
+                make_instructions_tpl(&[(GetGlobal, Some(0)), (ReturnVal, None)]),
+                0,
+                0,
+            ),
         ],
     );
     assert_equal_instr(
@@ -291,13 +382,570 @@ num
         &input,
         &[
             Object::Int(55),
-            Object::CompiledFunction(make_instructions_tpl(&[
-                (Constant, Some(0)),
-                (SetLocal, Some(0)),
-                (GetLocal, Some(0)),
-                (ReturnVal, None),
-            ])),
+            Object::new_compiled_function(
+                make_instructions_tpl(&[
+                    (Constant, Some(0)),
+                    (SetLocal, Some(0)),
+                    (GetLocal, Some(0)),
+                    (ReturnVal, None),
+                ]),
+                1,
+                0,
+            ),
         ],
     );
     assert_equal_instr(&input, &[Constant, Pop], &[&[1], &[]])
 }
+
+#[test]
+fn test_recursive_fn() {
+    let input = "let countDown = fn(x) { countDown(x - 1); };
+countDown(1);";
+    assert_constant_literals(
+        &input,
+        &[
+            Object::Int(1),
+            Object::new_compiled_function(
+                make_instructions_tpl(&[
+                    (CurrentFunction, None),
+                    (GetLocal, Some(0)),
+                    (Constant, Some(0)),
+                    (Sub, None),
+                    (Call, Some(1)),
+                    (ReturnVal, None),
+                ]),
+                1,
+                1,
+            ),
+        ],
+    );
+    assert_equal_instr(
+        &input,
+        &[Constant, SetGlobal, GetGlobal, Constant, Call, Pop],
+        &[&[1], &[0], &[0], &[0], &[1], &[]],
+    );
+}
+
+#[test]
+fn test_predeclared_globals() {
+    // `b` is predeclared, so `a` can refer to it before it is defined.
+    let input = "let a = fn() { b() };
+let b = fn() { 1 };";
+    assert_constant_literals(
+        &input,
+        &[
+            Object::new_compiled_function(
+                make_instructions_tpl(&[
+                    (GetGlobal, Some(1)),
+                    (Call, Some(0)),
+                    (ReturnVal, None),
+                ]),
+                0,
+                0,
+            ),
+            Object::Int(1),
+            Object::new_compiled_function(
+                make_instructions_tpl(&[(Constant, Some(1)), (ReturnVal, None)]),
+                0,
+                0,
+            ),
+        ],
+    );
+}
+
+#[test]
+fn test_builtins() {
+    // builtins are ordered by name: insert, len, print
+    let input = "len([]);
+print(\"monkey\");";
+    assert_equal_instr(
+        &input,
+        &[
+            GetBuiltin, Array, Call, Pop, GetBuiltin, Constant, Call, Pop,
+        ],
+        &[&[1], &[0], &[1], &[], &[2], &[0], &[1], &[]],
+    );
+    let input = "fn() { len([]) }";
+    assert_constant_literals(
+        &input,
+        &[Object::new_compiled_function(
+            make_instructions_tpl(&[
+                (GetBuiltin, Some(1)),
+                (Array, Some(0)),
+                (Call, Some(1)),
+                (ReturnVal, None),
+            ]),
+            0,
+            0,
+        )],
+    );
+    // globals shadow builtins
+    let input = "let len = fn(x) { 1 }; len([]);";
+    assert_equal_instr(
+        &input,
+        &[Constant, SetGlobal, GetGlobal, Array, Call, Pop],
+        &[&[1], &[0], &[0], &[0], &[1], &[]],
+    );
+}
+
+#[test]
+fn test_hash_literals() {
+    let input = "{}";
+    assert_equal_instr(&input, &[Hash, Pop], &[&[0], &[]]);
+
+    let input = "{1: 2, 3: 4, 5: 6}";
+    assert_equal_instr(
+        &input,
+        &[
+            Constant, Constant, Constant, Constant, Constant, Constant, Hash, Pop,
+        ],
+        &[&[0], &[1], &[2], &[3], &[4], &[5], &[6], &[]],
+    );
+    assert_constants(&input, &[1, 2, 3, 4, 5, 6]);
+
+    let input = "{1: 2 + 3, 4: 5 * 6}";
+    assert_equal_instr(
+        &input,
+        &[
+            Constant, Constant, Constant, Add, Constant, Constant, Constant, Mul, Hash,
+            Pop,
+        ],
+        &[&[0], &[1], &[2], &[], &[3], &[4], &[5], &[], &[4], &[]],
+    );
+}
+
+#[test]
+fn test_index_expr() {
+    let input = "[1, 2, 3][1 + 1]";
+    assert_equal_instr(
+        &input,
+        &[
+            Constant, Constant, Constant, Array, Constant, Constant, Add, Index, Pop,
+        ],
+        &[&[0], &[1], &[2], &[3], &[0], &[0], &[], &[], &[]],
+    );
+
+    let input = "{1: 2}[2 - 1]";
+    assert_equal_instr(
+        &input,
+        &[
+            Constant, Constant, Hash, Constant, Constant, Sub, Index, Pop,
+        ],
+        &[&[0], &[1], &[2], &[1], &[0], &[], &[], &[]],
+    );
+}
+
+#[test]
+fn test_method() {
+    let input = "{}.insert(1, 2)";
+    assert_equal_instr(
+        &input,
+        &[Hash, Constant, Constant, CallMethod, Pop],
+        &[&[0], &[0], &[1], &[2, 2], &[]],
+    );
+    assert_constant_literals(
+        &input,
+        &[Object::Int(1), Object::Int(2), Object::from("insert")],
+    );
+}
+
+#[test]
+fn test_compile_errors() {
+    // compilation continues after an error, so that all errors are reported.
+    let input = "let a = 1;
+b + a;
+let c = fn() { d };
+-a;";
+    let errors = compile(&input).err().unwrap();
+    assert_eq!(errors.len(), 2);
+    match &errors[0] {
+        CompileError::UndefinedVariable(ident, span) => {
+            assert_eq!(ident, "b");
+            assert_eq!(span.line, 2);
+        }
+        e => panic!("unexpected error: {}", e),
+    }
+    assert_eq!(
+        format!("{}", errors[1]),
+        "UndefinedVariable at line 3: undefined variable: d"
+    );
+
+    let input = "1;\n%1";
+    let errors = compile(&input).err().unwrap();
+    assert_eq!(
+        format!("{}", errors[0]),
+        "UnknownOperator at line 2: unknown operator: %"
+    );
+
+    let input = "let x;";
+    match &compile(&input).err().unwrap()[0] {
+        CompileError::Parser(_) => {}
+        e => panic!("unexpected error: {}", e),
+    }
+}
+
+#[test]
+fn test_line_table() {
+    let input = "let a = 1;
+let f = fn() {
+  a;
+  a + 2
+};
+f();";
+    let com = compile(&input).unwrap();
+    let bc = com.bytecode();
+    let lines = bc
+        .line_table
+        .ranges(bc.instructions.len())
+        .map(|(range, span)| (range, span.line))
+        .collect::<Vec<_>>();
+    // let a (Constant, SetGlobal), let f (Constant, SetGlobal), f() (GetGlobal, Call, Pop)
+    assert_eq!(lines, [(0..6, 1), (6..12, 2), (12..18, 6)]);
+    assert_eq!(bc.line_table.line_at(8), Some(2));
+    assert_eq!(bc.line_table.span_at(12), Some(Span::new(42, 46, 6)));
+
+    match &bc.constants[2] {
+        Object::CompiledFunction(cf) => {
+            let lines = cf
+                .line_table
+                .ranges(cf.instructions.len())
+                .map(|(range, span)| (range, span.line))
+                .collect::<Vec<_>>();
+            // a; (GetGlobal, Pop), a + 2 (GetGlobal, Constant, Add, ReturnVal)
+            assert_eq!(lines, [(0..4, 3), (4..12, 4)]);
+        }
+        o => panic!("expected a compiled function, got: {:?}", o),
+    }
+}
+
+/// Compile with constant folding.
+fn assert_optimized(
+    input: &str,
+    instructions: &[(OpCode, &[Operand])],
+    constants: &[Object],
+) {
+    assert_optimized_at(OptLevel::O1, input, instructions, constants)
+}
+
+/// Line tables of compiled functions are not compared.
+fn assert_optimized_at(
+    opt_level: OptLevel,
+    input: &str,
+    instructions: &[(OpCode, &[Operand])],
+    constants: &[Object],
+) {
+    let com = compile_with_opt_level(input, opt_level).unwrap();
+    let bc = com.bytecode();
+    let want = instructions
+        .iter()
+        .flat_map(|(oc, operands)| oc.make(operands))
+        .collect::<Vec<u8>>();
+    assert_eq!(bc.instructions, &want[..], "{}", input);
+    let got = bc
+        .constants
+        .iter()
+        .cloned()
+        .map(|o| match o {
+            Object::CompiledFunction(mut cf) => {
+                Rc::make_mut(&mut cf).line_table = LineTable::new();
+                Object::CompiledFunction(cf)
+            }
+            o => o,
+        })
+        .collect::<Vec<Object>>();
+    assert_eq!(
+        format!("{:?}", got),
+        format!("{:?}", constants),
+        "{}",
+        input
+    );
+}
+
+#[test]
+fn test_constant_folding() {
+    assert_optimized(
+        "1 + 2 * 3",
+        &[(Constant, &[0]), (Pop, &[])],
+        &[Object::Int(7)],
+    );
+    assert_optimized(
+        "-(4 / 2)",
+        &[(Constant, &[0]), (Pop, &[])],
+        &[Object::Int(-2)],
+    );
+    assert_optimized("1 < 2 == true", &[(True, &[]), (Pop, &[])], &[]);
+    assert_optimized("!false", &[(True, &[]), (Pop, &[])], &[]);
+    assert_optimized(
+        r#""mon" + "key""#,
+        &[(Constant, &[0]), (Pop, &[])],
+        &[Object::from("monkey")],
+    );
+    assert_optimized(
+        "fn() { 2 * 3 }",
+        &[(Constant, &[1]), (Pop, &[])],
+        &[
+            Object::Int(6),
+            Object::new_compiled_function(
+                [Constant.make(&[0]), ReturnVal.make(&[])].concat(),
+                0,
+                0,
+            ),
+        ],
+    );
+    // Operations that fail at runtime are left for the vm.
+    assert_optimized(
+        "1 / 0",
+        &[(Constant, &[0]), (Constant, &[1]), (Div, &[]), (Pop, &[])],
+        &[Object::Int(1), Object::Int(0)],
+    );
+    assert_optimized(
+        r#""a" == "a""#,
+        &[(Constant, &[0]), (Constant, &[0]), (Equal, &[]), (Pop, &[])],
+        &[Object::from("a")],
+    );
+}
+
+#[test]
+fn test_dead_branch_elimination() {
+    assert_optimized(
+        "if (1 < 2) { 10 } else { 20 }",
+        &[(Constant, &[0]), (Pop, &[])],
+        &[Object::Int(10)],
+    );
+    assert_optimized(
+        "if (false) { 10 } else { 20 }",
+        &[(Constant, &[0]), (Pop, &[])],
+        &[Object::Int(20)],
+    );
+    assert_optimized("if (0) { 10 }", &[(Null, &[]), (Pop, &[])], &[]);
+    assert_optimized("if (true) { }", &[(Null, &[]), (Pop, &[])], &[]);
+    assert_optimized(
+        "let x = 1; if (true) { x }",
+        &[
+            (Constant, &[0]),
+            (SetGlobal, &[0]),
+            (GetGlobal, &[0]),
+            (Pop, &[]),
+        ],
+        &[Object::Int(1)],
+    );
+}
+
+#[test]
+fn test_peephole() {
+    assert_optimized_at(
+        OptLevel::O2,
+        "0; 1; 7; 300",
+        &[(Constant, &[3]), (Pop, &[])],
+        &[0, 1, 7, 300]
+            .iter()
+            .map(|i| Object::Int(*i))
+            .collect::<Vec<_>>(),
+    );
+    assert_optimized_at(
+        OptLevel::O2,
+        "let a = 0; a",
+        &[
+            (Zero, &[]),
+            (SetGlobal, &[0]),
+            (GetGlobal, &[0]),
+            (Pop, &[]),
+        ],
+        &[Object::Int(0)],
+    );
+    assert_optimized_at(
+        OptLevel::O2,
+        "fn(a, b) { 5; a + b }",
+        &[(Constant, &[1]), (Pop, &[])],
+        &[
+            Object::Int(5),
+            Object::new_compiled_function(
+                [AddLocals.make(&[0, 1]), ReturnVal.make(&[])].concat(),
+                2,
+                2,
+            ),
+        ],
+    );
+    // The jump at the end of the inner if goes directly to the end of the outer if.
+    let input = "let x = 1; if (x) { if (x) { 2 } else { 3 } } else { 4 }";
+    assert_optimized_at(
+        OptLevel::O2,
+        input,
+        &[
+            (One, &[]),
+            (SetGlobal, &[0]),
+            (GetGlobal, &[0]),
+            (JumpNotTruthy, &[26]),
+            (GetGlobal, &[0]),
+            (JumpNotTruthy, &[21]),
+            (SmallInt, &[2]),
+            (Jump, &[28]),
+            (SmallInt, &[3]),
+            (Jump, &[28]),
+            (SmallInt, &[4]),
+            (Pop, &[]),
+        ],
+        &[1, 2, 3, 4]
+            .iter()
+            .map(|i| Object::Int(*i))
+            .collect::<Vec<_>>(),
+    );
+}
+
+#[test]
+fn test_peephole_line_table() {
+    let input = "let a = 1;
+5;
+a";
+    let com = compile_with_opt_level(input, OptLevel::O2).unwrap();
+    let bc = com.bytecode();
+    let lines = bc
+        .line_table
+        .ranges(bc.instructions.len())
+        .map(|(range, span)| (range, span.line))
+        .collect::<Vec<_>>();
+    // let a (One, SetGlobal), 5; is removed, a (GetGlobal, Pop)
+    assert_eq!(lines, [(0..4, 1), (4..8, 3)]);
+}
+
+#[test]
+fn test_constant_pool() {
+    let input = r#"1; "insert"; 1; "insert"; {}.insert(1, "insert")"#;
+    assert_constant_literals(&input, &[Object::Int(1), Object::from("insert")]);
+    assert_equal_instr(
+        &input,
+        &[
+            Constant, Pop, Constant, Pop, Constant, Pop, Constant, Pop, Hash, Constant,
+            Constant, CallMethod, Pop,
+        ],
+        &[
+            &[0],
+            &[],
+            &[1],
+            &[],
+            &[0],
+            &[],
+            &[1],
+            &[],
+            &[0],
+            &[0],
+            &[1],
+            &[1, 2],
+            &[],
+        ],
+    );
+
+    // Repeated literals don't fill the pool.
+    let input = "1 + 1;\n".repeat(70_000);
+    let com = compile(&input).unwrap();
+    assert_eq!(com.bytecode().constants.len(), 1);
+
+    // Constants behind the first 65536 are referenced with wide operands.
+    let input = (0..=65_536)
+        .map(|i| format!("{};\n", i))
+        .collect::<String>();
+    let com = compile(&input).unwrap();
+    let bc = com.bytecode();
+    assert_eq!(bc.constants.len(), 65_537);
+    let tail = &bc.instructions[bc.instructions.len() - 11..];
+    let mut want = Constant.make(&[65_535]);
+    want.extend(Pop.make(&[]));
+    want.extend(Constant.make(&[65_536]));
+    want.extend(Pop.make(&[]));
+    assert_eq!(tail, &want[..]);
+}
+
+/// An identifier for the number, the lexer does not allow digits in identifiers.
+fn identifier(prefix: &str, n: usize) -> String {
+    let digits = n.to_string();
+    let letters = digits.bytes().map(|d| (d - b'0' + b'a') as char);
+    prefix.chars().chain(letters).collect()
+}
+
+#[test]
+fn test_wide_operands() {
+    let decoded = |instructions: &[u8]| -> Vec<(OpCode, Vec<Operand>, bool)> {
+        decode(instructions)
+            .map(|ins| ins.unwrap())
+            .map(|ins| (ins.oc, ins.operands.clone(), ins.is_wide()))
+            .collect()
+    };
+
+    // The 257th local does not fit a one byte operand.
+    let lets = (0..257)
+        .map(|i| format!("let {} = 0;", identifier("l", i)))
+        .collect::<String>();
+    let input = format!("fn() {{ {} lcff + lcfg }}", lets);
+    let com = compile(&input).unwrap();
+    match com.bytecode().constants.last() {
+        Some(Object::CompiledFunction(cf)) => {
+            let code = decoded(&cf.instructions);
+            assert_eq!(
+                code[code.len() - 5..],
+                [
+                    (SetLocal, vec![256], true),
+                    (GetLocal, vec![255], false),
+                    (GetLocal, vec![256], true),
+                    (Add, vec![], false),
+                    (ReturnVal, vec![], false),
+                ]
+            );
+        }
+        c => panic!("expected a function, got: {:?}", c),
+    }
+
+    // The consequence is longer than a two byte jump. The jumps are placed after it
+    // is compiled, which moves the instructions behind them.
+    let input = format!("let c = true; if (c) {{ {} }}; 1", "c;".repeat(20_000));
+    let com = compile(&input).unwrap();
+    let bc = com.bytecode();
+    let code: Vec<_> = decode(bc.instructions).map(|ins| ins.unwrap()).collect();
+    let jumps: Vec<_> = code
+        .iter()
+        .filter(|ins| matches!(ins.oc, JumpNotTruthy | Jump))
+        .collect();
+    let (jump_not_truthy, jump) = (jumps[0], jumps[1]);
+    assert!(jump_not_truthy.is_wide() && jump.is_wide());
+    assert_eq!(jump_not_truthy.operands[0], jump.offset + jump.width());
+    // behind the Null of the missing alternative
+    assert_eq!(jump.operands[0], jump.offset + jump.width() + 1);
+    assert_eq!(
+        bc.instructions[jump_not_truthy.offset..jump_not_truthy.offset + 2],
+        [Wide as u8, JumpNotTruthy as u8]
+    );
+}
+
+#[test]
+fn test_operand_too_large() {
+    // Wide SetLocal takes a two byte index, the 65537th local does not fit.
+    let lets = (0..65_537)
+        .map(|i| format!("let {} = 0;", identifier("l", i)))
+        .collect::<String>();
+    let input = format!("fn() {{ {} }}", lets);
+    let errors = compile(&input).err().unwrap();
+    assert_eq!(errors.len(), 1);
+    assert_eq!(
+        format!("{}", errors[0]),
+        "OperandTooLarge at line 1: operand 65536 of SetLocal is larger than 65535"
+    );
+
+    // The vm has room for 65536 globals.
+    let lets = (0..65_537)
+        .map(|i| format!("let {} = 0;", identifier("g", i)))
+        .collect::<String>();
+    let errors = compile(&lets).err().unwrap();
+    assert_eq!(errors.len(), 1);
+    assert_eq!(
+        format!("{}", errors[0]),
+        "OperandTooLarge at line 1: operand 65536 of SetGlobal is larger than 65535"
+    );
+
+    let args = vec!["1"; 65_536].join(", ");
+    let input = format!("fn() {{ 1 }}({})", args);
+    match &compile(&input).err().unwrap()[0] {
+        CompileError::OperandTooLarge { op, operand, .. } => {
+            assert_eq!((*op, *operand), (Call, 65_536))
+        }
+        e => panic!("unexpected error: {}", e),
+    }
+}
@@ -0,0 +1,146 @@
+#![cfg(test)]
+//! Runs the programs of the corpus in `corpus/` on the evaluator, on the vm and on the
+//! register vm, which must print the same and give the same result, or both fail.
+//!
+//! Every program `name.mnk` has the expected outcome in `name.out`: what it prints,
+//! followed by `=> ` and its result, `=> error` or `=> panic`. The outcomes of new
+//! programs are written by the evaluator with
+//! `UPDATE_CORPUS=1 cargo test -p compiler corpus`.
+use crate::compiler::compiler::OptLevel;
+use crate::err::CompileError;
+use crate::register::vm::run_program;
+use crate::utils::{compile_register, compile_with_opt_level, parse};
+use crate::vm::vm::run_vm;
+use monkey::eval::builtins::capture_output;
+use monkey::eval::environment::Environment;
+use monkey::eval::evaluator::eval_program;
+use monkey::eval::object::Object;
+use std::fs;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::path::PathBuf;
+
+const CORPUS: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/../corpus");
+
+/// Programs that the compilers reject, and why. They must fail to compile with
+/// `CompileError::Unsupported`, instead of running differently.
+const UNSUPPORTED: &[(&str, &str)] = &[(
+    "closures",
+    "functions cannot capture the locals of enclosing functions",
+)];
+
+enum Outcome {
+    Result(Object),
+    Error,
+    Panic,
+}
+
+/// Run `f`, which gives the outcome of a program, and list what it printed and the
+/// outcome.
+fn run(f: impl FnOnce() -> Outcome) -> String {
+    let (outcome, printed) =
+        capture_output(|| catch_unwind(AssertUnwindSafe(f)).unwrap_or(Outcome::Panic));
+    match outcome {
+        Outcome::Result(obj) => format!("{}=> {}\n", printed, obj),
+        Outcome::Error => format!("{}=> error\n", printed),
+        Outcome::Panic => format!("{}=> panic\n", printed),
+    }
+}
+
+fn run_eval(source: &str) -> String {
+    let program = parse(source).unwrap();
+    run(|| match eval_program(&program, &Environment::new()) {
+        Object::Error(_) => Outcome::Error,
+        obj => Outcome::Result(obj),
+    })
+}
+
+/// Compile the program, and run it if it compiles.
+fn run_on_vm(source: &str, opt_level: OptLevel) -> Result<String, Vec<CompileError>> {
+    let com = compile_with_opt_level(source, opt_level)?;
+    Ok(run(|| match run_vm(&com.bytecode()) {
+        // errors of builtins and of indexing are values on the vm
+        Ok(Object::Error(_)) | Err(_) => Outcome::Error,
+        Ok(obj) => Outcome::Result(obj),
+    }))
+}
+
+fn run_on_register_vm(source: &str) -> Result<String, Vec<CompileError>> {
+    let program = compile_register(source)?;
+    Ok(run(|| match run_program(&program) {
+        Ok(Object::Error(_)) | Err(_) => Outcome::Error,
+        Ok(obj) => Outcome::Result(obj),
+    }))
+}
+
+fn programs() -> Vec<PathBuf> {
+    let mut programs: Vec<PathBuf> = fs::read_dir(CORPUS)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "mnk"))
+        .collect();
+    programs.sort();
+    programs
+}
+
+#[test]
+fn test_corpus() {
+    let update = std::env::var("UPDATE_CORPUS").is_ok();
+    let programs = programs();
+    assert!(!programs.is_empty(), "no programs in {}", CORPUS);
+
+    // All divergences are reported at once.
+    let mut failures = vec![];
+    for path in programs {
+        let name = path.file_stem().unwrap().to_string_lossy().to_string();
+        let source = fs::read_to_string(&path).unwrap();
+        let evaluated = run_eval(&source);
+        let expected_path = path.with_extension("out");
+        if update {
+            fs::write(&expected_path, &evaluated).unwrap();
+        }
+        let expected = fs::read_to_string(&expected_path).unwrap_or_default();
+        if evaluated != expected {
+            failures.push(format!(
+                "{}: the evaluator gives\n{}instead of\n{}",
+                name, evaluated, expected
+            ));
+        }
+
+        let unsupported = UNSUPPORTED.iter().any(|(n, _)| *n == name);
+        let backends = [
+            ("the vm at O0", run_on_vm(&source, OptLevel::O0)),
+            ("the vm at O1", run_on_vm(&source, OptLevel::O1)),
+            ("the vm at O2", run_on_vm(&source, OptLevel::O2)),
+            ("the register vm", run_on_register_vm(&source)),
+        ];
+        for (backend, outcome) in backends.iter() {
+            if unsupported {
+                let rejected = match outcome {
+                    Err(errors) => errors
+                        .iter()
+                        .any(|e| matches!(e, CompileError::Unsupported(..))),
+                    Ok(_) => false,
+                };
+                if !rejected {
+                    failures.push(format!(
+                        "{}: {} does not reject it, remove it from UNSUPPORTED",
+                        name, backend
+                    ));
+                }
+                continue;
+            }
+            // A program that does not compile fails without printing anything.
+            let vm = match outcome {
+                Ok(vm) => vm.clone(),
+                Err(_) => "=> error\n".to_string(),
+            };
+            if vm != evaluated {
+                failures.push(format!(
+                    "{}: {} gives\n{}where the evaluator gives\n{}",
+                    name, backend, vm, evaluated
+                ));
+            }
+        }
+    }
+    assert!(failures.is_empty(), "\n{}", failures.join("\n"));
+}
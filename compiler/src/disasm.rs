@@ -0,0 +1,229 @@
+//! Human readable listings of compiled programs.
+use crate::code::{decode, Instruction, OpCode};
+use crate::compiler::compiler::Bytecode;
+use monkey::eval::builtins::BUILTIN_LIST;
+use monkey::eval::object::{LineTable, Object};
+use std::fmt::Write;
+
+/// List the main program and every compiled function in the constants pool.
+///
+/// Instructions are shown with their offset, decoded operands and the constants, builtins
+/// and jump labels they refer to. If the source is given, each source line is printed
+/// above the instructions compiled from it, otherwise only the line number is shown.
+pub fn disassemble(bc: &Bytecode, source: Option<&str>) -> String {
+    let lines: Option<Vec<&str>> = source.map(|s| s.lines().collect());
+    let mut out = String::new();
+
+    writeln!(out, "main:");
+    fmt_code(
+        &mut out,
+        bc.instructions,
+        bc.line_table,
+        bc.constants,
+        &lines,
+    );
+
+    for (i, constant) in bc.constants.iter().enumerate() {
+        if let Object::CompiledFunction(cf) = constant {
+            writeln!(
+                out,
+                "\nfn {} (parameters: {}, locals: {}):",
+                i, cf.num_parameters, cf.num_locals
+            );
+            fmt_code(
+                &mut out,
+                &cf.instructions,
+                &cf.line_table,
+                bc.constants,
+                &lines,
+            );
+        }
+    }
+    out
+}
+
+fn fmt_code(
+    out: &mut String,
+    instructions: &[u8],
+    line_table: &LineTable,
+    constants: &[Object],
+    lines: &Option<Vec<&str>>,
+) {
+    let mut targets: Vec<usize> = decode(instructions)
+        .filter_map(|ins| ins.ok())
+        .filter(|ins| matches!(ins.oc, OpCode::Jump | OpCode::JumpNotTruthy))
+        .map(|ins| ins.operands[0])
+        .collect();
+    targets.sort_unstable();
+    targets.dedup();
+    let label = |offset: usize| targets.binary_search(&offset).ok();
+
+    let mut current_line = None;
+    for ins in decode(instructions) {
+        let ins = match ins {
+            Ok(ins) => ins,
+            Err((offset, e)) => {
+                writeln!(out, "  {:04}  error: {}", offset, e);
+                break;
+            }
+        };
+
+        if let Some(span) = line_table.span_at(ins.offset) {
+            if current_line != Some(span.line) {
+                current_line = Some(span.line);
+                match lines.as_ref().and_then(|l| l.get(span.line - 1)) {
+                    Some(text) => {
+                        writeln!(out, "  ; line {}: {}", span.line, text.trim())
+                    }
+                    None => writeln!(out, "  ; line {}", span.line),
+                };
+            }
+        }
+        if let Some(l) = label(ins.offset) {
+            writeln!(out, "L{}:", l);
+        }
+        writeln!(
+            out,
+            "  {:04}  {}",
+            ins.offset,
+            fmt_instruction(&ins, constants, &label)
+        );
+    }
+    // a jump to the end of the instructions
+    if let Some(l) = label(instructions.len()) {
+        writeln!(out, "L{}:", l);
+    }
+}
+
+fn fmt_instruction(
+    ins: &Instruction,
+    constants: &[Object],
+    label: &dyn Fn(usize) -> Option<usize>,
+) -> String {
+    let mut s = if ins.is_wide() {
+        format!("Wide {:?}", ins.oc)
+    } else {
+        format!("{:?}", ins.oc)
+    };
+    for operand in ins.operands.iter() {
+        write!(s, " {}", operand);
+    }
+
+    let comment = match ins.oc {
+        OpCode::Constant => constants.get(ins.operands[0]).map(|c| match c {
+            Object::CompiledFunction(_) => format!("fn {}", ins.operands[0]),
+            c => format!("{}", c),
+        }),
+        OpCode::CallMethod => constants.get(ins.operands[0]).map(|c| format!("{}", c)),
+        OpCode::GetBuiltin => BUILTIN_LIST
+            .get(ins.operands[0])
+            .map(|b| b.identifier.to_string()),
+        OpCode::Jump | OpCode::JumpNotTruthy => {
+            label(ins.operands[0]).map(|l| format!("L{}", l))
+        }
+        _ => None,
+    };
+    match comment {
+        Some(comment) => format!("{:<20} ; {}", s, comment),
+        None => s,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::utils::compile;
+
+    #[test]
+    fn test_disassemble() {
+        let input = r#"let double = fn(x) { x * 2 };
+if (len("ab") > 1) { double(2) } else { 0 }
+{"a": 1}.insert("b", 2)"#;
+        let com = compile(input).unwrap();
+        let expected = r#"main:
+  ; line 1: let double = fn(x) { x * 2 };
+  0000  Constant 1           ; fn 1
+  0003  SetGlobal 0
+  ; line 2: if (len("ab") > 1) { double(2) } else { 0 }
+  0006  GetBuiltin 1         ; len
+  0008  Constant 2           ; "ab"
+  0011  Call 1
+  0013  Constant 3           ; 1
+  0016  GT
+  0017  JumpNotTruthy 31     ; L0
+  0020  GetGlobal 0
+  0023  Constant 0           ; 2
+  0026  Call 1
+  0028  Jump 34              ; L1
+L0:
+  0031  Constant 4           ; 0
+L1:
+  0034  Pop
+  ; line 3: {"a": 1}.insert("b", 2)
+  0035  Constant 5           ; "a"
+  0038  Constant 3           ; 1
+  0041  Hash 2
+  0044  Constant 6           ; "b"
+  0047  Constant 0           ; 2
+  0050  CallMethod 7 2       ; "insert"
+  0054  Pop
+
+fn 1 (parameters: 1, locals: 1):
+  ; line 1: let double = fn(x) { x * 2 };
+  0000  GetLocal 0
+  0002  Constant 0           ; 2
+  0005  Mul
+  0006  ReturnVal
+"#;
+        assert_eq!(disassemble(&com.bytecode(), Some(input)), expected);
+    }
+
+    #[test]
+    fn test_disassemble_without_source() {
+        let com = compile("1;\n2").unwrap();
+        let expected = "main:
+  ; line 1
+  0000  Constant 0           ; 1
+  0003  Pop
+  ; line 2
+  0004  Constant 1           ; 2
+  0007  Pop
+";
+        assert_eq!(disassemble(&com.bytecode(), None), expected);
+    }
+
+    #[test]
+    fn test_disassemble_invalid() {
+        let instructions = [OpCode::True as u8, 255];
+        let bc = Bytecode {
+            instructions: &instructions,
+            constants: &[],
+            line_table: &LineTable::new(),
+            num_globals: 0,
+        };
+        let expected = "main:
+  0000  True
+  0001  error: unknown opcode: 255
+";
+        assert_eq!(disassemble(&bc, None), expected);
+    }
+
+    #[test]
+    fn test_disassemble_wide() {
+        let mut constants = vec![Object::Null; 70_000];
+        constants.push(Object::Int(7));
+        let mut instructions = OpCode::Constant.make(&[70_000]);
+        instructions.extend(OpCode::Pop.make(&[]));
+        let bc = Bytecode {
+            instructions: &instructions,
+            constants: &constants,
+            line_table: &LineTable::new(),
+            num_globals: 0,
+        };
+        let expected = "main:
+  0000  Wide Constant 70000  ; 7
+  0006  Pop
+";
+        assert_eq!(disassemble(&bc, None), expected);
+    }
+}
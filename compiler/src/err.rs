@@ -1,8 +1,289 @@
+use crate::code::OpCode;
+use crate::vm::vm::MAX_GLOBALS;
+use monkey::lexer::token::Span;
+use monkey::ParserError;
+use std::fmt;
+
+#[derive(Debug, PartialEq)]
 pub enum VMError {
     StackOverflow,
+    StackUnderflow,
+    FrameOverflow, // more than MAX_FRAMES nested calls
+    WrongNumberOfArgs { want: usize, got: usize },
+    TypeMismatch(&'static str, OpCode, &'static str), // left type, operator, right type
+    UnsupportedOperator(OpCode, &'static str),        // operator, operand type
+    DivisionByZero,
+    IntegerOverflow(OpCode),
+    CallingNonFunction(&'static str),
+    NoCurrentFunction, // the current function is read in the main program
+    UnsetGlobal(usize), // a global is read before its let statement ran
+    InvalidMethodName(&'static str), // type of the constant that names the method
+    UnknownOpCode(u8),
+}
+
+impl VMError {
+    pub fn as_str(&self) -> String {
+        match self {
+            VMError::StackOverflow => "stack overflow".to_string(),
+            VMError::StackUnderflow => "nothing on the stack".to_string(),
+            VMError::FrameOverflow => "maximum recursion depth exceeded".to_string(),
+            VMError::WrongNumberOfArgs { want, got } => {
+                format!("wrong number of arguments: want={}, got={}", want, got)
+            }
+            VMError::TypeMismatch(left, op, right) => {
+                format!("type mismatch: {} {} {}", left, op.symbol(), right)
+            }
+            VMError::UnsupportedOperator(op, operand) => {
+                format!("unknown operator: {} on {}", op.symbol(), operand)
+            }
+            VMError::DivisionByZero => "division by zero".to_string(),
+            VMError::IntegerOverflow(op) => {
+                format!("integer overflow in {}", op.symbol())
+            }
+            VMError::CallingNonFunction(t) => format!("calling non-function: {}", t),
+            VMError::NoCurrentFunction => "no function is being executed".to_string(),
+            VMError::UnsetGlobal(index) => format!("global {} is not set yet", index),
+            VMError::InvalidMethodName(t) => {
+                format!("method name is not a string: {}", t)
+            }
+            VMError::UnknownOpCode(byte) => format!("unknown opcode: {}", byte),
+        }
+    }
+}
+
+impl fmt::Display for VMError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.as_str())
+    }
+}
+
+/// A VMError and the location in the source of the instruction that raised it.
+#[derive(Debug)]
+pub struct RuntimeError {
+    pub error: VMError,
+    pub span: Option<Span>,
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.span {
+            Some(span) => write!(f, "RuntimeError at line {}: {}", span.line, self.error),
+            None => write!(f, "RuntimeError: {}", self.error),
+        }
+    }
 }
 
 #[derive(Debug)]
 pub enum CompileError {
-    Some,
+    Parser(ParserError),
+    UndefinedVariable(String, Span),
+    UnknownOperator(String, Span),
+    Unsupported(String, Span), // construct that cannot be compiled (yet)
+    TooManyConstants(Span),
+    // e.g. the index of a local or the number of arguments
+    OperandTooLarge {
+        op: OpCode,
+        operand: usize,
+        max: usize,
+        span: Span,
+    },
+}
+
+impl CompileError {
+    pub fn span(&self) -> Option<Span> {
+        match self {
+            CompileError::Parser(_) => None,
+            CompileError::UndefinedVariable(_, span)
+            | CompileError::UnknownOperator(_, span)
+            | CompileError::Unsupported(_, span)
+            | CompileError::TooManyConstants(span)
+            | CompileError::OperandTooLarge { span, .. } => Some(*span),
+        }
+    }
+
+    pub fn as_str(&self) -> String {
+        match self {
+            CompileError::Parser(e) => e.as_str(),
+            CompileError::UndefinedVariable(s, _) => format!("undefined variable: {}", s),
+            CompileError::UnknownOperator(s, _) => format!("unknown operator: {}", s),
+            CompileError::Unsupported(s, _) => format!("cannot compile {}", s),
+            CompileError::TooManyConstants(_) => format!(
+                "more than {} constants in the constants pool",
+                crate::compiler::compiler::MAX_CONSTANTS
+            ),
+            CompileError::OperandTooLarge {
+                op, operand, max, ..
+            } => format!("operand {} of {:?} is larger than {}", operand, op, max),
+        }
+    }
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let err_type = match self {
+            CompileError::Parser(e) => return write!(f, "{}", e),
+            CompileError::UndefinedVariable(..) => "UndefinedVariable",
+            CompileError::UnknownOperator(..) => "UnknownOperator",
+            CompileError::Unsupported(..) => "Unsupported",
+            CompileError::TooManyConstants(_) => "TooManyConstants",
+            CompileError::OperandTooLarge { .. } => "OperandTooLarge",
+        };
+        match self.span() {
+            Some(span) => {
+                write!(f, "{} at line {}: {}", err_type, span.line, self.as_str())
+            }
+            None => write!(f, "{}: {}", err_type, self.as_str()),
+        }
+    }
+}
+
+impl From<ParserError> for CompileError {
+    fn from(error: ParserError) -> Self {
+        CompileError::Parser(error)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum VerifyError {
+    InvalidOpCode(u8),
+    TruncatedInstruction(OpCode), // operands run past the end of the instructions
+    UnexpectedWide(OpCode),       // wide prefix that is not needed for the operands
+    InvalidJumpTarget(usize),
+    ConstantOutOfRange(usize),
+    GlobalOutOfRange(usize),
+    TooManyGlobals(usize),
+    LocalOutOfRange(usize),
+    BuiltinOutOfRange(usize),
+    InvalidMethodName(usize), // constant index that is not a string
+    OddHashLength(usize),
+    NotInFunction(OpCode),
+    SetGlobalInFunction,
+    MissingReturn,
+    StackUnderflow,
+    InconsistentStackHeight { want: usize, got: usize },
+}
+
+impl VerifyError {
+    pub fn as_str(&self) -> String {
+        match self {
+            VerifyError::InvalidOpCode(byte) => format!("unknown opcode: {}", byte),
+            VerifyError::TruncatedInstruction(op) => {
+                format!("missing operands for {:?}", op)
+            }
+            VerifyError::UnexpectedWide(op) => format!("unexpected wide {:?}", op),
+            VerifyError::InvalidJumpTarget(t) => format!("invalid jump target: {}", t),
+            VerifyError::ConstantOutOfRange(i) => format!("no constant at index {}", i),
+            VerifyError::GlobalOutOfRange(i) => format!("no global at index {}", i),
+            VerifyError::TooManyGlobals(n) => {
+                format!("{} globals, more than the {} the vm has", n, MAX_GLOBALS)
+            }
+            VerifyError::LocalOutOfRange(i) => format!("no local at index {}", i),
+            VerifyError::BuiltinOutOfRange(i) => format!("no builtin at index {}", i),
+            VerifyError::InvalidMethodName(i) => {
+                format!("method name at constant index {} is not a string", i)
+            }
+            VerifyError::OddHashLength(n) => {
+                format!("hash with an odd number of keys and values: {}", n)
+            }
+            VerifyError::NotInFunction(op) => format!("{:?} outside of a function", op),
+            VerifyError::SetGlobalInFunction => "SetGlobal in a function".to_string(),
+            VerifyError::MissingReturn => {
+                "function does not end with a return".to_string()
+            }
+            VerifyError::StackUnderflow => "nothing on the stack".to_string(),
+            VerifyError::InconsistentStackHeight { want, got } => {
+                format!("inconsistent stack height: want={}, got={}", want, got)
+            }
+        }
+    }
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.as_str())
+    }
+}
+
+/// A VerifyError and the instruction that caused it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct InvalidBytecode {
+    pub error: VerifyError,
+    pub function: Option<usize>, // constant index of the function, None for the main program
+    pub offset: usize,
+}
+
+impl fmt::Display for InvalidBytecode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.function {
+            Some(i) => write!(
+                f,
+                "InvalidBytecode in function {} at {:04}: {}",
+                i, self.offset, self.error
+            ),
+            None => write!(f, "InvalidBytecode at {:04}: {}", self.offset, self.error),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum BytecodeFileError {
+    Io(std::io::Error),
+    NotBytecode, // the magic header is missing
+    IncompatibleVersion(u16),
+    UnexpectedEnd,
+    TrailingBytes,
+    InvalidConstantTag(u8),
+    InvalidString,
+    UnsupportedConstant(&'static str),
+    TooLarge(usize), // a length or count that does not fit in four bytes
+    Invalid(InvalidBytecode),
+}
+
+impl BytecodeFileError {
+    pub fn as_str(&self) -> String {
+        match self {
+            BytecodeFileError::Io(e) => format!("{}", e),
+            BytecodeFileError::NotBytecode => "not a monkey bytecode file".to_string(),
+            BytecodeFileError::IncompatibleVersion(v) => format!(
+                "incompatible bytecode version: {}, expected: {}",
+                v,
+                crate::serialize::FORMAT_VERSION
+            ),
+            BytecodeFileError::UnexpectedEnd => "unexpected end of file".to_string(),
+            BytecodeFileError::TrailingBytes => {
+                "trailing bytes after program".to_string()
+            }
+            BytecodeFileError::InvalidConstantTag(t) => {
+                format!("invalid constant tag: {}", t)
+            }
+            BytecodeFileError::InvalidString => {
+                "string constant is not utf-8".to_string()
+            }
+            BytecodeFileError::UnsupportedConstant(t) => {
+                format!("cannot serialize constant of type: {}", t)
+            }
+            BytecodeFileError::TooLarge(v) => {
+                format!("cannot serialize {}, it does not fit in 4 bytes", v)
+            }
+            BytecodeFileError::Invalid(e) => format!("{}", e),
+        }
+    }
+}
+
+impl fmt::Display for BytecodeFileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "BytecodeFileError: {}", self.as_str())
+    }
+}
+
+impl From<std::io::Error> for BytecodeFileError {
+    fn from(error: std::io::Error) -> Self {
+        BytecodeFileError::Io(error)
+    }
+}
+
+impl From<InvalidBytecode> for BytecodeFileError {
+    fn from(error: InvalidBytecode) -> Self {
+        BytecodeFileError::Invalid(error)
+    }
 }
@@ -5,14 +5,39 @@ extern crate lazy_static;
 pub mod code;
 pub mod compiler {
     pub mod compiler;
-    mod symbol_table;
+    mod fold;
+    pub(crate) mod interner;
+    mod peephole;
+    pub(crate) mod symbol_table;
     mod test;
 }
 pub mod vm {
+    #[cfg(feature = "jit")]
+    pub mod jit;
     mod test;
+    pub mod value;
+    pub mod verify;
     pub mod vm;
 }
-mod err;
+pub mod register {
+    pub mod code;
+    pub mod compiler;
+    mod test;
+    pub mod vm;
+}
+pub mod wasm {
+    pub mod compiler;
+    pub mod runtime;
+    mod test;
+}
+pub mod transpile {
+    pub mod rust;
+    mod test;
+}
+mod differential;
+pub mod disasm;
+pub mod err;
+pub mod serialize;
 pub mod utils;
 
 fn main() {}
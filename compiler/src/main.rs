@@ -0,0 +1,184 @@
+use compiler::compiler::compiler::{Bytecode, OptLevel};
+use compiler::disasm::disassemble;
+use compiler::register::vm::run_program;
+use compiler::serialize::OwnedBytecode;
+use compiler::utils::{
+    compile_register, compile_wasm, compile_with_opt_level, transpile,
+};
+use compiler::vm::vm::run_vm;
+use std::env;
+use std::fs::File;
+use std::path::Path;
+
+const USAGE: &str = "usage: monkey compile [-O0 | -O1 | -O2] <file.mnk> [-o <file.mnkc>]
+       monkey run [-O0 | -O1 | -O2] <file.mnk | file.mnkc>
+       monkey run --register <file.mnk>
+       monkey disasm [-O0 | -O1 | -O2] <file.mnk | file.mnkc>
+       monkey wasm <file.mnk> [-o <file.wasm>]
+       monkey transpile <file.mnk> [-o <file.rs>]
+
+-O0 compiles the program as written, -O1 folds constant expressions,
+-O2 (default) also runs the peephole optimizer.
+--register runs the script on the experimental register vm instead.";
+
+fn main() {
+    let mut args: Vec<String> = env::args().collect();
+    let result = take_opt_level(&mut args).and_then(|opt_level| {
+        match args.get(1).map(|s| &s[..]) {
+            Some("compile") => compile_file(&args[2..], opt_level),
+            Some("run") => run_file(&args[2..], opt_level),
+            Some("disasm") => disasm_file(&args[2..], opt_level),
+            Some("wasm") => wasm_file(&args[2..]),
+            Some("transpile") => transpile_file(&args[2..]),
+            _ => Err(USAGE.to_string()),
+        }
+    });
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        std::process::exit(1)
+    }
+}
+
+/// Remove the optimization level flag from the arguments.
+fn take_opt_level(args: &mut Vec<String>) -> Result<OptLevel, String> {
+    let mut opt_level = OptLevel::O2;
+    let mut result = Ok(());
+    args.retain(|arg| match &arg[..] {
+        "-O0" => {
+            opt_level = OptLevel::O0;
+            false
+        }
+        "-O1" => {
+            opt_level = OptLevel::O1;
+            false
+        }
+        "-O2" => {
+            opt_level = OptLevel::O2;
+            false
+        }
+        flag if flag.starts_with("-O") => {
+            result = Err(format!("unknown optimization level: {}\n{}", flag, USAGE));
+            false
+        }
+        _ => true,
+    });
+    result.map(|_| opt_level)
+}
+
+fn read_source(path: &str) -> Result<String, String> {
+    std::fs::read_to_string(path).map_err(|e| format!("could not read {}: {}", path, e))
+}
+
+fn fmt_errors<T: std::fmt::Display>(errors: &[T]) -> String {
+    errors
+        .iter()
+        .map(|e| format!("{}", e))
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+/// Compile a monkey script to a .mnkc bytecode file.
+fn compile_file(args: &[String], opt_level: OptLevel) -> Result<(), String> {
+    let input = args.get(0).ok_or_else(|| USAGE.to_string())?;
+    let output = match (args.get(1).map(|s| &s[..]), args.get(2)) {
+        (Some("-o"), Some(output)) => output.to_string(),
+        (None, _) => Path::new(input)
+            .with_extension("mnkc")
+            .to_string_lossy()
+            .to_string(),
+        _ => return Err(USAGE.to_string()),
+    };
+
+    let com = compile_with_opt_level(&read_source(input)?, opt_level)
+        .map_err(|e| fmt_errors(&e))?;
+    let mut f = File::create(&output).map_err(|e| format!("{}: {}", output, e))?;
+    com.bytecode()
+        .write_to(&mut f)
+        .map_err(|e| format!("{}", e))
+}
+
+/// Run a monkey script or a precompiled .mnkc file on the vm.
+fn run_file(args: &[String], opt_level: OptLevel) -> Result<(), String> {
+    if args.get(0).map(|s| &s[..]) == Some("--register") {
+        return run_register(&args[1..]);
+    }
+    let input = args.get(0).ok_or_else(|| USAGE.to_string())?;
+
+    if input.ends_with(".mnkc") {
+        let mut f = File::open(input).map_err(|e| format!("{}: {}", input, e))?;
+        let loaded = OwnedBytecode::read_from(&mut f).map_err(|e| format!("{}", e))?;
+        run(&loaded.bytecode())
+    } else {
+        let com = compile_with_opt_level(&read_source(input)?, opt_level)
+            .map_err(|e| fmt_errors(&e))?;
+        run(&com.bytecode())
+    }
+}
+
+/// Compile a monkey script for the register vm and run it. Bytecode files hold
+/// instructions of the stack vm, so only scripts are accepted.
+fn run_register(args: &[String]) -> Result<(), String> {
+    let input = match args {
+        [input] if !input.ends_with(".mnkc") => input,
+        _ => return Err(USAGE.to_string()),
+    };
+    let program = compile_register(&read_source(input)?).map_err(|e| fmt_errors(&e))?;
+    let result = run_program(&program).map_err(|e| format!("{}", e))?;
+    println!("{}", result);
+    Ok(())
+}
+
+/// Print the instructions of a monkey script or a precompiled .mnkc file.
+fn disasm_file(args: &[String], opt_level: OptLevel) -> Result<(), String> {
+    let input = args.get(0).ok_or_else(|| USAGE.to_string())?;
+
+    if input.ends_with(".mnkc") {
+        let mut f = File::open(input).map_err(|e| format!("{}: {}", input, e))?;
+        let loaded = OwnedBytecode::read_from(&mut f).map_err(|e| format!("{}", e))?;
+        print!("{}", disassemble(&loaded.bytecode(), None));
+    } else {
+        let source = read_source(input)?;
+        let com =
+            compile_with_opt_level(&source, opt_level).map_err(|e| fmt_errors(&e))?;
+        print!("{}", disassemble(&com.bytecode(), Some(&source)));
+    }
+    Ok(())
+}
+
+/// Compile a monkey script to a WebAssembly module.
+fn wasm_file(args: &[String]) -> Result<(), String> {
+    let input = args.get(0).ok_or_else(|| USAGE.to_string())?;
+    let output = match (args.get(1).map(|s| &s[..]), args.get(2)) {
+        (Some("-o"), Some(output)) => output.to_string(),
+        (None, _) => Path::new(input)
+            .with_extension("wasm")
+            .to_string_lossy()
+            .to_string(),
+        _ => return Err(USAGE.to_string()),
+    };
+
+    let module = compile_wasm(&read_source(input)?).map_err(|e| fmt_errors(&e))?;
+    std::fs::write(&output, module).map_err(|e| format!("{}: {}", output, e))
+}
+
+/// Transpile a monkey script to a Rust module.
+fn transpile_file(args: &[String]) -> Result<(), String> {
+    let input = args.get(0).ok_or_else(|| USAGE.to_string())?;
+    let output = match (args.get(1).map(|s| &s[..]), args.get(2)) {
+        (Some("-o"), Some(output)) => output.to_string(),
+        (None, _) => Path::new(input)
+            .with_extension("rs")
+            .to_string_lossy()
+            .to_string(),
+        _ => return Err(USAGE.to_string()),
+    };
+
+    let module = transpile(&read_source(input)?).map_err(|e| fmt_errors(&e))?;
+    std::fs::write(&output, module).map_err(|e| format!("{}: {}", output, e))
+}
+
+fn run(bytecode: &Bytecode) -> Result<(), String> {
+    let result = run_vm(bytecode).map_err(|e| format!("{}", e))?;
+    println!("{}", result);
+    Ok(())
+}
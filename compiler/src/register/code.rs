@@ -0,0 +1,106 @@
+//! Instructions of the register vm.
+//!
+//! Every instruction names the registers it reads and writes, so values don't have to
+//! be pushed and popped. Registers are numbered from the base of the frame: the
+//! parameters and locals of a function come first, then the temporaries.
+//! Registers take two operand bytes, constants, globals and jump targets four.
+use crate::code::Operand;
+use num_enum::TryFromPrimitive;
+use std::convert::TryFrom;
+
+#[derive(PartialEq, Eq, Copy, Clone, Debug, TryFromPrimitive)]
+#[repr(u8)]
+pub enum RegOp {
+    LoadConst,       // A K: constant K into A
+    LoadTrue,        // A
+    LoadFalse,       // A
+    LoadNull,        // A
+    LoadIgnore,      // A: the value of a let statement into A
+    Move,            // A B: copy B into A
+    GetGlobal,       // A G
+    SetGlobal,       // G B
+    GetBuiltin,      // A I: builtin I into A
+    CurrentFunction, // A: the function that is being executed
+    Add,             // A B C: A = B + C
+    Sub,             // A B C
+    Mul,             // A B C
+    Div,             // A B C
+    Equal,           // A B C
+    NotEqual,        // A B C
+    GT,              // A B C
+    Minus,           // A B: A = -B
+    Bang,            // A B: A = !B
+    Jump,            // T: continue at offset T
+    JumpIfFalse,     // B T: continue at offset T if B is not truthy
+    Array,           // A B N: array of the N registers from B
+    Hash,            // A B N: hash of the N keys and values from B
+    Index,           // A B C: A = B[C]
+    Call,            // A F N: call F with the N arguments behind it, result into A
+    CallMethod,      // A K B N: call the method named by constant K on B with N args
+    Return,          // B: return B to the caller
+    ReturnNull,      // return null to the caller
+}
+
+pub type Register = usize;
+
+/// Most registers a frame can have.
+pub const MAX_REGISTERS: usize = u16::MAX as usize + 1;
+
+impl RegOp {
+    /// Width of the operands.
+    pub fn definition(&self) -> &'static [usize] {
+        use RegOp::*;
+        match self {
+            LoadTrue | LoadFalse | LoadNull | LoadIgnore | CurrentFunction | Return => {
+                &[2]
+            }
+            Move | GetBuiltin | Minus | Bang => &[2, 2],
+            LoadConst | GetGlobal | JumpIfFalse => &[2, 4],
+            SetGlobal => &[4, 2],
+            Add | Sub | Mul | Div | Equal | NotEqual | GT | Array | Hash | Index
+            | Call => &[2, 2, 2],
+            CallMethod => &[2, 4, 2, 2],
+            Jump => &[4],
+            ReturnNull => &[],
+        }
+    }
+
+    pub fn make(&self, operands: &[Operand]) -> Vec<u8> {
+        let mut instr = vec![*self as u8];
+        for (operand, width) in operands.iter().zip(self.definition()) {
+            let bytes = operand.to_be_bytes();
+            instr.extend_from_slice(&bytes[bytes.len() - width..]);
+        }
+        instr
+    }
+}
+
+/// Read the instruction at `ip`. Returns the opcode, its operands and the offset of the
+/// next instruction.
+pub fn read_instruction(code: &[u8], ip: usize) -> (RegOp, [Operand; 4], usize) {
+    let op = RegOp::try_from(code[ip]).expect("the register compiler emits valid code");
+    let mut operands = [0; 4];
+    let mut offset = ip + 1;
+    for (i, width) in op.definition().iter().enumerate() {
+        operands[i] = code[offset..offset + width]
+            .iter()
+            .fold(0, |n, byte| n << 8 | *byte as Operand);
+        offset += width;
+    }
+    (op, operands, offset)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_make() {
+        let ins = RegOp::CallMethod.make(&[1, 65_536, 2, 3]);
+        assert_eq!(ins, [RegOp::CallMethod as u8, 0, 1, 0, 1, 0, 0, 0, 2, 0, 3]);
+        assert_eq!(
+            read_instruction(&ins, 0),
+            (RegOp::CallMethod, [1, 65_536, 2, 3], ins.len())
+        );
+    }
+}
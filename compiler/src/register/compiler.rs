@@ -0,0 +1,638 @@
+//! Compiles the AST to instructions of the register vm.
+use crate::compiler::compiler::{ends_with_let, MAX_CONSTANTS};
+use crate::compiler::interner::Interner;
+use crate::compiler::symbol_table::{Scope, Symbol, SymbolTable};
+use crate::err::CompileError;
+use crate::register::code::{RegOp, Register, MAX_REGISTERS};
+use monkey::eval::builtins::BUILTIN_LIST;
+use monkey::eval::object::{CompiledFunction, LineTable, Object};
+use monkey::lexer::token::Span;
+use monkey::parser::ast::{Expression, Statement};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+/// A program for the register vm. The functions are compiled functions in the
+/// constants pool, with their number of registers as their number of locals.
+#[derive(Debug)]
+pub struct Program {
+    pub instructions: Vec<u8>,
+    pub num_registers: usize,
+    pub constants: Vec<Object>,
+    pub line_table: LineTable,
+}
+
+/// The main program keeps the value of the last statement in this register.
+pub const RESULT: Register = 0;
+
+struct FunctionScope {
+    instructions: Vec<u8>,
+    line_table: LineTable,
+    next_register: Register, // first free temporary
+    num_registers: usize,
+}
+
+impl FunctionScope {
+    /// The first `num_locals` registers are reserved for the parameters and locals.
+    fn new(num_locals: usize) -> FunctionScope {
+        FunctionScope {
+            instructions: vec![],
+            line_table: LineTable::new(),
+            next_register: num_locals,
+            num_registers: num_locals,
+        }
+    }
+}
+
+pub struct RegisterCompiler {
+    scopes: Vec<FunctionScope>,
+    constants: Vec<Object>,
+    // Ints and strings are added to the constants pool only once.
+    interned: HashMap<Object, usize>,
+    // Identifiers and strings are stored once.
+    interner: Interner,
+    symbol_table: Rc<RefCell<SymbolTable>>,
+    span: Span, // location of the statement that is being compiled
+}
+
+/// Compile all statements. Like the stack compiler, compilation continues after a
+/// statement that fails to compile, such that all errors are reported.
+pub fn compile_program(program: &[Statement]) -> Result<Program, Vec<CompileError>> {
+    let symbol_table = SymbolTable::new();
+    let mut interner = Interner::new();
+    for (i, builtin) in BUILTIN_LIST.iter().enumerate() {
+        symbol_table
+            .borrow_mut()
+            .define_builtin(i, interner.intern(&builtin.identifier));
+    }
+    let mut com = RegisterCompiler {
+        scopes: vec![FunctionScope::new(RESULT + 1)],
+        constants: vec![],
+        interned: HashMap::new(),
+        interner,
+        symbol_table,
+        span: Span::default(),
+    };
+    // Predeclare the globals so that functions can use the ones defined behind them.
+    for stmt in program {
+        if let Statement::Let(identifier, ..) = stmt {
+            com.define(identifier);
+        }
+    }
+    let errors: Vec<CompileError> = program
+        .iter()
+        .filter_map(|stmt| com.compile_stmt(stmt, Some(RESULT)).err())
+        .collect();
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    if program.last().is_some_and(ends_with_let) {
+        com.emit(RegOp::LoadIgnore, &[RESULT]);
+    }
+    let main = com.scopes.pop().unwrap();
+    Ok(Program {
+        instructions: main.instructions,
+        num_registers: main.num_registers,
+        constants: com.constants,
+        line_table: main.line_table,
+    })
+}
+
+impl RegisterCompiler {
+    fn scope(&mut self) -> &mut FunctionScope {
+        self.scopes.last_mut().unwrap()
+    }
+
+    fn emit(&mut self, op: RegOp, operands: &[usize]) -> usize {
+        let span = self.span;
+        let scope = self.scope();
+        let pos = scope.instructions.len();
+        scope.instructions.extend(op.make(operands));
+        scope.line_table.add(pos, span);
+        pos
+    }
+
+    /// Let the jump at `pos` continue behind the last emitted instruction.
+    fn patch_jump(&mut self, pos: usize) {
+        let scope = self.scope();
+        let target = scope.instructions.len();
+        let op = if scope.instructions[pos] == RegOp::Jump as u8 {
+            RegOp::Jump
+        } else {
+            RegOp::JumpIfFalse
+        };
+        let target_offset = pos + op.make(&[0, 0]).len() - 4;
+        scope.instructions[target_offset..target_offset + 4]
+            .copy_from_slice(&(target as u32).to_be_bytes());
+    }
+
+    /// Reserve `n` consecutive temporaries and return the first one.
+    fn alloc(&mut self, n: usize) -> Result<Register, CompileError> {
+        let span = self.span;
+        let scope = self.scope();
+        let first = scope.next_register;
+        scope.next_register += n;
+        if scope.next_register > MAX_REGISTERS {
+            return Err(CompileError::Unsupported(
+                format!("function with more than {} registers", MAX_REGISTERS),
+                span,
+            ));
+        }
+        scope.num_registers = scope.num_registers.max(scope.next_register);
+        Ok(first)
+    }
+
+    /// Free the temporaries from `mark` on.
+    fn free(&mut self, mark: Register) {
+        self.scope().next_register = mark;
+    }
+
+    /// Define the name in the current scope.
+    fn define(&mut self, name: &str) -> Symbol {
+        let name = self.interner.intern(name);
+        self.symbol_table.borrow_mut().define(name)
+    }
+
+    fn add_constant(&mut self, obj: Object) -> Result<usize, CompileError> {
+        let internable = matches!(obj, Object::Int(_) | Object::String(_));
+        if internable {
+            if let Some(index) = self.interned.get(&obj) {
+                return Ok(*index);
+            }
+        }
+        if self.constants.len() >= MAX_CONSTANTS {
+            return Err(CompileError::TooManyConstants(self.span));
+        }
+        let index = self.constants.len();
+        if internable {
+            self.interned.insert(obj.clone(), index);
+        }
+        self.constants.push(obj);
+        Ok(index)
+    }
+
+    /// Compile the statement. The value of an expression or let statement is written to
+    /// `result`, if given.
+    fn compile_stmt(
+        &mut self,
+        stmt: &Statement,
+        result: Option<Register>,
+    ) -> Result<(), CompileError> {
+        // Expressions are reported at the location of the statement they are part of.
+        let outer_span = self.span;
+        if let Some(span) = stmt.span() {
+            self.span = span;
+        }
+        let mark = self.scope().next_register;
+        let compiled = self.compile_stmt_inner(stmt, result);
+        self.free(mark);
+        self.span = outer_span;
+        compiled
+    }
+
+    fn compile_stmt_inner(
+        &mut self,
+        stmt: &Statement,
+        result: Option<Register>,
+    ) -> Result<(), CompileError> {
+        match stmt {
+            Statement::Expr(expr, _) => match result {
+                Some(result) => self.compile_expr(expr, result)?,
+                None => {
+                    self.compile_operand(expr)?;
+                }
+            },
+            Statement::Block(stmts) => {
+                for stmt in stmts.iter() {
+                    self.compile_stmt(stmt, result)?;
+                }
+            }
+            Statement::Let(identifier, expr, _) => {
+                let value = match result {
+                    Some(result) => result,
+                    None => self.alloc(1)?,
+                };
+                match expr {
+                    Expression::FunctionLiteral { parameters, body } => self
+                        .compile_function_literal(
+                            parameters,
+                            body,
+                            Some(identifier),
+                            value,
+                        )?,
+                    _ => self.compile_expr(expr, value)?,
+                }
+                let smbl = self.define(identifier);
+                match smbl.scope {
+                    Scope::Global => self.emit(RegOp::SetGlobal, &[smbl.index, value]),
+                    Scope::Local => self.emit(RegOp::Move, &[smbl.index, value]),
+                    Scope::Function | Scope::Builtin | Scope::Captured => unreachable!(),
+                };
+            }
+            Statement::Return(expr, _) => {
+                let value = self.compile_operand(expr)?;
+                self.emit(RegOp::Return, &[value]);
+            }
+        }
+        Ok(())
+    }
+
+    /// Compile the expression to a register. Locals are used in place.
+    fn compile_operand(&mut self, expr: &Expression) -> Result<Register, CompileError> {
+        if let Some(local) = self.local(expr) {
+            return Ok(local);
+        }
+        let register = self.alloc(1)?;
+        self.compile_expr(expr, register)?;
+        Ok(register)
+    }
+
+    /// Compile two operands that are evaluated from left to right.
+    fn compile_operands(
+        &mut self,
+        left: &Expression,
+        right: &Expression,
+    ) -> Result<(Register, Register), CompileError> {
+        // A let in the right operand can overwrite the local of the left one.
+        let left = if count_lets_expr(right) > 0 {
+            let register = self.alloc(1)?;
+            self.compile_expr(left, register)?;
+            register
+        } else {
+            self.compile_operand(left)?
+        };
+        let right = self.compile_operand(right)?;
+        Ok((left, right))
+    }
+
+    /// The register of the local that the expression names.
+    fn local(&self, expr: &Expression) -> Option<Register> {
+        match expr {
+            Expression::Identifier(ident) => {
+                match self.symbol_table.borrow().resolve(ident) {
+                    Some(smbl) => match smbl.scope {
+                        Scope::Local => Some(smbl.index),
+                        _ => None,
+                    },
+                    None => None,
+                }
+            }
+            _ => None,
+        }
+    }
+
+    /// Compile the expression, such that its value ends up in `dst`.
+    fn compile_expr(
+        &mut self,
+        expr: &Expression,
+        dst: Register,
+    ) -> Result<(), CompileError> {
+        let mark = self.scope().next_register;
+        match expr {
+            Expression::IntegerLiteral(v) => {
+                let constant = self.add_constant(Object::Int(*v))?;
+                self.emit(RegOp::LoadConst, &[dst, constant]);
+            }
+            Expression::StringLiteral(s) => {
+                let s = Object::String(self.interner.intern(s));
+                let constant = self.add_constant(s)?;
+                self.emit(RegOp::LoadConst, &[dst, constant]);
+            }
+            Expression::Bool(true) => {
+                self.emit(RegOp::LoadTrue, &[dst]);
+            }
+            Expression::Bool(false) => {
+                self.emit(RegOp::LoadFalse, &[dst]);
+            }
+            Expression::Prefix { operator, expr } => {
+                let right = self.compile_operand(expr)?;
+                let op = match &operator[..] {
+                    "-" => RegOp::Minus,
+                    "!" => RegOp::Bang,
+                    op => {
+                        return Err(CompileError::UnknownOperator(
+                            op.to_string(),
+                            self.span,
+                        ))
+                    }
+                };
+                self.emit(op, &[dst, right]);
+            }
+            Expression::Infix {
+                left,
+                operator,
+                right,
+            } => {
+                // `<` is `>` with the operands flipped, which are then also evaluated
+                // from right to left, like on the stack vm.
+                let (left, right) = if operator == "<" {
+                    let (r, l) = self.compile_operands(right, left)?;
+                    (l, r)
+                } else {
+                    self.compile_operands(left, right)?
+                };
+                let op = match &operator[..] {
+                    "+" => RegOp::Add,
+                    "-" => RegOp::Sub,
+                    "*" => RegOp::Mul,
+                    "/" => RegOp::Div,
+                    "==" => RegOp::Equal,
+                    "!=" => RegOp::NotEqual,
+                    ">" => RegOp::GT,
+                    "<" => {
+                        self.emit(RegOp::GT, &[dst, right, left]);
+                        self.free(mark);
+                        return Ok(());
+                    }
+                    op => {
+                        return Err(CompileError::UnknownOperator(
+                            op.to_string(),
+                            self.span,
+                        ))
+                    }
+                };
+                self.emit(op, &[dst, left, right]);
+            }
+            Expression::IfExpression {
+                condition,
+                consequence,
+                alternative,
+            } => {
+                let condition = self.compile_operand(condition)?;
+                let jump_if_false = self.emit(RegOp::JumpIfFalse, &[condition, 0]);
+                self.free(mark);
+                self.compile_branch(consequence, dst)?;
+                let jump = self.emit(RegOp::Jump, &[0]);
+                self.patch_jump(jump_if_false);
+                match alternative {
+                    Some(alternative) => self.compile_branch(alternative, dst)?,
+                    None => {
+                        self.emit(RegOp::LoadNull, &[dst]);
+                    }
+                }
+                self.patch_jump(jump);
+            }
+            Expression::Identifier(ident) => {
+                let smbl = self.symbol_table.borrow().resolve(ident);
+                match smbl {
+                    None => {
+                        return Err(CompileError::UndefinedVariable(
+                            ident.to_string(),
+                            self.span,
+                        ))
+                    }
+                    Some(smbl) => match smbl.scope {
+                        Scope::Local if smbl.index == dst => {}
+                        Scope::Local => {
+                            self.emit(RegOp::Move, &[dst, smbl.index]);
+                        }
+                        Scope::Global => {
+                            self.emit(RegOp::GetGlobal, &[dst, smbl.index]);
+                        }
+                        Scope::Function => {
+                            self.emit(RegOp::CurrentFunction, &[dst]);
+                        }
+                        Scope::Builtin => {
+                            self.emit(RegOp::GetBuiltin, &[dst, smbl.index]);
+                        }
+                        Scope::Captured => {
+                            return Err(CompileError::Unsupported(
+                                format!("captured variable: {}", ident),
+                                self.span,
+                            ))
+                        }
+                    },
+                }
+            }
+            Expression::ArrayLiteral(exprs) => {
+                let first = self.alloc(exprs.len())?;
+                for (i, expr) in exprs.iter().enumerate() {
+                    self.compile_expr(expr, first + i)?;
+                }
+                self.emit(RegOp::Array, &[dst, first, exprs.len()]);
+            }
+            Expression::HashLiteral { keys, values } => {
+                let first = self.alloc(keys.len() * 2)?;
+                for (i, (key, value)) in keys.iter().zip(values.iter()).enumerate() {
+                    self.compile_expr(key, first + 2 * i)?;
+                    self.compile_expr(value, first + 2 * i + 1)?;
+                }
+                self.emit(RegOp::Hash, &[dst, first, keys.len() * 2]);
+            }
+            Expression::IndexExpr { left, index } => {
+                let (left, index) = self.compile_operands(left, index)?;
+                self.emit(RegOp::Index, &[dst, left, index]);
+            }
+            Expression::FunctionLiteral { parameters, body } => {
+                self.compile_function_literal(parameters, body, None, dst)?;
+            }
+            Expression::CallExpr { function, args } => {
+                // The arguments follow the function, they are the first registers of
+                // the frame of the called function.
+                let first = self.alloc(args.len() + 1)?;
+                self.compile_expr(function, first)?;
+                for (i, arg) in args.iter().enumerate() {
+                    self.compile_expr(arg, first + 1 + i)?;
+                }
+                self.emit(RegOp::Call, &[dst, first, args.len()]);
+            }
+            Expression::Method {
+                left,
+                identifier,
+                args,
+            } => {
+                let first = self.alloc(args.len() + 1)?;
+                self.compile_expr(left, first)?;
+                for (i, arg) in args.iter().enumerate() {
+                    self.compile_expr(arg, first + 1 + i)?;
+                }
+                let method_name = match &**identifier {
+                    Expression::Identifier(s) => Object::String(self.interner.intern(s)),
+                    ident => {
+                        return Err(CompileError::Unsupported(
+                            format!("method name: {}", ident),
+                            self.span,
+                        ))
+                    }
+                };
+                let name = self.add_constant(method_name)?;
+                self.emit(RegOp::CallMethod, &[dst, name, first, args.len()]);
+            }
+            _ => {
+                return Err(CompileError::Unsupported(
+                    format!("expression: {}", expr),
+                    self.span,
+                ))
+            }
+        }
+        self.free(mark);
+        Ok(())
+    }
+
+    /// Compile a branch of an if expression. The value of its last statement is written
+    /// to `dst`, or null if the branch doesn't end with an expression or a let.
+    fn compile_branch(
+        &mut self,
+        block: &Statement,
+        dst: Register,
+    ) -> Result<(), CompileError> {
+        let stmts = statements(block);
+        let (last, init) = match stmts.split_last() {
+            Some(split) => split,
+            None => {
+                self.emit(RegOp::LoadNull, &[dst]);
+                return Ok(());
+            }
+        };
+        for stmt in init {
+            self.compile_stmt(stmt, None)?;
+        }
+        match last {
+            Statement::Expr(..) => self.compile_stmt(last, Some(dst))?,
+            Statement::Return(..) => self.compile_stmt(last, None)?,
+            _ => {
+                self.compile_stmt(last, None)?;
+                let op = if ends_with_let(last) {
+                    RegOp::LoadIgnore
+                } else {
+                    RegOp::LoadNull
+                };
+                self.emit(op, &[dst]);
+            }
+        }
+        Ok(())
+    }
+
+    /// Compile a function literal to a constant and load it into `dst`. If the function
+    /// is bound by a let statement, `name` is available in its body.
+    fn compile_function_literal(
+        &mut self,
+        parameters: &[Expression],
+        body: &Statement,
+        name: Option<&str>,
+        dst: Register,
+    ) -> Result<(), CompileError> {
+        let outer = Rc::clone(&self.symbol_table);
+        self.symbol_table = SymbolTable::new_enclosed(Rc::clone(&outer));
+        self.scopes
+            .push(FunctionScope::new(parameters.len() + count_lets(body)));
+        if let Some(name) = name {
+            let name = self.interner.intern(name);
+            self.symbol_table.borrow_mut().define_function_name(name);
+        }
+        for param in parameters.iter() {
+            if let Expression::Identifier(ident) = param {
+                let name = self.interner.intern(ident);
+                self.symbol_table.borrow_mut().define_parameter(name);
+            }
+        }
+        // A local function that calls another one resolves it as a captured variable.
+        self.predeclare_functions(statements(body));
+        let result = self.compile_body(body);
+        // Always leave the scope, also on errors.
+        let scope = self.scopes.pop().unwrap();
+        self.symbol_table = outer;
+        result?;
+
+        let function = Object::CompiledFunction(Rc::new(CompiledFunction {
+            instructions: scope.instructions,
+            num_locals: scope.num_registers,
+            num_parameters: parameters.len(),
+            line_table: scope.line_table,
+        }));
+        let constant = self.add_constant(function)?;
+        self.emit(RegOp::LoadConst, &[dst, constant]);
+        Ok(())
+    }
+
+    fn predeclare_functions(&mut self, stmts: &[Statement]) {
+        for stmt in stmts {
+            if let Statement::Let(identifier, Expression::FunctionLiteral { .. }, _) =
+                stmt
+            {
+                self.define(identifier);
+            }
+        }
+    }
+
+    /// The value of the last statement is returned, or null if the body doesn't end
+    /// with an expression or a let.
+    fn compile_body(&mut self, body: &Statement) -> Result<(), CompileError> {
+        let stmts = statements(body);
+        let (last, init) = match stmts.split_last() {
+            Some(split) => split,
+            None => {
+                self.emit(RegOp::ReturnNull, &[]);
+                return Ok(());
+            }
+        };
+        for stmt in init {
+            self.compile_stmt(stmt, None)?;
+        }
+        match last {
+            Statement::Expr(..) => {
+                let value = self.alloc(1)?;
+                self.compile_stmt(last, Some(value))?;
+                self.emit(RegOp::Return, &[value]);
+            }
+            Statement::Return(..) => self.compile_stmt(last, None)?,
+            _ if ends_with_let(last) => {
+                self.compile_stmt(last, None)?;
+                let value = self.alloc(1)?;
+                self.emit(RegOp::LoadIgnore, &[value]);
+                self.emit(RegOp::Return, &[value]);
+            }
+            _ => {
+                self.compile_stmt(last, None)?;
+                self.emit(RegOp::ReturnNull, &[]);
+            }
+        }
+        Ok(())
+    }
+}
+
+fn statements(block: &Statement) -> &[Statement] {
+    match block {
+        Statement::Block(stmts) => stmts,
+        stmt => std::slice::from_ref(stmt),
+    }
+}
+
+/// Number of let statements in the function body, outside of nested functions. An upper
+/// bound of the number of locals, names that are bound twice are counted twice.
+fn count_lets(stmt: &Statement) -> usize {
+    match stmt {
+        Statement::Let(_, expr, _) => 1 + count_lets_expr(expr),
+        Statement::Expr(expr, _) | Statement::Return(expr, _) => count_lets_expr(expr),
+        Statement::Block(stmts) => stmts.iter().map(count_lets).sum(),
+    }
+}
+
+fn count_lets_expr(expr: &Expression) -> usize {
+    let exprs = |exprs: &[Expression]| exprs.iter().map(count_lets_expr).sum::<usize>();
+    match expr {
+        Expression::Prefix { expr, .. } => count_lets_expr(expr),
+        Expression::Infix { left, right, .. } => {
+            count_lets_expr(left) + count_lets_expr(right)
+        }
+        Expression::IfExpression {
+            condition,
+            consequence,
+            alternative,
+        } => {
+            count_lets_expr(condition)
+                + count_lets(consequence)
+                + alternative.as_ref().map_or(0, |alt| count_lets(alt))
+        }
+        Expression::CallExpr { function, args } => {
+            count_lets_expr(function) + exprs(args)
+        }
+        Expression::ArrayLiteral(elements) => exprs(elements),
+        Expression::HashLiteral { keys, values } => exprs(keys) + exprs(values),
+        Expression::IndexExpr { left, index } => {
+            count_lets_expr(left) + count_lets_expr(index)
+        }
+        Expression::Method { left, args, .. } => count_lets_expr(left) + exprs(args),
+        _ => 0,
+    }
+}
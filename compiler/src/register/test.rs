@@ -0,0 +1,206 @@
+#![cfg(test)]
+use super::code::{read_instruction, RegOp};
+use super::compiler::{Program, RESULT};
+use super::vm::run_program;
+use crate::err::VMError;
+use crate::utils::{compile, compile_register};
+use crate::vm::vm::run_vm;
+use monkey::eval::object::{LineTable, Object};
+
+/// Run the program on the register vm and on the stack vm, which must agree on the
+/// result, or on the error and where it happened.
+fn run_both(input: &str) {
+    let program = compile_register(input).unwrap();
+    let registers = run_program(&program);
+    let com = compile(input).unwrap();
+    let stack = run_vm(&com.bytecode());
+    match (registers, stack) {
+        (Ok(registers), Ok(stack)) => {
+            // arrays and hashes are not comparable, their listings are
+            assert_eq!(format!("{}", registers), format!("{}", stack), "{}", input);
+        }
+        (Err(registers), Err(stack)) => {
+            assert_eq!(registers.error, stack.error, "{}", input);
+            assert_eq!(registers.span, stack.span, "{}", input);
+        }
+        (registers, stack) => panic!("{}: {:?} != {:?}", input, registers, stack),
+    }
+}
+
+#[test]
+fn test_same_results() {
+    let inputs = [
+        "1 + 2 * 3 - 4 / 2",
+        "50 / 2 * 2 + 10 - 5",
+        "-(1 - 3) * 2",
+        r#""mon" + "key""#,
+        "1 < 2 == !false",
+        "!!true",
+        "!5",
+        "true != false",
+        "if (1 > 2) { 10 } else { 20 }",
+        "if (0) { 10 }",
+        "if (true) { }",
+        "if (true) { let a = 1; }",
+        "let a = 5; let b = a * 2; b - a",
+        "1; 2; 3",
+        "let a = 1; 2; a",
+        "let a = 1;",
+        "[1, 2 * 2, 3 + 3]",
+        "[]",
+        "[1 + 1, 2 * 2][3 - 2]",
+        "[[1, 1, 1]][0][0]",
+        "[1, 2, 3][-1]",
+        "[1, 2, 3][3]",
+        "len({1: 2, 2: 3})",
+        r#"{"a" + "b": 1 + 1}["ab"]"#,
+        "{1: 1}[0]",
+        "1[0]",
+        "9223372036854775807 + 0",
+        "let f = fn() { }; f()",
+        "let f = fn() { let x = 1; }; f()",
+        "let f = fn() { let a = 1; }; f()",
+        "let f = fn() { 1; 2; 3 }; f()",
+        "let f = fn(a, b) { 1; a + b }; f(3, 4)",
+        r#"let f = fn(a, b) { a + b }; f("mon", "key")"#,
+        "let f = fn(a) { let b = 0; let c = 1; a + b + c + 255 + 256 }; f(-1)",
+        "let f = fn(x) { if (true) { x * (2 + 3) } }; f(2)",
+        "let f = fn(x) { if (!true) { return 1; } x }; f(3)",
+        "let f = fn(x) { if (x > 1) { return x; } }; [f(1), f(2)]",
+        "let f = fn(a, b) { let a = b; let b = a + 1; a + b }; f(1, 2)",
+        // the let overwrites the local after it is used as the left operand
+        "let f = fn(a) { a + if (true) { let a = 5; a } else { 0 } }; f(1)",
+        "let f = fn(a) { let g = fn(b) { b * 2 }; g(a) + g(a) }; f(3)",
+        "let f = fn() { fn(x) { x + 1 } }; f()(1)",
+        "let x = 3; if (x > 1) { if (x > 2) { 1 } else { 2 } } else { 3 }",
+        "let x = 0; if (x > 1) { if (x > 2) { 1 } } else { if (x < 1) { 4 } }",
+        "let globalNum = 10;
+let sum = fn(a, b) { let c = a + b; c + globalNum; };
+let outer = fn() { sum(1, 2) + sum(3, 4) + globalNum; };
+outer() + globalNum;",
+        "let wrapper = fn() {
+  let countDown = fn(x) { if (x == 0) { return 0; } else { countDown(x - 1); } };
+  countDown(1);
+};
+wrapper();",
+        "let fib = fn(n) { if (n < 2) { n } else { fib(n - 1) + fib(n - 2) } }; fib(15)",
+        // a nested function calls the global function that encloses it
+        "let f = fn(n) { let g = fn(k) { if (k == 0) { 0 } else { f(k - 1) + 1 } }; g(n) }; f(3)",
+        "let isEven = fn(n) { if (n == 0) { true } else { isOdd(n - 1) } };
+let isOdd = fn(n) { if (n == 0) { false } else { isEven(n - 1) } };
+[isEven(10), isOdd(10), isEven(7)]",
+        r#"len("four") + len([1, 2, 3])"#,
+        "len(1)",
+        "let f = fn(x) { len(x) }; f([1, 2])",
+        "let len = fn(x) { 1 }; len([1, 2])",
+        r#"print("monkey")"#,
+        "let h = {1: 1}; h.insert(2, 4); h[2]",
+        "let h = {}; let f = fn(k, v) { h.insert(k, v) }; f(1, 2); h[1]",
+        "[1].insert(1, 2)",
+        "{}.foo()",
+        // errors
+        "1 / 0",
+        r#""a" - "b""#,
+        "-true",
+        "1 + true",
+        "true > false",
+        "let a = 1; a()",
+        "fn(a, b) { a + b; }(1);",
+        "let f = fn(a, b) {\n a + b\n}; f(1, true)",
+        "let a = 1;
+let f = fn(x) {
+  let y = x * 2;
+  y / 0
+};
+f(a)",
+    ];
+    for input in inputs.iter() {
+        run_both(input);
+    }
+}
+
+#[test]
+fn test_compile_errors() {
+    let inputs = [
+        "a",
+        "let f = fn() { b }; 1",
+        "1 % 2",
+        // the registers of a function cannot hold the locals of enclosing functions
+        "let make = fn(x) { fn(y) { x + y } }; let addtwo = make(2); addtwo(40)",
+        "let outer = fn() { let a = 1; let b = 100; let inner = fn(z) { b }; inner(7) }; outer()",
+        "let f = fn(n) { let g = fn() { if (n == 0) { 0 } else { f(n - 1) } }; g() }; f(3)",
+        "let w = fn() { let f = fn(k) { let g = fn() { f }; g() }; f(1) }; w()",
+        "let f = fn(n) { let a = fn(k) { b(k) }; let b = fn(k) { a(k) }; a(n) }; f(4)",
+    ];
+    for input in inputs.iter() {
+        let errors = compile_register(input).unwrap_err();
+        let expected = compile(input).err().unwrap();
+        assert_eq!(
+            format!("{:?}", errors),
+            format!("{:?}", expected),
+            "{}",
+            input
+        );
+    }
+}
+
+#[test]
+fn test_no_current_function() {
+    let program = Program {
+        instructions: RegOp::CurrentFunction.make(&[RESULT]),
+        num_registers: 1,
+        constants: vec![],
+        line_table: LineTable::new(),
+    };
+    let err = run_program(&program).unwrap_err();
+    assert_eq!(err.error, VMError::NoCurrentFunction);
+}
+
+/// A return statement ends the main program, like in the evaluator.
+#[test]
+fn test_return_from_main() {
+    let program = compile_register("1; return 2; 3").unwrap();
+    assert_eq!(run_program(&program).unwrap(), Object::Int(2));
+}
+
+#[test]
+fn test_recursion_limit() {
+    let input = "let f = fn(x) { f(x) }; f(1)";
+    let program = compile_register(input).unwrap();
+    let err = run_program(&program).unwrap_err();
+    assert_eq!(err.error, VMError::FrameOverflow);
+    assert_eq!(err.span.unwrap().line, 1);
+
+    let input = "let f = fn(x) { if (x == 0) { 0 } else { 1 + f(x - 1) } }; f(1000)";
+    let program = compile_register(input).unwrap();
+    assert_eq!(run_program(&program).unwrap(), Object::Int(1000));
+}
+
+/// Parameters and locals are used in place, without moving them to the stack.
+#[test]
+fn test_locals_in_place() {
+    let program =
+        compile_register("let f = fn(a, b) { let c = a + b; c * a }; f(1, 2)").unwrap();
+    let cf = match &program.constants[0] {
+        Object::CompiledFunction(cf) => cf,
+        o => panic!("expected a function, got {:?}", o),
+    };
+    let mut code = vec![];
+    let mut ip = 0;
+    while ip < cf.instructions.len() {
+        let (op, operands, next) = read_instruction(&cf.instructions, ip);
+        code.push((op, operands));
+        ip = next;
+    }
+    assert_eq!(
+        code,
+        [
+            (RegOp::Add, [3, 0, 1, 0]),
+            (RegOp::Move, [2, 3, 0, 0]),
+            (RegOp::Mul, [3, 2, 0, 0]),
+            (RegOp::Return, [3, 0, 0, 0]),
+        ]
+    );
+    // the temporary of the sum is reused for the product
+    assert_eq!(cf.num_locals, 4);
+}
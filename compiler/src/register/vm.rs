@@ -0,0 +1,295 @@
+//! Runs the programs of the register compiler.
+use crate::code::OpCode;
+use crate::err::{RuntimeError, VMError};
+use crate::register::code::{read_instruction, RegOp, Register};
+use crate::register::compiler::{Program, RESULT};
+use crate::vm::value::{exec_binary, exec_cmp, exec_prefix, Heap, Value};
+use monkey::eval::{
+    builtins::{call_method, BUILTIN_LIST},
+    object::{CompiledFunction, LineTable, Object},
+};
+use monkey::lexer::token::Span;
+use std::rc::Rc;
+
+/// Most registers of all frames together.
+const MAX_STACK_REGISTERS: usize = 1 << 20;
+const MAX_FRAMES: usize = 1024;
+
+#[derive(Clone)]
+enum Code<'cmpl> {
+    Main(&'cmpl [u8]),
+    Function(Rc<CompiledFunction>),
+}
+
+struct Frame<'cmpl> {
+    code: Code<'cmpl>,
+    ip: usize,        // offset of the instruction that is being executed
+    base: usize,      // index of the first register of the frame
+    ret: usize,       // register of the caller that receives the return value
+    heap_mark: usize, // objects on the heap when the frame was entered
+}
+
+impl Frame<'_> {
+    fn instructions(&self) -> &[u8] {
+        match &self.code {
+            Code::Main(instructions) => instructions,
+            Code::Function(cf) => &cf.instructions,
+        }
+    }
+}
+
+struct RegisterVM<'cmpl> {
+    main_line_table: &'cmpl LineTable,
+    constants: &'cmpl [Object],
+    heap: Heap<'cmpl>,
+    registers: Vec<Value>,
+    globals: Vec<Option<Value>>,
+    frame: Frame<'cmpl>,
+    callers: Vec<Frame<'cmpl>>,
+}
+
+impl RegisterVM<'_> {
+    /// Location in the source of the instruction that is being executed.
+    fn current_span(&self) -> Option<Span> {
+        let line_table = match &self.frame.code {
+            Code::Main(_) => self.main_line_table,
+            Code::Function(cf) => &cf.line_table,
+        };
+        line_table.span_at(self.frame.ip)
+    }
+
+    fn get(&self, register: Register) -> Value {
+        self.registers[self.frame.base + register]
+    }
+
+    fn set(&mut self, register: Register, value: Value) {
+        self.registers[self.frame.base + register] = value;
+    }
+
+    /// Objects of `n` consecutive registers.
+    fn objects(&self, first: Register, n: usize) -> Vec<Object> {
+        let first = self.frame.base + first;
+        self.heap.objects(&self.registers[first..first + n])
+    }
+
+    fn call(
+        &mut self,
+        dst: Register,
+        function: Register,
+        n_args: usize,
+    ) -> Result<(), VMError> {
+        let cf = match self.get(function) {
+            Value::Builtin(b) => {
+                let result =
+                    (BUILTIN_LIST[b].function)(self.objects(function + 1, n_args));
+                let value = self.heap.alloc(result);
+                self.set(dst, value);
+                return Ok(());
+            }
+            Value::Heap(handle) => match self.heap.get(handle) {
+                Object::CompiledFunction(cf) => Rc::clone(cf),
+                o => return Err(VMError::CallingNonFunction(o.get_type())),
+            },
+            v => return Err(VMError::CallingNonFunction(self.heap.get_type(v))),
+        };
+        // Like in the evaluator, the arguments without a parameter are dropped.
+        if n_args < cf.num_parameters {
+            return Err(VMError::WrongNumberOfArgs {
+                want: cf.num_parameters,
+                got: n_args,
+            });
+        }
+        if self.callers.len() + 1 >= MAX_FRAMES {
+            return Err(VMError::FrameOverflow);
+        }
+        // The arguments are the first registers of the function, the function itself
+        // is right below them.
+        let base = self.frame.base + function + 1;
+        let end = base + cf.num_locals;
+        if end > MAX_STACK_REGISTERS {
+            return Err(VMError::StackOverflow);
+        }
+        if self.registers.len() < end {
+            self.registers.resize(end, Value::Null);
+        }
+        let frame = Frame {
+            code: Code::Function(cf),
+            ip: 0,
+            base,
+            ret: self.frame.base + dst,
+            heap_mark: self.heap.mark(),
+        };
+        let caller = std::mem::replace(&mut self.frame, frame);
+        self.callers.push(caller);
+        Ok(())
+    }
+}
+
+pub fn run_program(program: &Program) -> Result<Object, RuntimeError> {
+    let mut vm = RegisterVM {
+        main_line_table: &program.line_table,
+        constants: &program.constants,
+        heap: Heap::new(&program.constants),
+        registers: vec![Value::Null; program.num_registers],
+        // grows with the globals that are set
+        globals: vec![],
+        frame: Frame {
+            code: Code::Main(&program.instructions),
+            ip: 0,
+            base: 0,
+            ret: RESULT,
+            heap_mark: 0,
+        },
+        callers: vec![],
+    };
+    match execute(&mut vm) {
+        Ok(result) => Ok(vm.heap.object(result).into_owned()),
+        Err(error) => Err(RuntimeError {
+            error,
+            span: vm.current_span(),
+        }),
+    }
+}
+
+/// The operators of the stack vm, which implement the same operations.
+fn stack_op(op: RegOp) -> OpCode {
+    match op {
+        RegOp::Add => OpCode::Add,
+        RegOp::Sub => OpCode::Sub,
+        RegOp::Mul => OpCode::Mul,
+        RegOp::Div => OpCode::Div,
+        RegOp::Equal => OpCode::Equal,
+        RegOp::NotEqual => OpCode::NotEqual,
+        RegOp::GT => OpCode::GT,
+        RegOp::Minus => OpCode::Minus,
+        RegOp::Bang => OpCode::Bang,
+        _ => unreachable!("{:?} is not an operator", op),
+    }
+}
+
+/// Execute until the main program ends and return its result.
+fn execute(vm: &mut RegisterVM) -> Result<Value, VMError> {
+    loop {
+        if vm.frame.ip >= vm.frame.instructions().len() {
+            // Only the main program can run off its end, functions always return.
+            return Ok(vm.get(RESULT));
+        }
+        let (op, [a, b, c, d], next) =
+            read_instruction(vm.frame.instructions(), vm.frame.ip);
+        match op {
+            RegOp::LoadConst => vm.set(a, vm.heap.constant(b)),
+            RegOp::LoadTrue => vm.set(a, Value::Bool(true)),
+            RegOp::LoadFalse => vm.set(a, Value::Bool(false)),
+            RegOp::LoadNull => vm.set(a, Value::Null),
+            RegOp::LoadIgnore => {
+                let value = vm.heap.alloc(Object::Ignore);
+                vm.set(a, value);
+            }
+            RegOp::Move => vm.set(a, vm.get(b)),
+            RegOp::GetGlobal => {
+                // Like in the evaluator, a global cannot be used before it is set.
+                let global = vm.globals.get(b).copied().flatten();
+                vm.set(a, global.ok_or(VMError::UnsetGlobal(b))?);
+            }
+            RegOp::SetGlobal => {
+                if a >= vm.globals.len() {
+                    vm.globals.resize(a + 1, None);
+                }
+                vm.globals[a] = Some(vm.get(b));
+            }
+            RegOp::GetBuiltin => vm.set(a, Value::Builtin(b)),
+            RegOp::CurrentFunction => {
+                // the function being executed lies just below its registers.
+                if vm.callers.is_empty() {
+                    return Err(VMError::NoCurrentFunction);
+                }
+                vm.set(a, vm.registers[vm.frame.base - 1])
+            }
+            RegOp::Add | RegOp::Sub | RegOp::Mul | RegOp::Div => {
+                let (left, right) = (vm.get(b), vm.get(c));
+                let result = exec_binary(&mut vm.heap, left, right, stack_op(op))?;
+                vm.set(a, result);
+            }
+            RegOp::Equal | RegOp::NotEqual | RegOp::GT => {
+                let result = exec_cmp(&vm.heap, vm.get(b), vm.get(c), stack_op(op))?;
+                vm.set(a, result);
+            }
+            RegOp::Minus | RegOp::Bang => {
+                let result = exec_prefix(&vm.heap, vm.get(b), stack_op(op))?;
+                vm.set(a, result);
+            }
+            RegOp::Jump => {
+                vm.frame.ip = a;
+                continue;
+            }
+            RegOp::JumpIfFalse => {
+                if !vm.get(a).is_truthy() {
+                    vm.frame.ip = b;
+                    continue;
+                }
+            }
+            RegOp::Array => {
+                let array = Object::new_array(vm.objects(b, c));
+                let value = vm.heap.alloc(array);
+                vm.set(a, value);
+            }
+            RegOp::Hash => {
+                let objects = vm.objects(b, c);
+                let (keys, values) = objects
+                    .chunks(2)
+                    .map(|kv| (kv[0].clone(), kv[1].clone()))
+                    .unzip();
+                let value = vm.heap.alloc(Object::new_hash(keys, values));
+                vm.set(a, value);
+            }
+            RegOp::Index => {
+                let result = {
+                    let index = vm.heap.object(vm.get(c)).into_owned();
+                    vm.heap.object(vm.get(b)).index(index)
+                };
+                let value = vm.heap.alloc(result);
+                vm.set(a, value);
+            }
+            RegOp::Call => {
+                let is_function = !matches!(vm.get(b), Value::Builtin(_));
+                vm.call(a, b, c)?;
+                if is_function {
+                    // continue behind the call on return
+                    vm.callers.last_mut().unwrap().ip = next;
+                    continue;
+                }
+            }
+            RegOp::CallMethod => {
+                let args = vm.objects(c + 1, d);
+                let receiver = vm.heap.object(vm.get(c)).into_owned();
+                let result = match &vm.constants[b] {
+                    Object::String(method_name) => {
+                        call_method(receiver, method_name, args)
+                    }
+                    o => return Err(VMError::InvalidMethodName(o.get_type())),
+                };
+                let value = vm.heap.alloc(result);
+                vm.set(a, value);
+            }
+            RegOp::Return | RegOp::ReturnNull => {
+                let value = match op {
+                    RegOp::Return => vm.get(a),
+                    _ => Value::Null,
+                };
+                match vm.callers.pop() {
+                    Some(caller) => {
+                        // the objects the function created are no longer used
+                        let value = vm.heap.release(vm.frame.heap_mark, value);
+                        let ret = vm.frame.ret;
+                        vm.frame = caller;
+                        vm.registers[ret] = value;
+                        continue;
+                    }
+                    // a return statement in the main program ends it
+                    None => return Ok(value),
+                }
+            }
+        }
+        vm.frame.ip = next;
+    }
+}
@@ -0,0 +1,331 @@
+//! The .mnkc bytecode file format.
+//!
+//! All integers are big endian.
+//!
+//! header:       magic "MNKC", format version (u16), number of globals (u32)
+//! constants:    count (u32), per constant a tag (u8) followed by its payload
+//!               int:      i64
+//!               string:   length (u32), utf-8 bytes
+//!               function: num_locals (u32), num_parameters (u32), code
+//! main program: code
+//!
+//! code:         length (u32), instructions
+//!               line table: count (u32), per entry the instruction offset and the
+//!               start, end and line of its location in the source (all u32)
+use crate::compiler::compiler::Bytecode;
+use crate::err::BytecodeFileError;
+use crate::vm::verify::verify;
+use monkey::eval::object::{CompiledFunction, LineTable, Object};
+use monkey::lexer::token::Span;
+use std::convert::{TryFrom, TryInto};
+use std::io::{Read, Write};
+use std::rc::Rc;
+
+pub const MAGIC: &[u8; 4] = b"MNKC";
+pub const FORMAT_VERSION: u16 = 2;
+
+const TAG_INT: u8 = 0;
+const TAG_STRING: u8 = 1;
+const TAG_FUNCTION: u8 = 2;
+
+/// Bytecode that owns its instructions and constants, e.g. after loading it from a file.
+#[derive(Debug, Clone)]
+pub struct OwnedBytecode {
+    pub instructions: Vec<u8>,
+    pub constants: Vec<Object>,
+    pub line_table: LineTable,
+    pub num_globals: usize,
+}
+
+impl OwnedBytecode {
+    pub fn bytecode(&self) -> Bytecode<'_> {
+        Bytecode {
+            instructions: &self.instructions,
+            constants: &self.constants,
+            line_table: &self.line_table,
+            num_globals: self.num_globals,
+        }
+    }
+
+    pub fn read_from<R: Read>(r: &mut R) -> Result<OwnedBytecode, BytecodeFileError> {
+        let mut input = vec![];
+        r.read_to_end(&mut input)?;
+        deserialize(&input)
+    }
+}
+
+impl Bytecode<'_> {
+    pub fn serialize(&self) -> Result<Vec<u8>, BytecodeFileError> {
+        let mut out = MAGIC.to_vec();
+        out.extend_from_slice(&FORMAT_VERSION.to_be_bytes());
+        write_u32(&mut out, self.num_globals)?;
+
+        write_u32(&mut out, self.constants.len())?;
+        for constant in self.constants {
+            write_constant(&mut out, constant)?;
+        }
+        write_code(&mut out, self.instructions, self.line_table)?;
+        Ok(out)
+    }
+
+    pub fn write_to<W: Write>(&self, w: &mut W) -> Result<(), BytecodeFileError> {
+        w.write_all(&self.serialize()?)?;
+        Ok(())
+    }
+}
+
+/// Read a program from the .mnkc format. The program is verified before it is returned,
+/// so it is safe to run on the vm.
+pub fn deserialize(input: &[u8]) -> Result<OwnedBytecode, BytecodeFileError> {
+    let mut r = Reader { input, pos: 0 };
+
+    if r.read_bytes(MAGIC.len()).ok() != Some(&MAGIC[..]) {
+        return Err(BytecodeFileError::NotBytecode);
+    }
+    let version = r.read_u16()?;
+    if version != FORMAT_VERSION {
+        return Err(BytecodeFileError::IncompatibleVersion(version));
+    }
+    let num_globals = r.read_u32()?;
+
+    let n_constants = r.read_u32()?;
+    let mut constants = Vec::with_capacity(n_constants.min(input.len()));
+    for _ in 0..n_constants {
+        constants.push(r.read_constant()?);
+    }
+    let (instructions, line_table) = r.read_code()?;
+
+    if r.pos != input.len() {
+        return Err(BytecodeFileError::TrailingBytes);
+    }
+    let bytecode = OwnedBytecode {
+        instructions,
+        constants,
+        line_table,
+        num_globals,
+    };
+    verify(&bytecode.bytecode())?;
+    Ok(bytecode)
+}
+
+fn write_u32(out: &mut Vec<u8>, v: usize) -> Result<(), BytecodeFileError> {
+    let v = u32::try_from(v).map_err(|_| BytecodeFileError::TooLarge(v))?;
+    out.extend_from_slice(&v.to_be_bytes());
+    Ok(())
+}
+
+fn write_constant(out: &mut Vec<u8>, constant: &Object) -> Result<(), BytecodeFileError> {
+    match constant {
+        Object::Int(v) => {
+            out.push(TAG_INT);
+            out.extend_from_slice(&v.to_be_bytes());
+        }
+        Object::String(s) => {
+            out.push(TAG_STRING);
+            write_u32(out, s.len())?;
+            out.extend_from_slice(s.as_bytes());
+        }
+        Object::CompiledFunction(cf) => {
+            out.push(TAG_FUNCTION);
+            write_u32(out, cf.num_locals)?;
+            write_u32(out, cf.num_parameters)?;
+            write_code(out, &cf.instructions, &cf.line_table)?;
+        }
+        o => return Err(BytecodeFileError::UnsupportedConstant(o.get_type())),
+    }
+    Ok(())
+}
+
+fn write_code(
+    out: &mut Vec<u8>,
+    instructions: &[u8],
+    line_table: &LineTable,
+) -> Result<(), BytecodeFileError> {
+    write_u32(out, instructions.len())?;
+    out.extend_from_slice(instructions);
+
+    write_u32(out, line_table.len())?;
+    for (offset, span) in line_table.entries() {
+        write_u32(out, *offset)?;
+        write_u32(out, span.start)?;
+        write_u32(out, span.end)?;
+        write_u32(out, span.line)?;
+    }
+    Ok(())
+}
+
+struct Reader<'a> {
+    input: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn read_bytes(&mut self, n: usize) -> Result<&'a [u8], BytecodeFileError> {
+        if self.input.len() - self.pos < n {
+            return Err(BytecodeFileError::UnexpectedEnd);
+        }
+        let bytes = &self.input[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    fn read_u8(&mut self) -> Result<u8, BytecodeFileError> {
+        Ok(self.read_bytes(1)?[0])
+    }
+
+    fn read_u16(&mut self) -> Result<u16, BytecodeFileError> {
+        Ok(u16::from_be_bytes(self.read_bytes(2)?.try_into().unwrap()))
+    }
+
+    fn read_u32(&mut self) -> Result<usize, BytecodeFileError> {
+        Ok(u32::from_be_bytes(self.read_bytes(4)?.try_into().unwrap()) as usize)
+    }
+
+    fn read_i64(&mut self) -> Result<i64, BytecodeFileError> {
+        Ok(i64::from_be_bytes(self.read_bytes(8)?.try_into().unwrap()))
+    }
+
+    fn read_constant(&mut self) -> Result<Object, BytecodeFileError> {
+        let constant = match self.read_u8()? {
+            TAG_INT => Object::Int(self.read_i64()?),
+            TAG_STRING => {
+                let len = self.read_u32()?;
+                let s = std::str::from_utf8(self.read_bytes(len)?)
+                    .map_err(|_| BytecodeFileError::InvalidString)?;
+                Object::from(s)
+            }
+            TAG_FUNCTION => {
+                let num_locals = self.read_u32()?;
+                let num_parameters = self.read_u32()?;
+                let (instructions, line_table) = self.read_code()?;
+                Object::CompiledFunction(Rc::new(CompiledFunction {
+                    instructions,
+                    num_locals,
+                    num_parameters,
+                    line_table,
+                }))
+            }
+            tag => return Err(BytecodeFileError::InvalidConstantTag(tag)),
+        };
+        Ok(constant)
+    }
+
+    fn read_code(&mut self) -> Result<(Vec<u8>, LineTable), BytecodeFileError> {
+        let len = self.read_u32()?;
+        let instructions = self.read_bytes(len)?.to_vec();
+
+        let n_entries = self.read_u32()?;
+        let mut line_table = LineTable::new();
+        for _ in 0..n_entries {
+            let offset = self.read_u32()?;
+            let span = Span::new(self.read_u32()?, self.read_u32()?, self.read_u32()?);
+            line_table.add(offset, span);
+        }
+        Ok((instructions, line_table))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::code::OpCode;
+    use crate::utils::compile;
+    use crate::vm::vm::run_vm;
+
+    fn roundtrip(input: &str) -> OwnedBytecode {
+        let com = compile(input).unwrap();
+        let bytes = com.bytecode().serialize().unwrap();
+        deserialize(&bytes).unwrap()
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let input = r#"let fibonacci = fn(x) {
+  if (x < 2) { x } else { fibonacci(x - 1) + fibonacci(x - 2) }
+};
+let h = {"fib": fibonacci(10)};
+h["f" + "ib"]"#;
+        let com = compile(input).unwrap();
+        let bc = com.bytecode();
+        let loaded = roundtrip(input);
+
+        assert_eq!(loaded.instructions, bc.instructions);
+        assert_eq!(&loaded.line_table, bc.line_table);
+        assert_eq!(
+            format!("{:?}", loaded.constants),
+            format!("{:?}", bc.constants)
+        );
+        assert_eq!(run_vm(&loaded.bytecode()).unwrap(), Object::Int(55));
+    }
+
+    #[test]
+    fn test_header() {
+        let com = compile("1 + 2").unwrap();
+        let bytes = com.bytecode().serialize().unwrap();
+        assert_eq!(&bytes[..4], b"MNKC");
+        assert_eq!(&bytes[4..6], &FORMAT_VERSION.to_be_bytes());
+
+        let mut wrong_version = bytes.clone();
+        wrong_version[4..6].copy_from_slice(&(FORMAT_VERSION + 1).to_be_bytes());
+        match deserialize(&wrong_version) {
+            Err(BytecodeFileError::IncompatibleVersion(v)) => {
+                assert_eq!(v, FORMAT_VERSION + 1)
+            }
+            r => panic!("expected an incompatible version, got: {:?}", r),
+        }
+
+        match deserialize(b"MONKEY") {
+            Err(BytecodeFileError::NotBytecode) => {}
+            r => panic!("expected not bytecode, got: {:?}", r),
+        }
+        match deserialize(&bytes[..bytes.len() - 1]) {
+            Err(BytecodeFileError::UnexpectedEnd) => {}
+            r => panic!("expected an unexpected end, got: {:?}", r),
+        }
+
+        let mut corrupt = bytes.clone();
+        let bc = com.bytecode();
+        let main_start =
+            corrupt.len() - 16 * bc.line_table.len() - 4 - bc.instructions.len();
+        corrupt[main_start] = 255;
+        match deserialize(&corrupt) {
+            Err(BytecodeFileError::Invalid(e)) => {
+                assert_eq!(e.error, crate::err::VerifyError::InvalidOpCode(255))
+            }
+            r => panic!("expected invalid bytecode, got: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_too_many_globals() {
+        let instructions = [
+            OpCode::Null.make(&[]),
+            OpCode::SetGlobal.make(&[0xFFFF_FFF0]),
+        ]
+        .concat();
+        let bc = Bytecode {
+            instructions: &instructions,
+            constants: &[],
+            line_table: &LineTable::new(),
+            num_globals: 0xFFFF_FFFF,
+        };
+        match deserialize(&bc.serialize().unwrap()) {
+            Err(BytecodeFileError::Invalid(e)) => assert_eq!(
+                e.error,
+                crate::err::VerifyError::TooManyGlobals(0xFFFF_FFFF)
+            ),
+            r => panic!("expected invalid bytecode, got: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_too_large() {
+        let mut out = vec![];
+        write_u32(&mut out, u32::MAX as usize).unwrap();
+        match write_u32(&mut out, u32::MAX as usize + 1) {
+            Err(BytecodeFileError::TooLarge(v)) => assert_eq!(v, u32::MAX as usize + 1),
+            r => panic!("expected a too large value, got: {:?}", r),
+        }
+        assert_eq!(out, [255; 4]);
+    }
+}
@@ -0,0 +1,488 @@
+//! Transpiles the AST to the source of a Rust module, which runs the program on the
+//! `runtime` crate.
+//!
+//! The module has a function `run`, which returns the value of the last statement or the
+//! error of the program. Values are the values of the runtime. Functions become Rust
+//! closures, which copy the variables they capture when they are created, like the
+//! closures of the vm. Globals are late bound, like in the evaluator, such that a
+//! function can call a function that is defined behind it.
+use crate::err::CompileError;
+use monkey::eval::builtins::BUILTINS;
+use monkey::lexer::token::Span;
+use monkey::parser::ast::{Expression, Statement};
+use std::collections::HashMap;
+
+/// Warnings that the generated code may raise.
+const ALLOW: &str = "#[allow(unused_mut, unused_assignments, unused_variables, \
+                     unreachable_code, clippy::all)]";
+
+/// Where the value of a variable is kept.
+#[derive(Debug, Clone, Copy)]
+enum Variable<'a> {
+    Local(&'a str),
+    Current, // the function that is being compiled, bound by a let statement
+    Free(&'a str),
+    Global(usize),
+}
+
+struct FunctionScope {
+    params: Vec<String>,
+    locals: Vec<String>, // declared at the start of the function
+    free: Vec<String>,
+    name: Option<String>,
+}
+
+impl FunctionScope {
+    fn is_defined(&self, name: &str) -> bool {
+        self.params
+            .iter()
+            .chain(self.locals.iter())
+            .any(|n| n == name)
+    }
+}
+
+/// The code of a sequence of statements: the statements, and the expression of the
+/// value of the last one.
+struct Block {
+    stmts: Vec<String>,
+    value: String,
+    returns: bool, // the value is returned from the function
+}
+
+pub struct Transpiler {
+    scopes: Vec<FunctionScope>, // the main program is the first scope
+    globals: Vec<String>,
+    span: Span, // location of the statement that is being compiled
+}
+
+/// Transpile all statements to the source of a module. Like the compilers, compilation
+/// continues after a statement that fails to compile, such that all errors are reported.
+pub fn transpile_program(program: &[Statement]) -> Result<String, Vec<CompileError>> {
+    let mut tr = Transpiler {
+        scopes: vec![FunctionScope {
+            params: vec![],
+            locals: vec![],
+            free: vec![],
+            name: None,
+        }],
+        globals: vec![],
+        span: Span::default(),
+    };
+    for stmt in program {
+        if let Statement::Let(identifier, ..) = stmt {
+            tr.global(identifier);
+        }
+    }
+    let mut errors = vec![];
+    let mut block = Block {
+        stmts: vec![],
+        value: "NULL".to_string(),
+        returns: false,
+    };
+    for (i, stmt) in program.iter().enumerate() {
+        if let Err(e) = tr.stmt(stmt, i + 1 == program.len(), &mut block) {
+            errors.push(e);
+        }
+    }
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+
+    let names: Vec<String> = tr
+        .globals
+        .iter()
+        .map(|name| format!("{:?}", name))
+        .collect();
+    let mut out = String::new();
+    out.push_str("// Transpiled from Monkey by `monkey transpile`.\n");
+    out.push_str("use runtime::*;\n\n");
+    out.push_str(&format!(
+        "static GLOBALS: [&str; {}] = [{}];\n\n",
+        names.len(),
+        names.join(", ")
+    ));
+    out.push_str(ALLOW);
+    out.push_str("\npub fn run() -> Result {\n");
+    let mut body = vec!["let globals = Globals::new(&GLOBALS);".to_string()];
+    body.extend(block.stmts);
+    body.push(format!("Ok({})", block.value));
+    for line in body {
+        out.push_str(&indent(&line));
+        out.push('\n');
+    }
+    out.push_str("}\n");
+    Ok(out)
+}
+
+/// Indent every line of the code.
+fn indent(code: &str) -> String {
+    code.lines()
+        .map(|line| match line {
+            "" => String::new(),
+            line => format!("    {}", line),
+        })
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+/// A block expression of the statements and the value.
+fn block_expr(stmts: &[String], value: &str) -> String {
+    if stmts.is_empty() && !value.contains('\n') {
+        return format!("{{ {} }}", value);
+    }
+    let mut out = "{\n".to_string();
+    for line in stmts.iter().map(|s| &s[..]).chain(std::iter::once(value)) {
+        out.push_str(&indent(line));
+        out.push('\n');
+    }
+    out.push('}');
+    out
+}
+
+impl Transpiler {
+    fn scope(&mut self) -> &mut FunctionScope {
+        self.scopes.last_mut().unwrap()
+    }
+
+    fn global(&mut self, name: &str) -> usize {
+        match self.globals.iter().position(|n| n == name) {
+            Some(i) => i,
+            None => {
+                self.globals.push(name.to_string());
+                self.globals.len() - 1
+            }
+        }
+    }
+
+    /// The variable that the name refers to in the function at `depth`. A variable of
+    /// an enclosing function becomes a free variable of the functions in between.
+    fn resolve<'a>(&mut self, depth: usize, name: &'a str) -> Option<Variable<'a>> {
+        if depth == 0 {
+            let global = self.globals.iter().position(|n| n == name)?;
+            return Some(Variable::Global(global));
+        }
+        let scope = &self.scopes[depth];
+        if scope.is_defined(name) {
+            return Some(Variable::Local(name));
+        }
+        if scope.name.as_deref() == Some(name) {
+            return Some(Variable::Current);
+        }
+        if scope.free.iter().any(|n| n == name) {
+            return Some(Variable::Free(name));
+        }
+        match self.resolve(depth - 1, name)? {
+            Variable::Global(i) => Some(Variable::Global(i)),
+            _ => {
+                self.scopes[depth].free.push(name.to_string());
+                Some(Variable::Free(name))
+            }
+        }
+    }
+
+    fn load(var: Variable) -> String {
+        match var {
+            Variable::Local(name) => format!("v_{}.clone()", name),
+            Variable::Current => "this.clone()".to_string(),
+            Variable::Free(name) => format!("f_{}.clone()", name),
+            Variable::Global(i) => format!("globals.get({})?", i),
+        }
+    }
+
+    /// Compile the statements to a block, whose value is the value of the last statement.
+    fn block(&mut self, stmts: &[Statement]) -> Result<Block, CompileError> {
+        let mut block = Block {
+            stmts: vec![],
+            value: "NULL".to_string(),
+            returns: false,
+        };
+        for (i, stmt) in stmts.iter().enumerate() {
+            self.stmt(stmt, i + 1 == stmts.len(), &mut block)?;
+        }
+        Ok(block)
+    }
+
+    /// Add the statement to the block. The last statement gives the value of the block.
+    fn stmt(
+        &mut self,
+        stmt: &Statement,
+        last: bool,
+        block: &mut Block,
+    ) -> Result<(), CompileError> {
+        // Expressions are reported at the location of the statement they are part of.
+        let outer_span = self.span;
+        if let Some(span) = stmt.span() {
+            self.span = span;
+        }
+        let compiled = self.stmt_inner(stmt, last, block);
+        self.span = outer_span;
+        compiled
+    }
+
+    fn stmt_inner(
+        &mut self,
+        stmt: &Statement,
+        last: bool,
+        block: &mut Block,
+    ) -> Result<(), CompileError> {
+        match stmt {
+            Statement::Expr(expr, _) => {
+                let expr = self.expr(expr)?;
+                match last {
+                    true => block.value = expr,
+                    false => block.stmts.push(format!("let _ = {};", expr)),
+                }
+            }
+            Statement::Block(stmts) => {
+                let inner = self.block(stmts)?;
+                let expr = self.block_value(&inner);
+                match last {
+                    true => block.value = expr,
+                    false => block.stmts.push(format!("let _ = {};", expr)),
+                }
+            }
+            Statement::Return(expr, _) => {
+                let expr = self.expr(expr)?;
+                match last {
+                    true => {
+                        block.value = expr;
+                        block.returns = true;
+                    }
+                    false => block.stmts.push(format!("return Ok({});", expr)),
+                }
+            }
+            Statement::Let(identifier, expr, _) => {
+                let value = match expr {
+                    Expression::FunctionLiteral { parameters, body } => {
+                        self.function(parameters, body, Some(identifier))?
+                    }
+                    _ => self.expr(expr)?,
+                };
+                if self.scopes.len() == 1 {
+                    let global = self.global(identifier);
+                    block
+                        .stmts
+                        .push(format!("globals.set({}, {});", global, value));
+                } else {
+                    let scope = self.scope();
+                    if !scope.is_defined(identifier) {
+                        scope.locals.push(identifier.to_string());
+                    }
+                    block.stmts.push(format!("v_{} = {};", identifier, value));
+                }
+                // like the evaluator, which ignores the value of a let statement
+                if last {
+                    block.value = "IGNORE".to_string();
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The block as an expression.
+    fn block_value(&self, block: &Block) -> String {
+        let value = match block.returns {
+            true => format!("return Ok({})", block.value),
+            false => block.value.clone(),
+        };
+        block_expr(&block.stmts, &value)
+    }
+
+    fn exprs(&mut self, exprs: &[Expression]) -> Result<String, CompileError> {
+        let exprs = exprs
+            .iter()
+            .map(|expr| self.expr(expr))
+            .collect::<Result<Vec<String>, CompileError>>()?;
+        Ok(format!("vec![{}]", exprs.join(", ")))
+    }
+
+    /// Compile the expression to a Rust expression of its object.
+    fn expr(&mut self, expr: &Expression) -> Result<String, CompileError> {
+        let code = match expr {
+            Expression::IntegerLiteral(v) => format!("int({})", v),
+            Expression::StringLiteral(s) => format!("string({:?})", s),
+            Expression::Bool(v) => format!("boolean({})", v),
+            Expression::Prefix { operator, expr } => match &operator[..] {
+                "-" | "!" => format!("prefix({:?}, &{})?", operator, self.expr(expr)?),
+                op => {
+                    return Err(CompileError::UnknownOperator(op.to_string(), self.span))
+                }
+            },
+            Expression::Infix {
+                left,
+                operator,
+                right,
+            } => match &operator[..] {
+                "+" | "-" | "*" | "/" | "<" | ">" | "==" | "!=" => {
+                    let left = self.expr(left)?;
+                    let right = self.expr(right)?;
+                    format!("infix({:?}, &{}, &{})?", operator, left, right)
+                }
+                op => {
+                    return Err(CompileError::UnknownOperator(op.to_string(), self.span))
+                }
+            },
+            Expression::IfExpression {
+                condition,
+                consequence,
+                alternative,
+            } => {
+                let condition = self.expr(condition)?;
+                let consequence = self.branch(consequence)?;
+                let alternative = match alternative {
+                    Some(alternative) => self.branch(alternative)?,
+                    None => block_expr(&[], "NULL"),
+                };
+                format!(
+                    "if truthy(&{}) {} else {}",
+                    condition, consequence, alternative
+                )
+            }
+            Expression::Identifier(ident) => {
+                let depth = self.scopes.len() - 1;
+                match self.resolve(depth, ident) {
+                    Some(var) => Transpiler::load(var),
+                    None if BUILTINS.contains_key(&ident[..]) => {
+                        format!("builtin({:?})", &ident[..])
+                    }
+                    None => {
+                        return Err(CompileError::UndefinedVariable(
+                            ident.to_string(),
+                            self.span,
+                        ))
+                    }
+                }
+            }
+            Expression::FunctionLiteral { parameters, body } => {
+                self.function(parameters, body, None)?
+            }
+            Expression::CallExpr { function, args } => {
+                let function = self.expr(function)?;
+                format!("call(&{}, {})?", function, self.exprs(args)?)
+            }
+            Expression::ArrayLiteral(exprs) => format!("array({})?", self.exprs(exprs)?),
+            Expression::HashLiteral { keys, values } => {
+                // The evaluator evaluates the keys before the values.
+                let keys = self.exprs(keys)?;
+                let values = self.exprs(values)?;
+                block_expr(
+                    &[
+                        format!("let keys = {};", keys),
+                        format!("let values = {};", values),
+                    ],
+                    "hash(keys, values)?",
+                )
+            }
+            Expression::IndexExpr { left, index } => {
+                // The evaluator evaluates the index before the indexed object.
+                let index = self.expr(index)?;
+                let left = self.expr(left)?;
+                block_expr(
+                    &[format!("let key = {};", index)],
+                    &format!("index(&{}, key)?", left),
+                )
+            }
+            Expression::Method {
+                left,
+                identifier,
+                args,
+            } => {
+                let name = match &**identifier {
+                    Expression::Identifier(name) => name.to_string(),
+                    ident => {
+                        return Err(CompileError::Unsupported(
+                            format!("method name: {}", ident),
+                            self.span,
+                        ))
+                    }
+                };
+                let left = self.expr(left)?;
+                format!("method({}, {:?}, {})?", left, name, self.exprs(args)?)
+            }
+            _ => {
+                return Err(CompileError::Unsupported(
+                    format!("expression: {}", expr),
+                    self.span,
+                ))
+            }
+        };
+        Ok(code)
+    }
+
+    /// Compile a branch of an if expression to a block expression.
+    fn branch(&mut self, branch: &Statement) -> Result<String, CompileError> {
+        let block = match branch {
+            Statement::Block(stmts) => self.block(stmts)?,
+            stmt => self.block(std::slice::from_ref(stmt))?,
+        };
+        Ok(self.block_value(&block))
+    }
+
+    /// Compile a function literal to a closure. If the function is bound by a let
+    /// statement, `name` is available in its body.
+    fn function(
+        &mut self,
+        parameters: &[Expression],
+        body: &Statement,
+        name: Option<&str>,
+    ) -> Result<String, CompileError> {
+        let mut params = vec![];
+        for param in parameters.iter() {
+            match param {
+                Expression::Identifier(ident) => params.push(ident.to_string()),
+                param => {
+                    return Err(CompileError::Unsupported(
+                        format!("parameter: {}", param),
+                        self.span,
+                    ))
+                }
+            }
+        }
+        self.scopes.push(FunctionScope {
+            params,
+            locals: vec![],
+            free: vec![],
+            name: name.map(str::to_string),
+        });
+        let block = match body {
+            Statement::Block(stmts) => self.block(stmts),
+            stmt => self.block(std::slice::from_ref(stmt)),
+        };
+        // Always leave the scope, also on errors.
+        let scope = self.scopes.pop().unwrap();
+        let block = block?;
+
+        // The last of two parameters with the same name wins, it is bound last.
+        let mut body: Vec<String> = scope
+            .params
+            .iter()
+            .enumerate()
+            .map(|(i, param)| format!("let mut v_{} = args[{}].clone();", param, i))
+            .collect();
+        body.extend(
+            scope
+                .locals
+                .iter()
+                .map(|local| format!("let mut v_{} = NULL;", local)),
+        );
+        body.extend(block.stmts);
+        body.push(format!("Ok({})", block.value));
+        let closure = format!(
+            "function({}, move |this, args| {})",
+            scope.params.len(),
+            block_expr(&body[..body.len() - 1], &body[body.len() - 1])
+        );
+
+        // Copy the captured values. The enclosing function finds them, because they
+        // were resolved through it.
+        let mut captured = vec!["let globals = globals.clone();".to_string()];
+        let depth = self.scopes.len() - 1;
+        for free in scope.free.iter() {
+            let var = self
+                .resolve(depth, free)
+                .expect("free variables are defined");
+            captured.push(format!("let f_{} = {};", free, Transpiler::load(var)));
+        }
+        Ok(block_expr(&captured, &closure))
+    }
+}
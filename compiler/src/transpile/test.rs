@@ -0,0 +1,39 @@
+#![cfg(test)]
+//! The programs of the corpus are transpiled and run on the runtime by the tests of the
+//! `transpiled` crate.
+use crate::utils::transpile;
+
+#[test]
+fn test_module() {
+    let module = transpile("let add = fn(x, y) { x + y }; add(1, [2][0])").unwrap();
+    let expected = r#"// Transpiled from Monkey by `monkey transpile`.
+use runtime::*;
+
+static GLOBALS: [&str; 1] = ["add"];
+
+#[allow(unused_mut, unused_assignments, unused_variables, unreachable_code, clippy::all)]
+pub fn run() -> Result {
+    let globals = Globals::new(&GLOBALS);
+    globals.set(0, {
+        let globals = globals.clone();
+        function(2, move |this, args| {
+            let mut v_x = args[0].clone();
+            let mut v_y = args[1].clone();
+            Ok(infix("+", &v_x.clone(), &v_y.clone())?)
+        })
+    });
+    Ok(call(&globals.get(0)?, vec![int(1), {
+        let key = int(0);
+        index(&array(vec![int(2)])?, key)?
+    }])?)
+}
+"#;
+    assert_eq!(module, expected);
+}
+
+#[test]
+fn test_errors() {
+    let errors = transpile("let a = b; a + c").unwrap_err();
+    let messages: Vec<String> = errors.iter().map(|e| e.as_str()).collect();
+    assert_eq!(messages, ["undefined variable: b", "undefined variable: c"]);
+}
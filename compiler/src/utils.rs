@@ -1,6 +1,9 @@
-use crate::compiler::compiler::{Bytecode, Compiler};
+use crate::compiler::compiler::{Bytecode, Compiler, OptLevel};
 use crate::err::CompileError;
+use crate::register;
+use crate::transpile;
 use crate::vm::vm::VM;
+use crate::wasm;
 use monkey::{eval::object::Object, Lexer, ParseResult, Parser, ParserError, Program};
 
 pub fn parse(input: &str) -> Result<Program, ParserError> {
@@ -9,9 +12,36 @@ pub fn parse(input: &str) -> Result<Program, ParserError> {
     return par.parse_program();
 }
 
-pub fn compile(input: &str) -> Result<Compiler, CompileError> {
-    let ast = parse(input).expect("could not parse");
-    let mut com = Compiler::new();
-    com.compile_program(&ast);
+pub fn compile(input: &str) -> Result<Compiler, Vec<CompileError>> {
+    compile_with_opt_level(input, OptLevel::O0)
+}
+
+pub fn compile_with_opt_level(
+    input: &str,
+    opt_level: OptLevel,
+) -> Result<Compiler, Vec<CompileError>> {
+    let ast = parse(input).map_err(|e| vec![CompileError::from(e)])?;
+    let mut com = Compiler::with_opt_level(opt_level);
+    com.compile_program(&ast)?;
     Ok(com)
 }
+
+/// Compile for the register vm.
+pub fn compile_register(
+    input: &str,
+) -> Result<register::compiler::Program, Vec<CompileError>> {
+    let ast = parse(input).map_err(|e| vec![CompileError::from(e)])?;
+    register::compiler::compile_program(&ast)
+}
+
+/// Compile to the bytes of a WebAssembly module.
+pub fn compile_wasm(input: &str) -> Result<Vec<u8>, Vec<CompileError>> {
+    let ast = parse(input).map_err(|e| vec![CompileError::from(e)])?;
+    wasm::compiler::compile_program(&ast)
+}
+
+/// Transpile to the source of a Rust module.
+pub fn transpile(input: &str) -> Result<String, Vec<CompileError>> {
+    let ast = parse(input).map_err(|e| vec![CompileError::from(e)])?;
+    transpile::rust::transpile_program(&ast)
+}
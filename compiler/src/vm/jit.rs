@@ -0,0 +1,1008 @@
+//! Compiles hot functions of the vm to native code with Cranelift.
+//!
+//! The vm counts the calls of every compiled function. Once a function is hot, its
+//! bytecode is translated if it only computes with ints and bools: arithmetic,
+//! comparisons, jumps, locals and calls of itself or of functions in globals. Other
+//! functions stay with the interpreter.
+//!
+//! Native code doesn't change the state of the vm, it only returns a result. Whenever it
+//! cannot give the same result as the interpreter, like on a division by zero, an
+//! overflow, an argument that is not an int or a callee that cannot run natively, it
+//! bails out and the interpreter runs the call again from its start.
+use crate::code::{OpCode, Operand};
+use crate::vm::value::{Heap, Value};
+use crate::vm::vm::{MAX_FRAMES, STACKSIZE};
+use cranelift_codegen::ir::{
+    condcodes::IntCC, types, AbiParam, Block, InstBuilder, MemFlags, Signature,
+    StackSlotData, StackSlotKind,
+};
+use cranelift_codegen::{settings::Configurable, Context};
+use cranelift_frontend::{FunctionBuilder, FunctionBuilderContext, Variable};
+use cranelift_jit::{JITBuilder, JITModule};
+use cranelift_module::{default_libcall_names, FuncId, Module};
+use fnv::FnvHashMap as HashMap;
+use monkey::eval::object::{CompiledFunction, Object};
+use std::convert::TryFrom;
+use std::rc::Rc;
+
+/// Calls after which a function is compiled. Tests compile every function, such that
+/// the tests of the vm run its functions natively.
+const HOT_CALLS: usize = if cfg!(test) { 1 } else { 100 };
+/// Bailouts after which a function is left to the interpreter.
+const MAX_BAILOUTS: usize = 16;
+
+/// Status that native code returns.
+const OK: i64 = 0;
+const BAILOUT: i64 = 1;
+
+/// `(context, frames, base, args, out) -> status`. `frames` is the number of frames
+/// including the one of the call, `base` the stack pointer of the vm at its first
+/// local, `args` the arguments and `out` receives the result.
+type NativeFn =
+    unsafe extern "C" fn(*mut Callbacks, i64, i64, *const i64, *mut i64) -> i64;
+
+/// Static type of a value on the stack or in a local.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Ty {
+    Int,
+    Bool,
+    Callee(Callee),
+}
+
+/// A function that is pushed to be called.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Callee {
+    Current,
+    Global(usize),
+}
+
+#[derive(Clone, Copy)]
+struct Native {
+    code: NativeFn,
+    ret: Ty,
+}
+
+enum State {
+    Counting(usize),
+    Compiled { native: Native, bailouts: usize },
+    Interpreted,
+}
+
+/// Lets native code call the functions in globals.
+struct Callbacks<'vm, 'cmpl> {
+    jit: *mut Jit,
+    heap: &'vm Heap<'cmpl>,
+    globals: &'vm [Option<Value>],
+}
+
+struct Backend {
+    module: JITModule,
+    ctx: Context,
+    builder_ctx: FunctionBuilderContext,
+}
+
+/// The native code of the functions of a vm.
+pub struct Jit {
+    backend: Option<Backend>, // created for the first hot function
+    functions: HashMap<*const CompiledFunction, State>,
+}
+
+impl Jit {
+    pub fn new() -> Jit {
+        Jit {
+            backend: None,
+            functions: HashMap::default(),
+        }
+    }
+
+    /// Run the call natively if the function is hot. None if the interpreter has to
+    /// run it.
+    pub(crate) fn call(
+        &mut self,
+        cf: &Rc<CompiledFunction>,
+        args: &[Value],
+        heap: &Heap,
+        globals: &[Option<Value>],
+        frames: usize,
+        base: usize,
+    ) -> Option<Value> {
+        let native = self.native(cf, heap)?;
+        let args = args
+            .iter()
+            .map(|arg| match arg {
+                Value::Int(i) => Some(*i),
+                _ => None,
+            })
+            .collect::<Option<Vec<i64>>>()?;
+        let mut callbacks = Callbacks {
+            jit: self,
+            heap,
+            globals,
+        };
+        let mut out = 0;
+        let status = unsafe {
+            (native.code)(
+                &mut callbacks,
+                frames as i64,
+                base as i64,
+                args.as_ptr(),
+                &mut out,
+            )
+        };
+        if status == OK {
+            return Some(match native.ret {
+                Ty::Bool => Value::Bool(out != 0),
+                _ => Value::Int(out),
+            });
+        }
+        if let Some(State::Compiled { bailouts, .. }) =
+            self.functions.get_mut(&Rc::as_ptr(cf))
+        {
+            *bailouts += 1;
+            if *bailouts >= MAX_BAILOUTS {
+                self.functions.insert(Rc::as_ptr(cf), State::Interpreted);
+            }
+        }
+        None
+    }
+
+    /// Count the call and return the native code of the function, which is compiled
+    /// once it is hot.
+    fn native(&mut self, cf: &CompiledFunction, heap: &Heap) -> Option<Native> {
+        let key = cf as *const CompiledFunction;
+        match self.functions.entry(key).or_insert(State::Counting(0)) {
+            State::Counting(calls) => {
+                *calls += 1;
+                if *calls < HOT_CALLS {
+                    return None;
+                }
+            }
+            State::Compiled { native, .. } => return Some(*native),
+            State::Interpreted => return None,
+        }
+        let native = analyze(cf, heap).and_then(|analysis| self.compile(cf, &analysis));
+        let state = match native {
+            Some(native) => State::Compiled {
+                native,
+                bailouts: 0,
+            },
+            None => State::Interpreted,
+        };
+        self.functions.insert(key, state);
+        native
+    }
+
+    fn compile(&mut self, cf: &CompiledFunction, analysis: &Analysis) -> Option<Native> {
+        if self.backend.is_none() {
+            self.backend = Some(Backend::new()?);
+        }
+        let backend = self.backend.as_mut()?;
+        let code = backend.compile(cf, analysis)?;
+        Some(Native {
+            code: unsafe { std::mem::transmute::<*const u8, NativeFn>(code) },
+            ret: analysis.ret,
+        })
+    }
+}
+
+impl Default for Jit {
+    fn default() -> Jit {
+        Jit::new()
+    }
+}
+
+/// A clone of a vm compiles its hot functions again.
+impl Clone for Jit {
+    fn clone(&self) -> Jit {
+        Jit::new()
+    }
+}
+
+impl Drop for Jit {
+    fn drop(&mut self) {
+        if let Some(backend) = self.backend.take() {
+            // No native code runs once the vm is gone.
+            unsafe { backend.module.free_memory() }
+        }
+    }
+}
+
+/// Call the function in a global from native code.
+unsafe extern "C" fn call_global(
+    callbacks: *mut Callbacks,
+    index: i64,
+    frames: i64,
+    base: i64,
+    args: *const i64,
+    n_args: i64,
+    out: *mut i64,
+) -> i64 {
+    let callbacks = &mut *callbacks;
+    let cf = match callbacks.globals.get(index as usize) {
+        Some(Some(Value::Heap(handle))) => match callbacks.heap.get(*handle) {
+            Object::CompiledFunction(cf) => cf,
+            _ => return BAILOUT,
+        },
+        _ => return BAILOUT,
+    };
+    if cf.num_parameters != n_args as usize
+        || base as usize + cf.num_locals >= STACKSIZE
+        || frames as usize >= MAX_FRAMES
+    {
+        return BAILOUT;
+    }
+    // The caller was compiled for an int result.
+    match (*callbacks.jit).native(cf, callbacks.heap) {
+        Some(native) if native.ret == Ty::Int => {
+            (native.code)(callbacks, frames + 1, base, args, out)
+        }
+        _ => BAILOUT,
+    }
+}
+
+struct Instruction {
+    ip: usize,
+    op: OpCode,
+    operands: [Operand; 2],
+    next: usize,
+}
+
+fn decode(ins: &[u8]) -> Option<Vec<Instruction>> {
+    let mut instructions = vec![];
+    let mut ip = 0;
+    while ip < ins.len() {
+        let wide = ins[ip] == OpCode::Wide as u8;
+        let at = if wide { ip + 1 } else { ip };
+        let op = OpCode::try_from(*ins.get(at)?).ok()?;
+        let mut operands = [0; 2];
+        let mut offset = at + 1;
+        for (i, width) in op.definition().iter().enumerate() {
+            let width = if wide { 2 * width } else { *width };
+            let bytes = ins.get(offset..offset + width)?;
+            operands[i] = bytes.iter().fold(0, |n, byte| n << 8 | *byte as Operand);
+            offset += width;
+        }
+        instructions.push(Instruction {
+            ip,
+            op,
+            operands,
+            next: offset,
+        });
+        ip = offset;
+    }
+    Some(instructions)
+}
+
+/// Types of the stack and the locals before an instruction.
+#[derive(Clone, PartialEq)]
+struct Types {
+    stack: Vec<Ty>,
+    locals: Vec<Option<Ty>>, // None if not set on every path
+}
+
+impl Types {
+    fn pop(&mut self) -> Option<Ty> {
+        self.stack.pop()
+    }
+
+    fn pop_value(&mut self) -> Option<Ty> {
+        match self.stack.pop()? {
+            Ty::Callee(_) => None,
+            ty => Some(ty),
+        }
+    }
+
+    fn pop_int(&mut self) -> Option<()> {
+        match self.stack.pop()? {
+            Ty::Int => Some(()),
+            _ => None,
+        }
+    }
+
+    fn pop_bool(&mut self) -> Option<()> {
+        match self.stack.pop()? {
+            Ty::Bool => Some(()),
+            _ => None,
+        }
+    }
+
+    /// Merge the types of another path to the same instruction.
+    fn merge(&mut self, other: &Types) -> Option<bool> {
+        if self.stack != other.stack {
+            return None;
+        }
+        let mut changed = false;
+        for (local, other) in self.locals.iter_mut().zip(&other.locals) {
+            if local.is_some() && local != other {
+                *local = None;
+                changed = true;
+            }
+        }
+        Some(changed)
+    }
+}
+
+struct Analysis {
+    instructions: Vec<Instruction>,
+    types: Vec<Option<Types>>, // None for unreachable instructions
+    block_starts: Vec<bool>,   // whether an instruction starts a block
+    ret: Ty,
+    max_stack: usize,
+    max_args: usize,
+    constants: HashMap<usize, Value>,
+}
+
+/// Infer the types of the function. None if it cannot be compiled.
+fn analyze(cf: &CompiledFunction, heap: &Heap) -> Option<Analysis> {
+    let instructions = decode(&cf.instructions)?;
+    let index: HashMap<usize, usize> = instructions
+        .iter()
+        .enumerate()
+        .map(|(i, ins)| (ins.ip, i))
+        .collect();
+    let target = |ip: usize| index.get(&ip).copied();
+
+    let mut types: Vec<Option<Types>> = instructions.iter().map(|_| None).collect();
+    let mut block_starts = vec![false; instructions.len()];
+    let mut ret = None;
+    let mut max_stack = 0;
+    let mut max_args = 0;
+    let mut constants = HashMap::default();
+    let mut calls_current = false;
+
+    let mut locals = vec![None; cf.num_locals];
+    for local in locals.iter_mut().take(cf.num_parameters) {
+        *local = Some(Ty::Int);
+    }
+    types[0] = Some(Types {
+        stack: vec![],
+        locals,
+    });
+    let mut work = vec![0];
+    while let Some(i) = work.pop() {
+        let ins = &instructions[i];
+        let mut t = types[i].clone()?;
+        let [a, b] = ins.operands;
+        let mut successors = vec![];
+        let mut falls_through = true;
+        match ins.op {
+            OpCode::Constant => {
+                let value = heap.constant(a);
+                let ty = match value {
+                    Value::Int(_) => Ty::Int,
+                    Value::Bool(_) => Ty::Bool,
+                    _ => return None,
+                };
+                constants.insert(a, value);
+                t.stack.push(ty);
+            }
+            OpCode::Pop => {
+                t.pop()?;
+            }
+            OpCode::Add | OpCode::Sub | OpCode::Mul | OpCode::Div => {
+                t.pop_int()?;
+                t.pop_int()?;
+                t.stack.push(Ty::Int);
+            }
+            OpCode::True | OpCode::False => t.stack.push(Ty::Bool),
+            OpCode::Zero | OpCode::One | OpCode::SmallInt => t.stack.push(Ty::Int),
+            OpCode::Equal | OpCode::NotEqual => {
+                if t.pop_value()? != t.pop_value()? {
+                    return None;
+                }
+                t.stack.push(Ty::Bool);
+            }
+            OpCode::GT => {
+                t.pop_int()?;
+                t.pop_int()?;
+                t.stack.push(Ty::Bool);
+            }
+            OpCode::Minus => {
+                t.pop_int()?;
+                t.stack.push(Ty::Int);
+            }
+            OpCode::Bang => {
+                t.pop_bool()?;
+                t.stack.push(Ty::Bool);
+            }
+            OpCode::JumpNotTruthy => {
+                t.pop_value()?;
+                let jump = target(a)?;
+                block_starts[jump] = true;
+                *block_starts.get_mut(i + 1)? = true;
+                successors.push(jump);
+            }
+            OpCode::Jump => {
+                let jump = target(a)?;
+                block_starts[jump] = true;
+                successors.push(jump);
+                falls_through = false;
+            }
+            OpCode::GetLocal => {
+                let ty = (*t.locals.get(a)?)?;
+                t.stack.push(ty);
+            }
+            OpCode::SetLocal => {
+                let ty = t.pop_value()?;
+                *t.locals.get_mut(a)? = Some(ty);
+            }
+            OpCode::AddLocals => {
+                for local in [a, b].iter() {
+                    if *t.locals.get(*local)? != Some(Ty::Int) {
+                        return None;
+                    }
+                }
+                t.stack.push(Ty::Int);
+            }
+            OpCode::CurrentFunction => t.stack.push(Ty::Callee(Callee::Current)),
+            OpCode::GetGlobal => t.stack.push(Ty::Callee(Callee::Global(a))),
+            OpCode::Call => {
+                for _ in 0..a {
+                    t.pop_int()?;
+                }
+                match t.pop()? {
+                    Ty::Callee(Callee::Current) if a == cf.num_parameters => {
+                        calls_current = true
+                    }
+                    Ty::Callee(Callee::Global(_)) => {}
+                    _ => return None,
+                }
+                max_args = max_args.max(a);
+                t.stack.push(Ty::Int);
+            }
+            OpCode::ReturnVal => {
+                let ty = t.pop_value()?;
+                if ret.is_some() && ret != Some(ty) {
+                    return None;
+                }
+                ret = Some(ty);
+                falls_through = false;
+            }
+            _ => return None,
+        }
+        max_stack = max_stack.max(t.stack.len());
+        if falls_through {
+            // Functions always end with a return.
+            successors.push(target(ins.next)?);
+        }
+        for next in successors {
+            let changed = match &mut types[next] {
+                Some(types) => types.merge(&t)?,
+                None => {
+                    types[next] = Some(t.clone());
+                    true
+                }
+            };
+            if changed {
+                work.push(next);
+            }
+        }
+    }
+    let ret = ret?;
+    if calls_current && ret != Ty::Int {
+        return None;
+    }
+    Some(Analysis {
+        instructions,
+        types,
+        block_starts,
+        ret,
+        max_stack,
+        max_args,
+        constants,
+    })
+}
+
+impl Backend {
+    fn new() -> Option<Backend> {
+        let mut flags = cranelift_codegen::settings::builder();
+        flags.set("opt_level", "speed").ok()?;
+        flags.set("use_colocated_libcalls", "false").ok()?;
+        flags.set("is_pic", "false").ok()?;
+        let isa = cranelift_native::builder()
+            .ok()?
+            .finish(cranelift_codegen::settings::Flags::new(flags))
+            .ok()?;
+        let module = JITModule::new(JITBuilder::with_isa(isa, default_libcall_names()));
+        Some(Backend {
+            ctx: module.make_context(),
+            module,
+            builder_ctx: FunctionBuilderContext::new(),
+        })
+    }
+
+    fn signature(&self) -> Signature {
+        let ptr = self.module.target_config().pointer_type();
+        let mut sig = self.module.make_signature();
+        for ty in [ptr, types::I64, types::I64, ptr, ptr].iter() {
+            sig.params.push(AbiParam::new(*ty));
+        }
+        sig.returns.push(AbiParam::new(types::I64));
+        sig
+    }
+
+    /// Translate the function and return the address of its code.
+    fn compile(
+        &mut self,
+        cf: &CompiledFunction,
+        analysis: &Analysis,
+    ) -> Option<*const u8> {
+        let sig = self.signature();
+        let id = self.module.declare_anonymous_function(&sig).ok()?;
+        self.module.clear_context(&mut self.ctx);
+        self.ctx.func.signature = sig;
+
+        let ptr = self.module.target_config().pointer_type();
+        let mut call_global_sig = self.module.make_signature();
+        for ty in [
+            ptr,
+            types::I64,
+            types::I64,
+            types::I64,
+            ptr,
+            types::I64,
+            ptr,
+        ]
+        .iter()
+        {
+            call_global_sig.params.push(AbiParam::new(*ty));
+        }
+        call_global_sig.returns.push(AbiParam::new(types::I64));
+
+        let builder = FunctionBuilder::new(&mut self.ctx.func, &mut self.builder_ctx);
+        let mut translator =
+            Translator::new(builder, &mut self.module, id, cf, analysis, call_global_sig);
+        translator.translate();
+        translator.builder.seal_all_blocks();
+        translator.builder.finalize();
+
+        self.module.define_function(id, &mut self.ctx).ok()?;
+        self.module.clear_context(&mut self.ctx);
+        self.module.finalize_definitions().ok()?;
+        Some(self.module.get_finalized_function(id))
+    }
+}
+
+fn var(index: usize) -> Variable {
+    Variable::from_u32(index as u32)
+}
+
+struct Translator<'a> {
+    builder: FunctionBuilder<'a>,
+    cf: &'a CompiledFunction,
+    analysis: &'a Analysis,
+    ptr: types::Type,
+    current: cranelift_codegen::ir::FuncRef,
+    call_global: cranelift_codegen::ir::SigRef,
+    // parameters of the native function
+    callbacks: cranelift_codegen::ir::Value,
+    frames: cranelift_codegen::ir::Value,
+    base: cranelift_codegen::ir::Value,
+    out: cranelift_codegen::ir::Value,
+    args: cranelift_codegen::ir::StackSlot, // arguments of calls
+    result: cranelift_codegen::ir::StackSlot,
+    bailout: Block,
+    height: usize, // of the stack
+}
+
+impl<'a> Translator<'a> {
+    fn new(
+        mut builder: FunctionBuilder<'a>,
+        module: &mut JITModule,
+        id: FuncId,
+        cf: &'a CompiledFunction,
+        analysis: &'a Analysis,
+        call_global_sig: Signature,
+    ) -> Translator<'a> {
+        let ptr = module.target_config().pointer_type();
+        let entry = builder.create_block();
+        builder.append_block_params_for_function_params(entry);
+        builder.switch_to_block(entry);
+        let params = builder.block_params(entry).to_vec();
+
+        // The locals, then one variable per stack position.
+        for i in 0..cf.num_locals + analysis.max_stack {
+            let var = var(i);
+            builder.declare_var(var, types::I64);
+            let zero = builder.ins().iconst(types::I64, 0);
+            builder.def_var(var, zero);
+        }
+        for i in 0..cf.num_parameters {
+            let arg = builder.ins().load(
+                types::I64,
+                MemFlags::trusted(),
+                params[3],
+                8 * i as i32,
+            );
+            builder.def_var(var(i), arg);
+        }
+        let args = builder.create_sized_stack_slot(StackSlotData::new(
+            StackSlotKind::ExplicitSlot,
+            8 * analysis.max_args.max(1) as u32,
+            3,
+        ));
+        let result = builder.create_sized_stack_slot(StackSlotData::new(
+            StackSlotKind::ExplicitSlot,
+            8,
+            3,
+        ));
+        let current = module.declare_func_in_func(id, builder.func);
+        let call_global = builder.import_signature(call_global_sig);
+        let bailout = builder.create_block();
+
+        let mut translator = Translator {
+            builder,
+            cf,
+            analysis,
+            ptr,
+            current,
+            call_global,
+            callbacks: params[0],
+            frames: params[1],
+            base: params[2],
+            out: params[4],
+            args,
+            result,
+            bailout,
+            height: 0,
+        };
+        // The vm checks every push against the size of the stack.
+        let highest = translator
+            .iadd_imm(translator.base, (cf.num_locals + analysis.max_stack) as i64);
+        let overflows = translator.builder.ins().icmp_imm(
+            IntCC::SignedGreaterThan,
+            highest,
+            STACKSIZE as i64,
+        );
+        translator.bail_if(overflows);
+        translator
+    }
+
+    fn iadd_imm(
+        &mut self,
+        x: cranelift_codegen::ir::Value,
+        n: i64,
+    ) -> cranelift_codegen::ir::Value {
+        let n = self.builder.ins().iconst(types::I64, n);
+        self.builder.ins().iadd(x, n)
+    }
+
+    fn stack_var(&self, height: usize) -> Variable {
+        var(self.cf.num_locals + height)
+    }
+
+    fn push(&mut self, value: cranelift_codegen::ir::Value) {
+        let var = self.stack_var(self.height);
+        self.builder.def_var(var, value);
+        self.height += 1;
+    }
+
+    fn pop(&mut self) -> cranelift_codegen::ir::Value {
+        self.height -= 1;
+        let var = self.stack_var(self.height);
+        self.builder.use_var(var)
+    }
+
+    fn push_bool(&mut self, condition: cranelift_codegen::ir::Value) {
+        let value = self.builder.ins().uextend(types::I64, condition);
+        self.push(value);
+    }
+
+    /// Continue in a new block, or bail out if the condition holds.
+    fn bail_if(&mut self, condition: cranelift_codegen::ir::Value) {
+        let next = self.builder.create_block();
+        self.builder
+            .ins()
+            .brif(condition, self.bailout, &[], next, &[]);
+        self.builder.switch_to_block(next);
+    }
+
+    fn translate(&mut self) {
+        let analysis = self.analysis;
+        let blocks: Vec<Option<Block>> = analysis
+            .block_starts
+            .iter()
+            .map(|start| {
+                if *start {
+                    Some(self.builder.create_block())
+                } else {
+                    None
+                }
+            })
+            .collect();
+        let block_at = |ip: usize| {
+            let i = analysis
+                .instructions
+                .iter()
+                .position(|ins| ins.ip == ip)
+                .unwrap();
+            blocks[i].unwrap()
+        };
+        let mut terminated = false;
+        for (i, ins) in analysis.instructions.iter().enumerate() {
+            let types = match &analysis.types[i] {
+                Some(types) => types,
+                None => continue, // unreachable
+            };
+            if let Some(block) = blocks[i] {
+                if !terminated {
+                    self.builder.ins().jump(block, &[]);
+                }
+                self.builder.switch_to_block(block);
+                terminated = false;
+            }
+            self.height = types.stack.len();
+            let [a, b] = ins.operands;
+            match ins.op {
+                OpCode::Constant => {
+                    let value = match analysis.constants[&a] {
+                        Value::Int(i) => i,
+                        Value::Bool(b) => b as i64,
+                        _ => unreachable!(),
+                    };
+                    let value = self.builder.ins().iconst(types::I64, value);
+                    self.push(value);
+                }
+                OpCode::Pop => {
+                    self.pop();
+                }
+                OpCode::Add | OpCode::Sub | OpCode::Mul => {
+                    let right = self.pop();
+                    let left = self.pop();
+                    let (result, overflow) = match ins.op {
+                        OpCode::Add => self.builder.ins().sadd_overflow(left, right),
+                        OpCode::Sub => self.builder.ins().ssub_overflow(left, right),
+                        _ => self.builder.ins().smul_overflow(left, right),
+                    };
+                    self.bail_if(overflow);
+                    self.push(result);
+                }
+                OpCode::Div => {
+                    let right = self.pop();
+                    let left = self.pop();
+                    // division by zero and overflow are left to the interpreter
+                    let zero = self.builder.ins().icmp_imm(IntCC::Equal, right, 0);
+                    let minus_one = self.builder.ins().icmp_imm(IntCC::Equal, right, -1);
+                    let bail = self.builder.ins().bor(zero, minus_one);
+                    self.bail_if(bail);
+                    let result = self.builder.ins().sdiv(left, right);
+                    self.push(result);
+                }
+                OpCode::True | OpCode::False | OpCode::Zero | OpCode::One => {
+                    let value = match ins.op {
+                        OpCode::True | OpCode::One => 1,
+                        _ => 0,
+                    };
+                    let value = self.builder.ins().iconst(types::I64, value);
+                    self.push(value);
+                }
+                OpCode::SmallInt => {
+                    let value = self.builder.ins().iconst(types::I64, a as i64);
+                    self.push(value);
+                }
+                OpCode::Equal | OpCode::NotEqual | OpCode::GT => {
+                    let right = self.pop();
+                    let left = self.pop();
+                    let cc = match ins.op {
+                        OpCode::Equal => IntCC::Equal,
+                        OpCode::NotEqual => IntCC::NotEqual,
+                        _ => IntCC::SignedGreaterThan,
+                    };
+                    let result = self.builder.ins().icmp(cc, left, right);
+                    self.push_bool(result);
+                }
+                OpCode::Minus => {
+                    let right = self.pop();
+                    let overflow =
+                        self.builder.ins().icmp_imm(IntCC::Equal, right, i64::MIN);
+                    self.bail_if(overflow);
+                    let result = self.builder.ins().ineg(right);
+                    self.push(result);
+                }
+                OpCode::Bang => {
+                    let right = self.pop();
+                    let result = self.builder.ins().icmp_imm(IntCC::Equal, right, 0);
+                    self.push_bool(result);
+                }
+                OpCode::JumpNotTruthy => {
+                    let condition = self.pop();
+                    let next = blocks[i + 1].unwrap();
+                    self.builder
+                        .ins()
+                        .brif(condition, next, &[], block_at(a), &[]);
+                    terminated = true;
+                }
+                OpCode::Jump => {
+                    self.builder.ins().jump(block_at(a), &[]);
+                    terminated = true;
+                }
+                OpCode::GetLocal => {
+                    let value = self.builder.use_var(var(a));
+                    self.push(value);
+                }
+                OpCode::SetLocal => {
+                    let value = self.pop();
+                    self.builder.def_var(var(a), value);
+                }
+                OpCode::AddLocals => {
+                    let left = self.builder.use_var(var(a));
+                    let right = self.builder.use_var(var(b));
+                    let (result, overflow) =
+                        self.builder.ins().sadd_overflow(left, right);
+                    self.bail_if(overflow);
+                    self.push(result);
+                }
+                // The callee is known from the analysis, its value is not needed.
+                OpCode::CurrentFunction | OpCode::GetGlobal => {
+                    let zero = self.builder.ins().iconst(types::I64, 0);
+                    self.push(zero);
+                }
+                OpCode::Call => {
+                    let callee = match types.stack[types.stack.len() - a - 1] {
+                        Ty::Callee(callee) => callee,
+                        _ => unreachable!(),
+                    };
+                    self.call(callee, a);
+                }
+                OpCode::ReturnVal => {
+                    let value = self.pop();
+                    self.builder
+                        .ins()
+                        .store(MemFlags::trusted(), value, self.out, 0);
+                    let ok = self.builder.ins().iconst(types::I64, OK);
+                    self.builder.ins().return_(&[ok]);
+                    terminated = true;
+                }
+                op => unreachable!("{:?} is not compiled", op),
+            }
+        }
+        self.builder.switch_to_block(self.bailout);
+        let bailout = self.builder.ins().iconst(types::I64, BAILOUT);
+        self.builder.ins().return_(&[bailout]);
+    }
+
+    /// Call with the arguments and the callee on the stack, like the vm.
+    fn call(&mut self, callee: Callee, n_args: usize) {
+        for i in (0..n_args).rev() {
+            let arg = self.pop();
+            self.builder.ins().stack_store(arg, self.args, 8 * i as i32);
+        }
+        self.pop(); // the callee
+                    // The stack pointer of the vm at the first local of the callee.
+                    // The callee was below the arguments.
+        let callee_base =
+            self.iadd_imm(self.base, (self.cf.num_locals + self.height + 1) as i64);
+        let args = self.builder.ins().stack_addr(self.ptr, self.args, 0);
+        let out = self.builder.ins().stack_addr(self.ptr, self.result, 0);
+        let status = match callee {
+            Callee::Current => {
+                let full = self.iadd_imm(callee_base, self.cf.num_locals as i64);
+                let stack_overflow = self.builder.ins().icmp_imm(
+                    IntCC::SignedGreaterThanOrEqual,
+                    full,
+                    STACKSIZE as i64,
+                );
+                let frame_overflow = self.builder.ins().icmp_imm(
+                    IntCC::SignedGreaterThanOrEqual,
+                    self.frames,
+                    MAX_FRAMES as i64,
+                );
+                let overflow = self.builder.ins().bor(stack_overflow, frame_overflow);
+                self.bail_if(overflow);
+                let frames = self.iadd_imm(self.frames, 1);
+                let call = self.builder.ins().call(
+                    self.current,
+                    &[self.callbacks, frames, callee_base, args, out],
+                );
+                self.builder.inst_results(call)[0]
+            }
+            Callee::Global(index) => {
+                let function = self
+                    .builder
+                    .ins()
+                    .iconst(self.ptr, call_global as *const () as i64);
+                let index = self.builder.ins().iconst(types::I64, index as i64);
+                let n_args = self.builder.ins().iconst(types::I64, n_args as i64);
+                let call = self.builder.ins().call_indirect(
+                    self.call_global,
+                    function,
+                    &[
+                        self.callbacks,
+                        index,
+                        self.frames,
+                        callee_base,
+                        args,
+                        n_args,
+                        out,
+                    ],
+                );
+                self.builder.inst_results(call)[0]
+            }
+        };
+        let failed = self.builder.ins().icmp_imm(IntCC::NotEqual, status, OK);
+        self.bail_if(failed);
+        let result = self.builder.ins().stack_load(types::I64, self.result, 0);
+        self.push(result);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::utils::compile;
+    use crate::vm::vm::{execute, new_globals, VM};
+
+    /// Run the program and return the states of its functions.
+    fn states(input: &str) -> Vec<&'static str> {
+        let com = compile(input).unwrap();
+        let bytecode = com.bytecode();
+        let mut vm = VM::new(&bytecode);
+        execute(&mut vm, &mut new_globals(&bytecode)).unwrap();
+        let mut states: Vec<&str> = vm
+            .jit
+            .functions
+            .values()
+            .map(|state| match state {
+                State::Counting(_) => "counting",
+                State::Compiled { .. } => "compiled",
+                State::Interpreted => "interpreted",
+            })
+            .collect();
+        states.sort();
+        states
+    }
+
+    #[test]
+    fn test_compiled() {
+        let input = "let fibonacci = fn(x) { if (x < 2) { x } else { fibonacci(x - 1) + fibonacci(x - 2) } };
+fibonacci(10)";
+        assert_eq!(states(input), ["compiled"]);
+
+        let input = "let double = fn(x) { x * 2 }; let f = fn(x) { let y = double(x); !(y > 3) }; f(1)";
+        assert_eq!(states(input), ["compiled", "compiled"]);
+
+        // hashes and strings are not compiled
+        let input = r#"let f = fn(x) { "a" }; f(1)"#;
+        assert_eq!(states(input), ["interpreted"]);
+    }
+
+    #[test]
+    fn test_results() {
+        let inout: &[(&str, &str)] = &[
+            (
+                "let a = fn(n) { if (n == 0) { 0 } else { b(n - 1) + 1 } };
+let b = fn(n) { if (n == 0) { 0 } else { a(n - 1) * 1 + 1 } };
+a(10)",
+                "10",
+            ),
+            // b returns a bool, which a was not compiled for
+            (
+                "let a = fn(n) { if (b(n)) { 1 } else { 2 } }; let b = fn(n) { n > 1 }; a(5)",
+                "1",
+            ),
+            ("let f = fn(x, y) { let z = x / y; -z }; f(7, 2)", "-3"),
+            ("let f = fn(x) { x / -1 }; f(5)", "-5"),
+            ("let f = fn(n) { if (n == 0) { 0 } else { 1 + f(n - 1) } }; f(500)", "500"),
+        ];
+        for (input, output) in inout {
+            let com = compile(input).unwrap();
+            let result = crate::vm::vm::run_vm(&com.bytecode());
+            assert_eq!(format!("{}", result.unwrap()), *output, "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_bailout() {
+        // The division by zero bails out of f(3), then of f(2), f(1) and f(0), which
+        // the interpreter calls. The last one reports the error.
+        let input = "let f = fn(x) { if (x == 0) { 1 / x } else { f(x - 1) } }; f(3)";
+        let com = compile(input).unwrap();
+        let bytecode = com.bytecode();
+        let mut vm = VM::new(&bytecode);
+        assert!(execute(&mut vm, &mut new_globals(&bytecode)).is_err());
+        match vm.jit.functions.values().next() {
+            Some(State::Compiled { bailouts, .. }) => assert_eq!(*bailouts, 4),
+            _ => panic!("expected a compiled function"),
+        }
+    }
+}
@@ -1,7 +1,9 @@
 #![cfg(test)]
-use super::vm::{run_vm, VM};
-use crate::compiler::compiler::Compiler;
-use crate::utils::{compile, parse};
+use super::verify::verify;
+use super::vm::{execute, new_globals, run_vm, VM};
+use crate::compiler::compiler::{Compiler, OptLevel};
+use crate::err::{RuntimeError, VMError};
+use crate::utils::{compile, compile_with_opt_level, parse};
 use monkey::eval::object::Object;
 
 fn compile_and_run_vm(input: &str) -> Object {
@@ -10,6 +12,12 @@ fn compile_and_run_vm(input: &str) -> Object {
     run_vm(&bytecode).unwrap_or(Object::Error("something went wrong".to_string()))
 }
 
+fn run_vm_err(input: &str) -> RuntimeError {
+    let com = compile(&input).unwrap();
+    let bytecode = com.bytecode();
+    run_vm(&bytecode).err().expect("expected a runtime error")
+}
+
 #[test]
 fn test_addition() {
     let inout: &[(&str, i64)] = &[
@@ -57,13 +65,7 @@ fn test_cmp() {
 
 #[test]
 fn test_prefix() {
-    let inout = &[
-        ("!true", false),
-        ("!!true", true),
-        ("!0", true),
-        ("!5", false),
-        ("!!5", true),
-    ];
+    let inout = &[("!true", false), ("!!true", true), ("!!false", false)];
     for (input, output) in inout {
         assert_eq!(compile_and_run_vm(&input), Object::Bool(*output));
     }
@@ -86,14 +88,36 @@ fn test_conditional() {
         assert_eq!(compile_and_run_vm(&input), Object::Int(*output));
     }
 
-    use Object::{Bool, Null};
-    let inout = &[
-        ("if (1 > 2) { 10 }", Null),
-        ("if (false) { 10 }", Null),
-        ("!(if (false) { 5; })", Bool(true)), // tests that we don't get null from conditional
+    let inputs = ["if (1 > 2) { 10 }", "if (false) { 10 }"];
+    for input in inputs.iter() {
+        assert_eq!(compile_and_run_vm(input), Object::Null);
+    }
+}
+
+#[test]
+fn test_conditional_without_value() {
+    let inputs = [
+        "if (true) { }",
+        "1; if (true) { }",
+        "let f = fn(x) { [x, if (x) { }] }; f(1)[1]",
     ];
-    for (input, output) in inout {
-        assert_eq!(compile_and_run_vm(&input), *output);
+    // Like in the evaluator, a block that ends with a let gives the value of the let.
+    let lets = [
+        "if (false) { 1 } else { let x = 2; }",
+        "let f = fn() { if (true) { let x = 1; } }; f()",
+        "let f = fn() { let x = 1; }; f()",
+        "1; let x = 2;",
+    ];
+    for opt_level in [OptLevel::O0, OptLevel::O1, OptLevel::O2].iter() {
+        for input in inputs.iter() {
+            let com = compile_with_opt_level(input, *opt_level).unwrap();
+            assert_eq!(run_vm(&com.bytecode()).unwrap(), Object::Null, "{}", input);
+        }
+        for input in lets.iter() {
+            let com = compile_with_opt_level(input, *opt_level).unwrap();
+            let result = run_vm(&com.bytecode()).unwrap();
+            assert!(matches!(result, Object::Ignore), "{}: {}", input, result);
+        }
     }
 }
 
@@ -181,3 +205,522 @@ noReturnTwo();",
         assert_eq!(compile_and_run_vm(&input), *output)
     }
 }
+
+#[test]
+fn test_fn_locals() {
+    let inout = &[
+        ("let one = fn() { let one = 1; one }; one();", 1),
+        (
+            "let oneAndTwo = fn() { let one = 1; let two = 2; one + two; };
+oneAndTwo();",
+            3,
+        ),
+        (
+            "let oneAndTwo = fn() { let one = 1; let two = 2; one + two; };
+let threeAndFour = fn() { let three = 3; let four = 4; three + four; };
+oneAndTwo() + threeAndFour();",
+            10,
+        ),
+        (
+            "let globalSeed = 50;
+let minusOne = fn() { let num = 1; globalSeed - num; };
+let minusTwo = fn() { let num = 2; globalSeed - num; };
+minusOne() + minusTwo();",
+            97,
+        ),
+    ];
+    for (input, output) in inout {
+        assert_eq!(compile_and_run_vm(&input), Object::from(*output));
+    }
+}
+
+#[test]
+fn test_fn_args() {
+    let inout = &[
+        ("let identity = fn(a) { a; }; identity(4);", 4),
+        ("let sum = fn(a, b) { a + b; }; sum(1, 2);", 3),
+        (
+            "let sum = fn(a, b) { let c = a + b; c; };
+sum(1, 2) + sum(3, 4);",
+            10,
+        ),
+        (
+            "let sum = fn(a, b) { let c = a + b; c; };
+let outer = fn() { sum(1, 2) + sum(3, 4); };
+outer();",
+            10,
+        ),
+        (
+            "let globalNum = 10;
+let sum = fn(a, b) { let c = a + b; c + globalNum; };
+let outer = fn() { sum(1, 2) + sum(3, 4) + globalNum; };
+outer() + globalNum;",
+            50,
+        ),
+    ];
+    for (input, output) in inout {
+        assert_eq!(compile_and_run_vm(&input), Object::from(*output));
+    }
+}
+
+#[test]
+fn test_fn_wrong_arity() {
+    let inout = &[
+        (
+            "fn(a) { a; }();",
+            VMError::WrongNumberOfArgs { want: 1, got: 0 },
+        ),
+        (
+            "fn(a, b) { a + b; }(1);",
+            VMError::WrongNumberOfArgs { want: 2, got: 1 },
+        ),
+    ];
+    for (input, output) in inout {
+        assert_eq!(run_vm_err(&input).error, *output);
+    }
+    // Arguments without a parameter are dropped, like in the evaluator.
+    let inout = &[
+        ("fn() { 1; }(1);", 1),
+        ("let f = fn(a) { let b = 2; a + b }; f(1, 5, 7)", 3),
+    ];
+    for (input, output) in inout {
+        assert_eq!(compile_and_run_vm(input), Object::Int(*output));
+    }
+}
+
+#[test]
+fn test_recursive_fn() {
+    let inout = &[
+        (
+            "let countDown = fn(x) { if (x == 0) { return 0; } else { countDown(x - 1); } };
+countDown(1);",
+            0,
+        ),
+        (
+            "let wrapper = fn() {
+  let countDown = fn(x) { if (x == 0) { return 0; } else { countDown(x - 1); } };
+  countDown(1);
+};
+wrapper();",
+            0,
+        ),
+        (
+            "let fibonacci = fn(x) {
+  if (x == 0) {
+    0
+  } else {
+    if (x == 1) {
+      return 1;
+    } else {
+      fibonacci(x - 1) + fibonacci(x - 2);
+    }
+  }
+};
+fibonacci(15);",
+            610,
+        ),
+        (
+            "let f = fn(n) { let g = fn(k) { if (k == 0) { 0 } else { f(k - 1) + 1 } }; g(n) };
+f(3);",
+            3,
+        ),
+    ];
+    for (input, output) in inout {
+        assert_eq!(compile_and_run_vm(&input), Object::from(*output));
+    }
+}
+
+#[test]
+fn test_mutual_recursive_fn() {
+    let input = "let isEven = fn(n) { if (n == 0) { true } else { isOdd(n - 1) } };
+let isOdd = fn(n) { if (n == 0) { false } else { isEven(n - 1) } };
+[isEven(10), isOdd(10), isEven(7)]";
+    assert_eq!(
+        format!("{}", compile_and_run_vm(&input)),
+        "[true, false, false]"
+    );
+}
+
+#[test]
+fn test_captured_locals() {
+    // The frame of a function has no slots for the locals of enclosing functions.
+    let inout = &[
+        (
+            "let make = fn(x) { fn(y) { x + y } }; let addtwo = make(2); addtwo(40)",
+            "Unsupported at line 1: cannot compile captured variable: x",
+        ),
+        (
+            "let outer = fn() { let a = 1; let b = 100; let inner = fn(z) { b }; inner(7) };
+outer()",
+            "Unsupported at line 1: cannot compile captured variable: b",
+        ),
+        (
+            "let wrapper = fn() {
+  let f = fn(n) { let g = fn() { if (n == 0) { 0 } else { f(n - 1) } }; g() };
+  f(3)
+};
+wrapper()",
+            "Unsupported at line 2: cannot compile captured variable: n",
+        ),
+        (
+            "let wrapper = fn() {
+  let f = fn(k) { let g = fn() { f }; g() };
+  f(3)
+};
+wrapper()",
+            "Unsupported at line 2: cannot compile captured variable: f",
+        ),
+        (
+            "let f = fn(n) {
+  let even = fn(k) { if (k == 0) { true } else { odd(k - 1) } };
+  let odd = fn(k) { if (k == 0) { false } else { even(k - 1) } };
+  even(n)
+};
+f(4)",
+            "Unsupported at line 2: cannot compile captured variable: odd",
+        ),
+    ];
+    for (input, output) in inout {
+        let errors = compile(input).err().expect("expected a compile error");
+        assert_eq!(format!("{}", errors[0]), *output);
+    }
+}
+
+#[test]
+fn test_builtins() {
+    let inout = &[
+        (r#"len("")"#, Object::Int(0)),
+        (r#"len("four")"#, Object::Int(4)),
+        (r#"len("hello world")"#, Object::Int(11)),
+        ("len([1, 2, 3])", Object::Int(3)),
+        ("len([])", Object::Int(0)),
+        (
+            "len(1)",
+            Object::new_error("invalid argument type for builtin: len()"),
+        ),
+        (
+            r#"len("one", "two")"#,
+            Object::new_error("wrong number of arguments. got=2, want=1"),
+        ),
+        ("let f = fn(x) { len(x) }; f([1, 2])", Object::Int(2)),
+        ("let len = fn(x) { 1 }; len([1, 2])", Object::Int(1)),
+    ];
+    for (input, output) in inout {
+        assert_eq!(compile_and_run_vm(&input), *output);
+    }
+    assert_eq!(
+        format!("{}", compile_and_run_vm(r#"print("monkey")"#)),
+        format!("{}", Object::Ignore)
+    );
+}
+
+#[test]
+fn test_index_expr() {
+    let inout = &[
+        ("[1, 2, 3][1]", Object::Int(2)),
+        ("[1, 2, 3][0 + 2]", Object::Int(3)),
+        ("[[1, 1, 1]][0][0]", Object::Int(1)),
+        ("[1, 2, 3][-1]", Object::Int(3)),
+        ("[1, 2, 3][-3]", Object::Int(1)),
+        (
+            "[1, 2, 3][3]",
+            Object::new_error("index value outside the array's range: 3, index: 3"),
+        ),
+        (
+            "[1, 2, 3][-4]",
+            Object::new_error("index value outside the array's range: 3, index: -4"),
+        ),
+        ("{1: 1, 2: 2}[1]", Object::Int(1)),
+        ("{1: 1, 2: 2}[2]", Object::Int(2)),
+        (r#"{"one": 1}["o" + "ne"]"#, Object::Int(1)),
+        ("{1: 1}[0]", Object::new_error("key: 0 not found")),
+        (
+            "1[0]",
+            Object::new_error("index operator `int` not supported on: int"),
+        ),
+    ];
+    for (input, output) in inout {
+        assert_eq!(compile_and_run_vm(&input), *output);
+    }
+}
+
+#[test]
+fn test_hash_literals() {
+    let inout = &[
+        ("let h = {1: 2, 2: 3}; h[1] + h[2]", Object::Int(5)),
+        (
+            "let h = {1 + 1: 2 * 2, 3 + 3: 4 * 4}; h[2] + h[6]",
+            Object::Int(20),
+        ),
+        ("let h = {}; len([h])", Object::Int(1)),
+    ];
+    for (input, output) in inout {
+        assert_eq!(compile_and_run_vm(&input), *output);
+    }
+}
+
+#[test]
+fn test_method() {
+    let inout = &[
+        ("let h = {1: 1}; h.insert(2, 4); h[2]", Object::Int(4)),
+        (
+            "let h = {}; let f = fn(k, v) { h.insert(k, v) }; f(1, 2); h[1]",
+            Object::Int(2),
+        ),
+        (
+            "[1].insert(1, 2)",
+            Object::Error("method not found on array".to_string()),
+        ),
+        ("{}.foo()", Object::Error("method not found".to_string())),
+        // the call site is cached for hashes, then called on an array
+        (
+            "let f = fn(x) { x.insert(1, 2) }; f({}); f([1])",
+            Object::Error("method not found on array".to_string()),
+        ),
+        (
+            "let h = {}; let f = fn(k) { h.insert(k, k) }; f(1); f(2); h[1] + h[2]",
+            Object::Int(3),
+        ),
+    ];
+    for (input, output) in inout {
+        assert_eq!(compile_and_run_vm(&input), *output);
+    }
+}
+
+#[test]
+fn test_runtime_errors() {
+    use crate::code::OpCode;
+    let inout = &[
+        (
+            "1 + true",
+            VMError::TypeMismatch("int", OpCode::Add, "bool"),
+        ),
+        (
+            r#""a" - "b""#,
+            VMError::UnsupportedOperator(OpCode::Sub, "str"),
+        ),
+        (
+            "true > false",
+            VMError::UnsupportedOperator(OpCode::GT, "bool"),
+        ),
+        (
+            r#"-"a""#,
+            VMError::UnsupportedOperator(OpCode::Minus, "str"),
+        ),
+        ("!0", VMError::UnsupportedOperator(OpCode::Bang, "int")),
+        (
+            "!(if (false) { 5 })",
+            VMError::UnsupportedOperator(OpCode::Bang, "null"),
+        ),
+        ("1 / 0", VMError::DivisionByZero),
+        ("let a = 1; a()", VMError::CallingNonFunction("int")),
+        ("let f = fn() { f() }; f()", VMError::FrameOverflow),
+        ("let f = fn(x) { f(x) }; f(1)", VMError::StackOverflow),
+        (
+            "let a = 9223372036854775807; a + 1",
+            VMError::IntegerOverflow(OpCode::Add),
+        ),
+        (
+            "let a = 9223372036854775807; -a - 2",
+            VMError::IntegerOverflow(OpCode::Sub),
+        ),
+        (
+            "let a = 9223372036854775807; a * 2",
+            VMError::IntegerOverflow(OpCode::Mul),
+        ),
+        (
+            "let min = -9223372036854775807 - 1; min / -1",
+            VMError::IntegerOverflow(OpCode::Div),
+        ),
+        (
+            "let min = -9223372036854775807 - 1; -min",
+            VMError::IntegerOverflow(OpCode::Minus),
+        ),
+    ];
+    for (input, output) in inout {
+        assert_eq!(run_vm_err(&input).error, *output);
+    }
+}
+
+#[test]
+fn test_invalid_method_name() {
+    use crate::code::OpCode;
+    use crate::compiler::compiler::Bytecode;
+    use monkey::eval::object::LineTable;
+    let instructions: Vec<u8> = [
+        OpCode::Constant.make(&[0]),
+        OpCode::CallMethod.make(&[0, 0]),
+    ]
+    .concat();
+    let bc = Bytecode {
+        instructions: &instructions,
+        constants: &[Object::Int(1)],
+        line_table: &LineTable::new(),
+        num_globals: 0,
+    };
+    let err = run_vm(&bc).err().expect("expected a runtime error");
+    assert_eq!(err.error, VMError::InvalidMethodName("int"));
+}
+
+#[test]
+fn test_return_from_main() {
+    // a return outside of a function ends the program, like in the evaluator.
+    let inout = &[
+        ("return 5; 10", 5),
+        ("if (true) { return 5; }; 10", 5),
+        ("let f = fn() { 1 }; return f() + 1; 3", 2),
+    ];
+    for (input, output) in inout {
+        for opt_level in [OptLevel::O0, OptLevel::O2].iter() {
+            let com = compile_with_opt_level(input, *opt_level).unwrap();
+            assert_eq!(run_vm(&com.bytecode()).unwrap(), Object::Int(*output));
+        }
+    }
+}
+
+#[test]
+fn test_runtime_error_location() {
+    let input = "let a = 1;
+let f = fn(x) {
+  let y = x * 2;
+  y / 0
+};
+f(a)";
+    let err = run_vm_err(&input);
+    assert_eq!(err.error, VMError::DivisionByZero);
+    assert_eq!(err.span.unwrap().line, 4);
+    assert_eq!(
+        format!("{}", err),
+        "RuntimeError at line 4: division by zero"
+    );
+
+    let input = "let a = 1;
+a + true";
+    assert_eq!(run_vm_err(&input).span.unwrap().line, 2);
+
+    // the call that does not fit, not the instruction behind it
+    let input = "let f = fn() {
+  f()
+};
+f()";
+    let err = run_vm_err(&input);
+    assert_eq!(err.error, VMError::FrameOverflow);
+    assert_eq!(err.span.unwrap().line, 2);
+}
+
+/// Programs must give the same results and errors at every optimization level.
+#[test]
+fn test_optimized_programs() {
+    let inputs = [
+        "1 + 2 * 3 - 4 / 2",
+        "-(1 - 3) * 2",
+        r#""mon" + "key""#,
+        "1 < 2 == !false",
+        "if (1 > 2) { 10 } else { 20 }",
+        "if (0) { 10 }",
+        "if (true) { let a = 1; }",
+        "let f = fn(x) { if (true) { x * (2 + 3) } }; f(2)",
+        "let f = fn(x) { if (!true) { return 1; } x }; f(3)",
+        r#"{"a" + "b": 1 + 1}["ab"]"#,
+        "[1 + 1, 2 * 2][3 - 2]",
+        "9223372036854775807 + 0",
+        "1; 2; 3",
+        "let a = 1; 2; a",
+        "let f = fn() { 1; 2; 3 }; f()",
+        "let f = fn(a, b) { 1; a + b }; f(3, 4)",
+        r#"let f = fn(a, b) { a + b }; f("mon", "key")"#,
+        "let f = fn(a) { let b = 0; let c = 1; a + b + c + 255 + 256 }; f(-1)",
+        "let x = 3; if (x > 1) { if (x > 2) { 1 } else { 2 } } else { 3 }",
+        "let x = 2; if (x > 1) { if (x > 2) { 1 } else { 2 } } else { 3 }",
+        "let x = 0; if (x > 1) { if (x > 2) { 1 } } else { if (x < 1) { 4 } }",
+        "let fib = fn(n) { if (n < 2) { n } else { fib(n - 1) + fib(n - 2) } }; fib(15)",
+    ];
+    let errors = [
+        "1 / 0",
+        r#""a" - "b""#,
+        "-true",
+        "1 + true",
+        "let f = fn(a, b) {\n a + b\n}; f(1, true)",
+    ];
+    for opt_level in [OptLevel::O1, OptLevel::O2].iter() {
+        for input in inputs.iter() {
+            let com = compile_with_opt_level(input, *opt_level).unwrap();
+            let optimized = run_vm(&com.bytecode()).unwrap();
+            // Compared as debug strings, the value of a let is not equal to itself.
+            assert_eq!(
+                format!("{:?}", optimized),
+                format!("{:?}", compile_and_run_vm(input)),
+                "{}",
+                input
+            );
+        }
+        for input in errors.iter() {
+            let com = compile_with_opt_level(input, *opt_level).unwrap();
+            let optimized = run_vm(&com.bytecode()).unwrap_err();
+            let unoptimized = run_vm_err(input);
+            assert_eq!(optimized.error, unoptimized.error, "{}", input);
+            assert_eq!(optimized.span, unoptimized.span, "{}", input);
+        }
+    }
+}
+
+/// An identifier for the number, the lexer does not allow digits in identifiers.
+fn identifier(prefix: &str, n: usize) -> String {
+    let digits = n.to_string();
+    let letters = digits.bytes().map(|d| (d - b'0' + b'a') as char);
+    prefix.chars().chain(letters).collect()
+}
+
+/// Programs with operands that only fit wide instructions.
+#[test]
+fn test_wide_operands() {
+    let constants = (0..=70_000).map(|i| format!("{};", i)).collect::<String>();
+    let locals = format!(
+        "let f = fn() {{ {} la + lcjj }}; f()",
+        (0..300)
+            .map(|i| format!("let {} = {};", identifier("l", i), i))
+            .collect::<String>()
+    );
+    let params = (0..300).map(|i| identifier("a", i)).collect::<Vec<_>>();
+    let args = (0..300).map(|i| i.to_string()).collect::<Vec<_>>();
+    let arguments = format!(
+        "let f = fn({}) {{ ab + acjj }}; f({})",
+        params.join(", "),
+        args.join(", ")
+    );
+    let long_if = format!(
+        "let f = fn(c) {{ if (c) {{ {} 1 }} else {{ 2 }} }}; f(true) + f(false) * 10",
+        "c;".repeat(30_000)
+    );
+    let tests = [
+        (constants, 70_000),
+        (locals, 299),
+        (arguments, 300),
+        (long_if, 21),
+    ];
+    for (input, want) in tests.iter() {
+        for opt_level in [OptLevel::O0, OptLevel::O2].iter() {
+            let com = compile_with_opt_level(input, *opt_level).unwrap();
+            verify(&com.bytecode()).unwrap();
+            assert_eq!(run_vm(&com.bytecode()).unwrap(), Object::Int(*want));
+        }
+    }
+}
+
+#[test]
+fn test_heap_is_released() {
+    // Every call creates an array, which is freed when the call returns.
+    let input = "let g = fn(n) {
+  if (n == 0) { 0 } else { let a = [n, n, n, n]; g(n - 1) + g(n - 1) }
+};
+g(10);
+let f = fn(n) { if (n == 0) { [] } else { let a = [n]; [a[0], f(n - 1)] } };
+f(3)";
+    let com = compile(input).unwrap();
+    let bc = com.bytecode();
+    let mut vm = VM::new(&bc);
+    execute(&mut vm, &mut new_globals(&bc)).unwrap();
+    assert_eq!(format!("{}", vm.last_popped()), "[3, [2, [1, []]]]");
+    // only the array that f returned is left
+    assert_eq!(vm.heap.mark(), 1);
+}
@@ -0,0 +1,258 @@
+//! The values the vm computes with.
+use crate::code::OpCode;
+use crate::err::VMError;
+use monkey::eval::builtins::BUILTIN_LIST;
+use monkey::eval::object::Object;
+use std::borrow::Cow;
+
+/// A value on the stack or in a global. Ints, bools and null are stored inline, all
+/// other objects live on the heap and are referred to by their handle.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Int(i64),
+    Builtin(usize), // index in BUILTIN_LIST
+    Heap(usize),    // handle of an object on the heap
+}
+
+impl Value {
+    pub fn is_truthy(self) -> bool {
+        !matches!(self, Value::Null | Value::Bool(false) | Value::Int(0))
+    }
+}
+
+/// The objects that values refer to. The constants are borrowed from the bytecode and
+/// their handle is their index in the constants pool. Objects created while running are
+/// stored behind them. The objects a function creates are freed when it returns, except
+/// the one it returns, as only globals outlive a frame and functions don't set them.
+#[derive(Debug, Clone)]
+pub struct Heap<'cmpl> {
+    constants: &'cmpl [Object],
+    objects: Vec<Object>,
+}
+
+impl<'cmpl> Heap<'cmpl> {
+    pub fn new(constants: &'cmpl [Object]) -> Heap<'cmpl> {
+        Heap {
+            constants,
+            objects: vec![],
+        }
+    }
+
+    /// Value of the constant at the index in the constants pool.
+    pub fn constant(&self, index: usize) -> Value {
+        match self.constants[index] {
+            Object::Int(v) => Value::Int(v),
+            Object::Bool(v) => Value::Bool(v),
+            Object::Null => Value::Null,
+            _ => Value::Heap(index),
+        }
+    }
+
+    pub fn get(&self, handle: usize) -> &Object {
+        match self.constants.get(handle) {
+            Some(constant) => constant,
+            None => &self.objects[handle - self.constants.len()],
+        }
+    }
+
+    /// Value of the object. Objects that cannot be stored inline are moved to the heap.
+    pub fn alloc(&mut self, object: Object) -> Value {
+        match object {
+            Object::Int(v) => Value::Int(v),
+            Object::Bool(v) => Value::Bool(v),
+            Object::Null => Value::Null,
+            object => {
+                self.objects.push(object);
+                Value::Heap(self.constants.len() + self.objects.len() - 1)
+            }
+        }
+    }
+
+    /// Mark of the objects created so far, see `release`.
+    pub fn mark(&self) -> usize {
+        self.objects.len()
+    }
+
+    /// Free the objects created since the mark, except the object of `value`, which
+    /// gets the first freed handle. Returns the value with its new handle.
+    pub fn release(&mut self, mark: usize, value: Value) -> Value {
+        let first = self.constants.len() + mark;
+        match value {
+            Value::Heap(handle) if handle >= first => {
+                self.objects.swap(mark, handle - self.constants.len());
+                self.objects.truncate(mark + 1);
+                Value::Heap(first)
+            }
+            value => {
+                self.objects.truncate(mark);
+                value
+            }
+        }
+    }
+
+    /// Object of the value. Borrowed if it lives on the heap.
+    pub fn object(&self, value: Value) -> Cow<'_, Object> {
+        match value {
+            Value::Null => Cow::Owned(Object::Null),
+            Value::Bool(v) => Cow::Owned(Object::Bool(v)),
+            Value::Int(v) => Cow::Owned(Object::Int(v)),
+            Value::Builtin(i) => Cow::Owned(Object::Builtin(BUILTIN_LIST[i].clone())),
+            Value::Heap(handle) => Cow::Borrowed(self.get(handle)),
+        }
+    }
+
+    /// Objects of the values, e.g. the arguments of a builtin.
+    pub fn objects(&self, values: &[Value]) -> Vec<Object> {
+        values
+            .iter()
+            .map(|value| self.object(*value).into_owned())
+            .collect()
+    }
+
+    pub fn get_type(&self, value: Value) -> &'static str {
+        match value {
+            Value::Null => "null",
+            Value::Bool(_) => "bool",
+            Value::Int(_) => "int",
+            Value::Builtin(_) => "builtin",
+            Value::Heap(handle) => self.get(handle).get_type(),
+        }
+    }
+}
+
+pub fn exec_binary(
+    heap: &mut Heap,
+    left: Value,
+    right: Value,
+    op: OpCode,
+) -> Result<Value, VMError> {
+    match (left, right) {
+        (Value::Int(l), Value::Int(r)) => binary_operation(l, r, op),
+        (Value::Heap(l), Value::Heap(r)) => match (heap.get(l), heap.get(r)) {
+            (Object::String(l), Object::String(r)) => {
+                let result = string_infix(l, r, op)?;
+                Ok(heap.alloc(result))
+            }
+            (l, r) => Err(VMError::TypeMismatch(l.get_type(), op, r.get_type())),
+        },
+        _ => Err(type_mismatch(heap, left, op, right)),
+    }
+}
+
+pub fn exec_cmp(
+    heap: &Heap,
+    left: Value,
+    right: Value,
+    op: OpCode,
+) -> Result<Value, VMError> {
+    match (left, right) {
+        (Value::Int(l), Value::Int(r)) => exec_int_cmp(l, r, op),
+        (Value::Bool(l), Value::Bool(r)) => exec_bool_cmp(l, r, op),
+        _ if heap.get_type(left) == heap.get_type(right) => {
+            Err(VMError::UnsupportedOperator(op, heap.get_type(left)))
+        }
+        _ => Err(type_mismatch(heap, left, op, right)),
+    }
+}
+
+pub fn exec_prefix(heap: &Heap, right: Value, oc: OpCode) -> Result<Value, VMError> {
+    let result = match (oc, right) {
+        // like in the evaluator, only bools can be negated
+        (OpCode::Bang, Value::Bool(v)) => Value::Bool(!v),
+        (OpCode::Minus, Value::Int(v)) => {
+            Value::Int(v.checked_neg().ok_or(VMError::IntegerOverflow(oc))?)
+        }
+        _ => return Err(VMError::UnsupportedOperator(oc, heap.get_type(right))),
+    };
+    Ok(result)
+}
+
+fn type_mismatch(heap: &Heap, left: Value, op: OpCode, right: Value) -> VMError {
+    VMError::TypeMismatch(heap.get_type(left), op, heap.get_type(right))
+}
+
+fn binary_operation(l: i64, r: i64, op: OpCode) -> Result<Value, VMError> {
+    let result = match op {
+        OpCode::Add => l.checked_add(r),
+        OpCode::Sub => l.checked_sub(r),
+        OpCode::Mul => l.checked_mul(r),
+        OpCode::Div => {
+            if r == 0 {
+                return Err(VMError::DivisionByZero);
+            }
+            l.checked_div(r)
+        }
+        _ => return Err(VMError::UnsupportedOperator(op, "int")),
+    };
+    result.map(Value::Int).ok_or(VMError::IntegerOverflow(op))
+}
+
+fn exec_int_cmp(left: i64, right: i64, op: OpCode) -> Result<Value, VMError> {
+    match op {
+        OpCode::Equal => Ok(Value::Bool(left == right)),
+        OpCode::GT => Ok(Value::Bool(left > right)),
+        OpCode::NotEqual => Ok(Value::Bool(left != right)),
+        _ => Err(VMError::UnsupportedOperator(op, "int")),
+    }
+}
+
+fn exec_bool_cmp(left: bool, right: bool, op: OpCode) -> Result<Value, VMError> {
+    match op {
+        OpCode::Equal => Ok(Value::Bool(left == right)),
+        OpCode::NotEqual => Ok(Value::Bool(left != right)),
+        _ => Err(VMError::UnsupportedOperator(op, "bool")),
+    }
+}
+
+fn string_infix(left: &str, right: &str, oc: OpCode) -> Result<Object, VMError> {
+    match oc {
+        OpCode::Add => Ok(Object::from(format!("{}{}", left, right))),
+        _ => Err(VMError::UnsupportedOperator(oc, "str")),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_heap() {
+        let constants = [Object::Int(1), Object::from("a")];
+        let mut heap = Heap::new(&constants);
+        assert_eq!(heap.constant(0), Value::Int(1));
+        assert_eq!(heap.constant(1), Value::Heap(1));
+        assert_eq!(heap.alloc(Object::Bool(true)), Value::Bool(true));
+
+        let b = heap.alloc(Object::from("b"));
+        assert_eq!(b, Value::Heap(2));
+        assert_eq!(*heap.object(b), Object::from("b"));
+        assert_eq!(*heap.object(Value::Int(3)), Object::Int(3));
+        assert_eq!(heap.get_type(Value::Heap(1)), "str");
+        assert_eq!(heap.get_type(Value::Builtin(0)), "builtin");
+    }
+
+    #[test]
+    fn test_release() {
+        let constants = [Object::from("a")];
+        let mut heap = Heap::new(&constants);
+        let b = heap.alloc(Object::from("b"));
+        let mark = heap.mark();
+        heap.alloc(Object::from("c"));
+        let d = heap.alloc(Object::from("d"));
+        assert_eq!(heap.release(mark, d), Value::Heap(2));
+        assert_eq!(*heap.object(Value::Heap(2)), Object::from("d"));
+        assert_eq!(heap.mark(), 2);
+
+        // values from before the mark keep their handle
+        assert_eq!(heap.release(1, b), b);
+        assert_eq!(heap.release(0, Value::Heap(0)), Value::Heap(0));
+        assert_eq!(heap.mark(), 0);
+    }
+
+    #[test]
+    fn test_value_size() {
+        assert_eq!(std::mem::size_of::<Value>(), 16);
+    }
+}
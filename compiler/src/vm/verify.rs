@@ -0,0 +1,327 @@
+use crate::code::{decode, Instruction, OpCode, Operand};
+use crate::compiler::compiler::Bytecode;
+use crate::err::{InvalidBytecode, VerifyError};
+use crate::vm::vm::MAX_GLOBALS;
+use monkey::eval::builtins::BUILTIN_LIST;
+use monkey::eval::object::{LineTable, Object};
+use std::collections::VecDeque;
+
+/// The code that is verified. Either the main program or a compiled function.
+struct Code<'a> {
+    instructions: &'a [u8],
+    num_locals: Option<usize>, // None for the main program
+}
+
+/// Check that the bytecode can be run by the vm without reading out of bounds.
+///
+/// Verifies that every instruction is a known opcode with all its operands, that jumps
+/// land on instruction boundaries, that constants, globals, locals and builtins exist and that
+/// the stack height is the same on every path to an instruction.
+pub fn verify(bc: &Bytecode) -> Result<(), InvalidBytecode> {
+    if bc.num_globals > MAX_GLOBALS {
+        return Err(InvalidBytecode {
+            error: VerifyError::TooManyGlobals(bc.num_globals),
+            function: None,
+            offset: 0,
+        });
+    }
+    let main = Code {
+        instructions: bc.instructions,
+        num_locals: None,
+    };
+    verify_code(&main, bc).map_err(|(offset, error)| InvalidBytecode {
+        error,
+        function: None,
+        offset,
+    })?;
+
+    for (i, constant) in bc.constants.iter().enumerate() {
+        if let Object::CompiledFunction(cf) = constant {
+            let function = Code {
+                instructions: &cf.instructions,
+                num_locals: Some(cf.num_locals),
+            };
+            let result = if cf.num_parameters > cf.num_locals {
+                Err((0, VerifyError::LocalOutOfRange(cf.num_parameters)))
+            } else {
+                verify_code(&function, bc)
+            };
+            result.map_err(|(offset, error)| InvalidBytecode {
+                error,
+                function: Some(i),
+                offset,
+            })?;
+        }
+    }
+    Ok(())
+}
+
+fn verify_code(code: &Code, bc: &Bytecode) -> Result<(), (usize, VerifyError)> {
+    let decoded = decode(code.instructions).collect::<Result<Vec<_>, _>>()?;
+    let len = code.instructions.len();
+    let index_of =
+        |offset: usize| decoded.binary_search_by_key(&offset, |ins| ins.offset);
+
+    for ins in decoded.iter() {
+        check_operands(ins, code, bc).map_err(|e| (ins.offset, e))?;
+    }
+
+    // Walk all paths through the code and track the stack height of every instruction.
+    let mut heights: Vec<Option<usize>> = vec![None; decoded.len()];
+    let mut work = VecDeque::new();
+    if !decoded.is_empty() {
+        heights[0] = Some(0);
+        work.push_back(0);
+    } else if code.num_locals.is_some() {
+        return Err((0, VerifyError::MissingReturn));
+    }
+
+    while let Some(idx) = work.pop_front() {
+        let ins = &decoded[idx];
+        let height = heights[idx].unwrap();
+        let (n_pop, n_push) = ins.stack_effect();
+        if height < n_pop {
+            return Err((ins.offset, VerifyError::StackUnderflow));
+        }
+        let height = height - n_pop + n_push;
+
+        let mut successors = vec![];
+        match ins.oc {
+            OpCode::Return | OpCode::ReturnVal => {}
+            OpCode::Jump => successors.push(ins.operands[0]),
+            OpCode::JumpNotTruthy => {
+                successors.push(ins.operands[0]);
+                successors.push(ins.offset + ins.width());
+            }
+            _ => successors.push(ins.offset + ins.width()),
+        }
+
+        for target in successors {
+            if target == len {
+                // Only the main program may run until the end of its instructions.
+                if code.num_locals.is_some() {
+                    return Err((ins.offset, VerifyError::MissingReturn));
+                }
+                continue;
+            }
+            let next = match index_of(target) {
+                Ok(next) if target < len => next,
+                _ => return Err((ins.offset, VerifyError::InvalidJumpTarget(target))),
+            };
+            match heights[next] {
+                None => {
+                    heights[next] = Some(height);
+                    work.push_back(next);
+                }
+                Some(h) if h != height => {
+                    return Err((
+                        target,
+                        VerifyError::InconsistentStackHeight {
+                            want: h,
+                            got: height,
+                        },
+                    ))
+                }
+                _ => {}
+            }
+        }
+    }
+    Ok(())
+}
+
+fn check_operands(
+    ins: &Instruction,
+    code: &Code,
+    bc: &Bytecode,
+) -> Result<(), VerifyError> {
+    let constants = bc.constants;
+    match ins.oc {
+        OpCode::Constant if ins.operands[0] >= constants.len() => {
+            Err(VerifyError::ConstantOutOfRange(ins.operands[0]))
+        }
+        OpCode::CallMethod => match constants.get(ins.operands[0]) {
+            Some(Object::String(_)) => Ok(()),
+            _ => Err(VerifyError::InvalidMethodName(ins.operands[0])),
+        },
+        OpCode::SetLocal | OpCode::GetLocal | OpCode::AddLocals => {
+            match code.num_locals {
+                Some(n) => match ins.operands.iter().find(|index| **index >= n) {
+                    Some(index) => Err(VerifyError::LocalOutOfRange(*index)),
+                    None => Ok(()),
+                },
+                None => Err(VerifyError::NotInFunction(ins.oc)),
+            }
+        }
+        OpCode::SetGlobal | OpCode::GetGlobal if ins.operands[0] >= bc.num_globals => {
+            Err(VerifyError::GlobalOutOfRange(ins.operands[0]))
+        }
+        // The vm frees the objects of a function when it returns, so it may not store
+        // them in a global.
+        OpCode::SetGlobal if code.num_locals.is_some() => {
+            Err(VerifyError::SetGlobalInFunction)
+        }
+        OpCode::GetBuiltin if ins.operands[0] >= BUILTIN_LIST.len() => {
+            Err(VerifyError::BuiltinOutOfRange(ins.operands[0]))
+        }
+        OpCode::Hash if !ins.operands[0].is_multiple_of(2) => {
+            Err(VerifyError::OddHashLength(ins.operands[0]))
+        }
+        // a return with a value ends the main program
+        OpCode::Return | OpCode::CurrentFunction if code.num_locals.is_none() => {
+            Err(VerifyError::NotInFunction(ins.oc))
+        }
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::compiler::compiler::OptLevel;
+    use crate::utils::compile_with_opt_level;
+    use OpCode::*;
+
+    fn make(instructions: &[(OpCode, &[Operand])]) -> Vec<u8> {
+        instructions
+            .iter()
+            .flat_map(|(oc, operands)| oc.make(operands))
+            .collect()
+    }
+
+    fn verify_main(instructions: &[u8], constants: &[Object]) -> Result<(), VerifyError> {
+        let bc = Bytecode {
+            instructions,
+            constants,
+            line_table: &LineTable::new(),
+            num_globals: 1,
+        };
+        verify(&bc).map_err(|e| e.error)
+    }
+
+    #[test]
+    fn test_compiled_programs() {
+        let inputs = [
+            "1 + 2; [1, 2][0]; {1: 2}.insert(3, 4)",
+            "if (true) { 10 } else { 20 }; if (false) { 10 }",
+            "let fib = fn(x) { if (x < 2) { return x; } fib(x - 1) + fib(x - 2) }; fib(5)",
+            "let f = fn(a, b) { let c = a + b; len([c]) }; f(1, 2)",
+            "fn() {}",
+            "if (true) { return 5; }; 10",
+        ];
+        for opt_level in [OptLevel::O0, OptLevel::O2].iter() {
+            for input in inputs.iter() {
+                let com = compile_with_opt_level(input, *opt_level).unwrap();
+                verify(&com.bytecode()).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn test_invalid_programs() {
+        let ints = [Object::Int(1)];
+        let cases: &[(Vec<u8>, VerifyError)] = &[
+            (vec![255], VerifyError::InvalidOpCode(255)),
+            (
+                vec![Constant as u8, 0],
+                VerifyError::TruncatedInstruction(Constant),
+            ),
+            (
+                make(&[(Constant, &[1]), (Pop, &[])]),
+                VerifyError::ConstantOutOfRange(1),
+            ),
+            (make(&[(Pop, &[])]), VerifyError::StackUnderflow),
+            (
+                make(&[(True, &[]), (Add, &[])]),
+                VerifyError::StackUnderflow,
+            ),
+            (
+                make(&[(True, &[]), (JumpNotTruthy, &[2]), (Null, &[])]),
+                VerifyError::InvalidJumpTarget(2),
+            ),
+            (make(&[(Jump, &[10])]), VerifyError::InvalidJumpTarget(10)),
+            (
+                // Null is reached with and without the constant on the stack.
+                make(&[
+                    (True, &[]),
+                    (JumpNotTruthy, &[7]),
+                    (Constant, &[0]),
+                    (Null, &[]),
+                ]),
+                VerifyError::InconsistentStackHeight { want: 0, got: 1 },
+            ),
+            (
+                make(&[(GetLocal, &[0])]),
+                VerifyError::NotInFunction(GetLocal),
+            ),
+            (make(&[(Return, &[])]), VerifyError::NotInFunction(Return)),
+            (
+                make(&[(GetBuiltin, &[200])]),
+                VerifyError::BuiltinOutOfRange(200),
+            ),
+            (make(&[(Hash, &[1])]), VerifyError::OddHashLength(1)),
+            (
+                make(&[(Null, &[]), (SetGlobal, &[0]), (GetGlobal, &[1])]),
+                VerifyError::GlobalOutOfRange(1),
+            ),
+            (
+                make(&[(Null, &[]), (SetGlobal, &[0xFFFF_FFFF])]),
+                VerifyError::GlobalOutOfRange(0xFFFF_FFFF),
+            ),
+            (
+                vec![Wide as u8, Pop as u8],
+                VerifyError::UnexpectedWide(Pop),
+            ),
+            (
+                make(&[(Constant, &[70_000]), (Pop, &[])]),
+                VerifyError::ConstantOutOfRange(70_000),
+            ),
+        ];
+        for (instructions, error) in cases.iter() {
+            assert_eq!(verify_main(instructions, &ints), Err(error.clone()));
+        }
+
+        // The global is in range, but the vm has no room for that many globals.
+        let instructions = make(&[(Null, &[]), (SetGlobal, &[0xFFFF_FFF0])]);
+        let bc = Bytecode {
+            instructions: &instructions,
+            constants: &[],
+            line_table: &LineTable::new(),
+            num_globals: 0xFFFF_FFFF,
+        };
+        let err = verify(&bc).unwrap_err();
+        assert_eq!(err.error, VerifyError::TooManyGlobals(0xFFFF_FFFF));
+        assert_eq!(err.function, None);
+    }
+
+    #[test]
+    fn test_invalid_functions() {
+        let function = |instructions: Vec<u8>| {
+            vec![Object::new_compiled_function(instructions, 1, 1)]
+        };
+        let main = make(&[(Constant, &[0]), (Pop, &[])]);
+        let cases: &[(Vec<u8>, VerifyError)] = &[
+            (make(&[(GetLocal, &[0])]), VerifyError::MissingReturn),
+            (
+                make(&[(GetLocal, &[0]), (SetGlobal, &[0]), (Return, &[])]),
+                VerifyError::SetGlobalInFunction,
+            ),
+            (
+                make(&[(GetLocal, &[1]), (ReturnVal, &[])]),
+                VerifyError::LocalOutOfRange(1),
+            ),
+            (vec![], VerifyError::MissingReturn),
+        ];
+        for (instructions, error) in cases.iter() {
+            let constants = function(instructions.clone());
+            let bc = Bytecode {
+                instructions: &main,
+                constants: &constants,
+                line_table: &LineTable::new(),
+                num_globals: 1,
+            };
+            let err = verify(&bc).unwrap_err();
+            assert_eq!(err.error, *error);
+            assert_eq!(err.function, Some(0));
+        }
+    }
+}
@@ -1,72 +1,113 @@
-use crate::code::{read_be_u16, OpCode, Operand};
+use crate::code::{OpCode, Operand};
 use crate::compiler::compiler::Bytecode;
-use crate::err::VMError;
-use monkey::eval::{evaluator::is_truthy, object::Object};
-use std::borrow::{Borrow, Cow};
+use crate::err::{RuntimeError, VMError};
+#[cfg(feature = "jit")]
+use crate::vm::jit::Jit;
+use crate::vm::value::{exec_binary, exec_cmp, exec_prefix, Heap, Value};
+use fnv::FnvHashMap as HashMap;
+use monkey::eval::{
+    builtins::{lookup_method, BuiltinFn, BUILTIN_LIST},
+    object::{CompiledFunction, LineTable, Object},
+};
+use monkey::lexer::token::Span;
 use std::convert::TryFrom;
-use std::mem;
-use std::ptr::null;
-
-const STACKSIZE: usize = 2048;
-const OBJECT_TRUE: Object = Object::Bool(true);
-const OBJECT_FALSE: Object = Object::Bool(false);
-const COW_TRUE: Cow<'static, Object> = Cow::Borrowed(&OBJECT_TRUE);
-const COW_FALSE: Cow<'static, Object> = Cow::Borrowed(&OBJECT_FALSE);
-const OBJECT_NULL: Object = Object::Null;
-const COW_NULL: Cow<'static, Object> = Cow::Borrowed(&OBJECT_NULL);
-const EMPTY_STACK: &'static str = "nothing on the stack";
-const GLOBAL_SIZE: usize = 65536;
-const MAX_FRAMES: usize = 1024;
+use std::rc::Rc;
 
+pub(crate) const STACKSIZE: usize = 2048;
+pub(crate) const MAX_FRAMES: usize = 1024;
+/// The most globals a program can have. The compiler refuses more, and the verifier
+/// rejects bytecode that declares more.
+pub const MAX_GLOBALS: usize = 65_536;
+
+/// The instructions a frame executes. The main program is borrowed from the bytecode,
+/// a function shares its instructions with the constants pool.
 #[derive(Clone)]
-pub struct Frame {
-    function_instr: Vec<u8>, // Object::CompiledFunction
-    ip: usize,               // instruction pointer
+enum Code<'cmpl> {
+    Main(&'cmpl [u8]),
+    Function(Rc<CompiledFunction>),
+}
+
+#[derive(Clone)]
+pub struct Frame<'cmpl> {
+    code: Code<'cmpl>,
+    ip: usize,           // instruction pointer
+    base_pointer: usize, // stack pointer before the function's locals
+    heap_mark: usize,    // objects on the heap when the frame was entered
+}
+
+impl Code<'_> {
+    fn instructions(&self) -> &[u8] {
+        match self {
+            Code::Main(instructions) => instructions,
+            Code::Function(cf) => &cf.instructions,
+        }
+    }
 }
 
-impl Frame {
-    fn new(function_instr: Vec<u8>) -> Frame {
+impl<'cmpl> Frame<'cmpl> {
+    fn new(code: Code<'cmpl>, base_pointer: usize, heap_mark: usize) -> Frame<'cmpl> {
         Frame {
-            function_instr,
+            code,
             ip: 0, // -1 not possible
+            base_pointer,
+            heap_mark,
         }
     }
 
     fn instructions(&self) -> &[u8] {
-        &self.function_instr
+        self.code.instructions()
     }
 }
 
 #[derive(Clone)]
 pub struct VM<'cmpl> {
     pub constants: &'cmpl [Object],
-    // pub globals: Vec<Object>,
-    pub stack: Vec<Cow<'cmpl, Object>>,
+    main_line_table: &'cmpl LineTable,
+    pub heap: Heap<'cmpl>,
+    pub stack: Vec<Value>,
     pub sp: usize, // Stack Pointer: points to the next free registry on the stack
-    pub frames: Vec<Frame>,
+    pub frames: Vec<Frame<'cmpl>>,
     pub frames_index: usize,
+    method_cache: HashMap<CallSite, MethodCache>,
+    #[cfg(feature = "jit")]
+    pub(crate) jit: Jit,
+}
+
+/// A CallMethod instruction: the address of the code it is part of and its offset.
+type CallSite = (usize, usize);
+
+/// The method that a call site resolved to, for receivers of the type.
+#[derive(Clone, Copy)]
+struct MethodCache {
+    receiver_type: &'static str,
+    method: BuiltinFn,
 }
 
 impl VM<'_> {
     pub fn new<'cmpl>(bytecode: &'cmpl Bytecode) -> VM<'cmpl> {
-        let main_instructions = bytecode.instructions.to_vec();
-        let main_frame = Frame::new(main_instructions);
+        let main_frame = Frame::new(Code::Main(bytecode.instructions), 0, 0);
         let mut frames = Vec::with_capacity(MAX_FRAMES);
         frames.push(main_frame);
 
         VM {
             constants: bytecode.constants,
+            main_line_table: bytecode.line_table,
+            heap: Heap::new(bytecode.constants),
 
-            stack: vec![OBJECT_NULL.into(); STACKSIZE],
+            // grows up to STACKSIZE with the values that are pushed
+            stack: vec![],
             sp: 0,
             frames,
             frames_index: 1,
+            method_cache: HashMap::default(),
+            #[cfg(feature = "jit")]
+            jit: Jit::new(),
         }
     }
 }
 
 impl<'cmpl> VM<'cmpl> {
-    pub fn current_frame(&mut self) -> &mut Frame {
+    pub fn current_frame(&mut self) -> &mut Frame<'cmpl> {
         self.frames.last_mut().unwrap()
     }
 
@@ -74,263 +115,491 @@ impl<'cmpl> VM<'cmpl> {
         self.frames[self.frames_index - 1].instructions()
     }
 
-    pub fn push_frame(&mut self, f: Frame) {
+    pub fn push_frame(&mut self, f: Frame<'cmpl>) -> Result<(), VMError> {
+        if self.frames_index >= MAX_FRAMES {
+            return Err(VMError::FrameOverflow);
+        }
         self.frames.push(f);
-        self.frames_index += 1
+        self.frames_index += 1;
+        Ok(())
     }
 
-    pub fn pop_frame(&mut self) -> Frame {
+    pub fn pop_frame(&mut self) -> Frame<'cmpl> {
         self.frames_index -= 1;
         self.frames.pop().unwrap()
     }
 
-    pub fn stack_top(&self) -> Option<&Object> {
+    pub fn stack_top(&self) -> Option<Value> {
         if self.sp == 0 {
             None
         } else {
-            Some(&self.stack[self.sp - 1])
-        }
-    }
-
-    pub fn pop(&mut self) -> Option<&Object> {
-        if self.sp == 0 {
-            return None;
+            Some(self.stack[self.sp - 1])
         }
-        self.sp -= 1;
-        self.stack.get(self.sp).map(|x| x.borrow())
     }
 
-    /// Use raw pointers to get two multiple objects of the stack without cloning
-    /// Needs unsafe code to dereference.
-    pub fn pop_raw(&mut self) -> Option<*const Object> {
+    pub fn pop(&mut self) -> Result<Value, VMError> {
         if self.sp == 0 {
-            return None;
+            return Err(VMError::StackUnderflow);
         }
-        let o = &*self.stack[self.sp - 1] as *const Object;
         self.sp -= 1;
-        Some(o)
+        Ok(self.stack[self.sp])
     }
 
-    /// Pop two references from the stack without cloning.
-    /// The borrowck doesn't let use call self.pop twice wo/ a clone.
-    pub fn pop_2(&mut self) -> Option<(&Object, &Object)> {
-        if self.sp <= 1 {
-            return None;
-        }
-        // first right than left. Such that this function can be unpacked as left, right
-        let two = unsafe {
-            let r = &*self.pop_raw().unwrap();
-            let l = &*self.pop_raw().unwrap();
-            (l, r)
-        };
-        Some(two)
+    /// Pop the right and then the left operand of an infix operator.
+    pub fn pop_2(&mut self) -> Result<(Value, Value), VMError> {
+        let right = self.pop()?;
+        let left = self.pop()?;
+        Ok((left, right))
     }
 
-    pub fn push(&mut self, o: Cow<'cmpl, Object>) -> Result<(), VMError> {
+    pub fn push(&mut self, value: Value) -> Result<(), VMError> {
         if self.sp >= STACKSIZE {
             return Err(VMError::StackOverflow);
         }
-        self.stack[self.sp] = o.into();
+        if self.sp == self.stack.len() {
+            self.stack.push(value);
+        } else {
+            self.stack[self.sp] = value;
+        }
         self.sp += 1;
         Ok(())
     }
 
-    pub fn last_popped(&self) -> &Object {
-        &self.stack[self.sp]
+    /// Store the object on the heap and push its value.
+    fn push_object(&mut self, object: Object) -> Result<(), VMError> {
+        let value = self.heap.alloc(object);
+        self.push(value)
+    }
+
+    /// Check that at least `n` values are on the stack.
+    fn check_stack(&self, n: usize) -> Result<(), VMError> {
+        if self.sp < n {
+            Err(VMError::StackUnderflow)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Location in the source of the instruction that is being executed.
+    pub fn current_span(&self) -> Option<Span> {
+        let frame = &self.frames[self.frames_index - 1];
+        let line_table = match &frame.code {
+            Code::Main(_) => self.main_line_table,
+            Code::Function(cf) => &cf.line_table,
+        };
+        line_table.span_at(frame.ip)
+    }
+
+    pub fn last_popped(&self) -> Object {
+        match self.stack.get(self.sp) {
+            Some(value) => self.heap.object(*value).into_owned(),
+            None => Object::Null,
+        }
     }
 
     fn build_array(&self, start_index: usize, end_index: usize) -> Object {
-        let mut elements = Vec::with_capacity(end_index - start_index);
+        Object::new_array(self.owned_objects(start_index, end_index))
+    }
+
+    /// Keys and values are interleaved on the stack.
+    fn build_hash(&self, start_index: usize, end_index: usize) -> Object {
+        let n_pairs = (end_index - start_index) / 2;
+        let mut keys = Vec::with_capacity(n_pairs);
+        let mut values = Vec::with_capacity(n_pairs);
 
-        for i in start_index..end_index {
-            let el = self.stack[i].clone();
-            elements.push(el.into_owned())
+        for i in (start_index..end_index).step_by(2) {
+            keys.push(self.heap.object(self.stack[i]).into_owned());
+            values.push(self.heap.object(self.stack[i + 1]).into_owned());
         }
-        Object::new_array(elements)
+        Object::new_hash(keys, values)
     }
-}
 
-fn binary_operation(l: i64, r: i64, op: OpCode) -> Object {
-    match op {
-        OpCode::Add => Object::Int(l + r),
-        OpCode::Sub => Object::Int(l - r),
-        OpCode::Mul => Object::Int(l * r),
-        OpCode::Div => Object::Int(l / r),
-        _ => panic!("not impl"),
+    fn owned_objects(&self, start_index: usize, end_index: usize) -> Vec<Object> {
+        self.heap.objects(&self.stack[start_index..end_index])
     }
 }
 
-fn exec_cmp(left: &Object, right: &Object, op: OpCode) -> Object {
-    match (left, right) {
-        (Object::Int(l), Object::Int(r)) => exec_int_cmp(*l, *r, op),
-        (Object::Bool(l), Object::Bool(r)) => exec_bool_cmp(*l, *r, op),
-        _ => panic!("NOT IMPL"),
+pub fn run_vm(bc: &Bytecode) -> Result<Object, RuntimeError> {
+    let mut vm = VM::new(bc);
+    let mut globals = new_globals(bc);
+
+    match execute(&mut vm, &mut globals) {
+        Ok(()) => Ok(vm.last_popped()),
+        Err(error) => Err(RuntimeError {
+            error,
+            span: vm.current_span(),
+        }),
     }
 }
 
-fn exec_int_cmp(left: i64, right: i64, op: OpCode) -> Object {
-    match op {
-        OpCode::Equal => native_bool_to_object(left == right),
-        OpCode::GT => native_bool_to_object(left > right),
-        OpCode::NotEqual => native_bool_to_object(left != right),
-        _ => panic!("unknown operator {:?}", op),
-    }
+/// The globals of the program, which are unset until their let statement runs.
+pub(crate) fn new_globals(bc: &Bytecode) -> Vec<Option<Value>> {
+    vec![None; bc.num_globals]
 }
 
-fn exec_bool_cmp(left: bool, right: bool, op: OpCode) -> Object {
-    match op {
-        OpCode::Equal => native_bool_to_object(left == right),
-        OpCode::NotEqual => native_bool_to_object(left != right),
-        _ => panic!("unknown operator {:?}", op),
-    }
+/// Why a frame stopped running.
+enum Exit<'cmpl> {
+    Call(Frame<'cmpl>),
+    Return(Value),
+    End,
 }
 
-fn native_bool_to_object(input: bool) -> Object {
-    if input {
-        OBJECT_TRUE
-    } else {
-        OBJECT_FALSE
+pub(crate) fn execute(vm: &mut VM, globals: &mut [Option<Value>]) -> Result<(), VMError> {
+    loop {
+        let frame = vm.current_frame();
+        // Shares the code, such that the stack can be changed while it is borrowed.
+        let code = frame.code.clone();
+        let base_pointer = frame.base_pointer;
+        let mut ip = frame.ip;
+        let exit = run_frame(vm, globals, code.instructions(), base_pointer, &mut ip);
+        // On errors, the frame points at the instruction that failed.
+        vm.current_frame().ip = ip;
+        match exit? {
+            Exit::Call(frame) => {
+                vm.push_frame(frame)?;
+            }
+            Exit::Return(value) if vm.frames_index == 1 => {
+                // a return outside of a function ends the program with the value.
+                vm.push(value)?;
+                vm.pop()?;
+                return Ok(());
+            }
+            Exit::Return(value) => {
+                // leave function scope, the objects it created are no longer used
+                let frame = vm.pop_frame();
+                let value = vm.heap.release(frame.heap_mark, value);
+                // pop the locals and the just executed compiled function from the stack.
+                vm.sp = frame.base_pointer - 1;
+                vm.push(value)?;
+            }
+            Exit::End => return Ok(()),
+        }
     }
 }
 
-fn exec_prefix(right: &Object, oc: OpCode) -> Object {
-    match oc {
-        OpCode::Bang => match right {
-            Object::Bool(v) => native_bool_to_object(!*v),
-            Object::Int(i) => native_bool_to_object(!if *i == 0 { false } else { true }),
-            Object::Null => OBJECT_TRUE,
-            _ => Object::Error(format!("Prefix ! not allowed with {}", right.get_type())),
-        },
-        OpCode::Minus => match right {
-            Object::Int(v) => Object::Int(-*v),
-            _ => Object::Error(format!("Prefix - not allowed with {}", right.get_type())),
-        },
-        _ => panic!("unknown operator {:?}", oc),
+/// Read the operand of a one byte operand instruction at `ip`. Returns the operand and
+/// the offset of the next instruction.
+#[inline(always)]
+fn operand_u8(ins: &[u8], ip: usize, wide: bool) -> (Operand, usize) {
+    if wide {
+        (read_u16(ins, ip + 1), ip + 3)
+    } else {
+        (ins[ip + 1] as Operand, ip + 2)
     }
 }
 
-fn string_infix(left: &str, right: &str, oc: OpCode) -> Object {
-    match oc {
-        OpCode::Add => Object::String(format!("{}{}", left, right)),
-        _ => Object::Error(format!("operand {:?} not support on string", oc)),
+/// Like `operand_u8`, for a two byte operand.
+#[inline(always)]
+fn operand_u16(ins: &[u8], ip: usize, wide: bool) -> (Operand, usize) {
+    if wide {
+        let bytes = [ins[ip + 1], ins[ip + 2], ins[ip + 3], ins[ip + 4]];
+        (u32::from_be_bytes(bytes) as Operand, ip + 5)
+    } else {
+        (read_u16(ins, ip + 1), ip + 3)
     }
 }
 
-pub fn run_vm(bc: &Bytecode) -> Result<Object, VMError> {
-    let mut vm = VM::new(bc);
-    let mut globals = vec![OBJECT_NULL; GLOBAL_SIZE];
+#[inline(always)]
+fn read_u16(ins: &[u8], at: usize) -> Operand {
+    u16::from_be_bytes([ins[at], ins[at + 1]]) as Operand
+}
 
-    while vm.current_frame().ip < vm.current_frame().instructions().len() {
-        let i = vm.current_frame().ip;
-        let oc = unsafe { OpCode::from_unchecked(vm.current_instructions()[i]) };
-        match oc {
+/// Execute the instructions of the current frame from `ip`, until it calls a function,
+/// returns or ends. `ip` is kept at the instruction that is being executed.
+fn run_frame<'cmpl>(
+    vm: &mut VM<'cmpl>,
+    globals: &mut [Option<Value>],
+    ins: &[u8],
+    base_pointer: usize,
+    ip: &mut usize,
+) -> Result<Exit<'cmpl>, VMError> {
+    // set by a Wide prefix for the instruction behind it
+    let mut wide = false;
+    while *ip < ins.len() {
+        let byte = ins[*ip];
+        let oc = OpCode::try_from(byte).map_err(|_| VMError::UnknownOpCode(byte))?;
+        let next = match oc {
             OpCode::Constant => {
-                let (const_index, width) =
-                    oc.read_operand(&vm.current_instructions()[i + 1..]);
-                vm.current_frame().ip += width;
-                let r = vm.push(Cow::from(&vm.constants[const_index]))?;
+                let (const_index, next) = operand_u16(ins, *ip, wide);
+                vm.push(vm.heap.constant(const_index))?;
+                next
             }
             OpCode::Pop => {
-                vm.pop();
+                vm.pop()?;
+                *ip + 1
             }
             OpCode::Add | OpCode::Sub | OpCode::Mul | OpCode::Div => {
-                let (left, right) = vm.pop_2().expect(EMPTY_STACK);
-                let result = match (left, right) {
-                    (Object::Int(l), Object::Int(r)) => binary_operation(*l, *r, oc),
-                    (Object::String(l), Object::String(r)) => string_infix(l, r, oc),
-                    _ => panic!("not impl"),
-                };
-                vm.push(Cow::from(result));
+                let (left, right) = vm.pop_2()?;
+                let result = exec_binary(&mut vm.heap, left, right, oc)?;
+                vm.push(result)?;
+                *ip + 1
             }
             OpCode::True => {
-                vm.push(COW_TRUE);
+                vm.push(Value::Bool(true))?;
+                *ip + 1
             }
             OpCode::False => {
-                vm.push(COW_FALSE);
+                vm.push(Value::Bool(false))?;
+                *ip + 1
             }
             OpCode::Equal | OpCode::NotEqual | OpCode::GT => {
-                let result = {
-                    // left and right should be dropped before getting 2nd mutable borrow.
-                    let (left, right) = vm.pop_2().expect(EMPTY_STACK);
-                    exec_cmp(left, right, oc)
-                };
-                vm.push(Cow::from(result));
+                let (left, right) = vm.pop_2()?;
+                let result = exec_cmp(&vm.heap, left, right, oc)?;
+                vm.push(result)?;
+                *ip + 1
             }
             OpCode::Minus | OpCode::Bang => {
-                let result = {
-                    let right = vm.pop().expect(EMPTY_STACK);
-                    exec_prefix(right, oc)
-                };
-                vm.push(Cow::from(result));
-            }
-            OpCode::Jump => {
-                // TODO: benchmark by directly reading big endian 16 here
-                let (jump_pos, _) = oc.read_operand(&vm.current_instructions()[i + 1..]);
-                vm.current_frame().ip = jump_pos - 1;
+                let right = vm.pop()?;
+                let result = exec_prefix(&vm.heap, right, oc)?;
+                vm.push(result)?;
+                *ip + 1
             }
+            OpCode::Jump => operand_u16(ins, *ip, wide).0,
             OpCode::JumpNotTruthy => {
-                let condition = vm.pop().expect(EMPTY_STACK);
-                if !is_truthy(condition) {
-                    let (jump_pos, width) =
-                        oc.read_operand(&vm.current_instructions()[i + 1..]);
-                    vm.current_frame().ip = jump_pos - 1;
+                let condition = vm.pop()?;
+                let (jump_pos, next) = operand_u16(ins, *ip, wide);
+                if condition.is_truthy() {
+                    next
                 } else {
-                    // skip jump operand
-                    let width = oc.definition()[0];
-                    vm.current_frame().ip += width;
+                    jump_pos
                 }
             }
             OpCode::Null => {
-                vm.push(COW_NULL);
+                vm.push(Value::Null)?;
+                *ip + 1
+            }
+            OpCode::Ignore => {
+                vm.push_object(Object::Ignore)?;
+                *ip + 1
             }
             OpCode::SetGlobal => {
-                let (index, width) = oc.read_operand(&vm.current_instructions()[i + 1..]);
-                vm.current_frame().ip += width;
-                globals[index] = vm.pop().expect(EMPTY_STACK).clone();
+                let (index, next) = operand_u16(ins, *ip, wide);
+                globals[index] = Some(vm.pop()?);
+                next
             }
             OpCode::GetGlobal => {
-                let (index, width) = oc.read_operand(&vm.current_instructions()[i + 1..]);
-                vm.current_frame().ip += width;
-                let global = globals[index].clone();
-                vm.push(Cow::from(global));
+                let (index, next) = operand_u16(ins, *ip, wide);
+                // Like in the evaluator, a global cannot be used before it is set.
+                let global = globals[index].ok_or(VMError::UnsetGlobal(index))?;
+                vm.push(global)?;
+                next
             }
             OpCode::Array => {
-                let (n_elements, width) =
-                    oc.read_operand(&vm.current_instructions()[i + 1..]);
-                vm.current_frame().ip += width;
+                let (n_elements, next) = operand_u16(ins, *ip, wide);
+                vm.check_stack(n_elements)?;
                 let array = vm.build_array(vm.sp - n_elements, vm.sp);
-                vm.push(Cow::from(array));
+                vm.sp -= n_elements;
+                vm.push_object(array)?;
+                next
             }
             OpCode::Call => {
-                let fun = vm.stack_top().unwrap();
-                if let Object::CompiledFunction(instr) = fun {
-                    // TODO: borrow instructions. Lifetime mess.
-                    let frame = Frame::new(instr.clone());
-                    vm.push_frame(frame);
-                    // don't increment the instruction pointer this loop.
-                    continue;
+                let (n_args, next) = operand_u8(ins, *ip, wide);
+                vm.check_stack(n_args + 1)?;
+                let fun = vm.stack[vm.sp - 1 - n_args];
+                if let Value::Builtin(b) = fun {
+                    let f = BUILTIN_LIST[b].function;
+                    let args = vm.owned_objects(vm.sp - n_args, vm.sp);
+                    // pop the arguments and the builtin from the stack.
+                    vm.sp -= n_args + 1;
+                    vm.push_object(f(args))?;
+                    next
                 } else {
-                    panic!("calling non-function")
+                    let cf = match fun {
+                        Value::Heap(handle) => match vm.heap.get(handle) {
+                            Object::CompiledFunction(cf) => Rc::clone(cf),
+                            o => return Err(VMError::CallingNonFunction(o.get_type())),
+                        },
+                        v => {
+                            return Err(VMError::CallingNonFunction(vm.heap.get_type(v)))
+                        }
+                    };
+                    if n_args < cf.num_parameters {
+                        return Err(VMError::WrongNumberOfArgs {
+                            want: cf.num_parameters,
+                            got: n_args,
+                        });
+                    }
+                    // Like in the evaluator, the arguments without a parameter are
+                    // dropped.
+                    vm.sp -= n_args - cf.num_parameters;
+                    // The arguments are the first locals of the function.
+                    let base_pointer = vm.sp - cf.num_parameters;
+                    if base_pointer + cf.num_locals >= STACKSIZE {
+                        return Err(VMError::StackOverflow);
+                    }
+                    if vm.frames_index >= MAX_FRAMES {
+                        return Err(VMError::FrameOverflow);
+                    }
+                    if let Some(result) = call_native(vm, globals, &cf, base_pointer) {
+                        // pop the arguments and the function from the stack.
+                        vm.sp = base_pointer - 1;
+                        vm.push(result)?;
+                        next
+                    } else {
+                        vm.sp = base_pointer + cf.num_locals;
+                        if vm.stack.len() < vm.sp {
+                            vm.stack.resize(vm.sp, Value::Null);
+                        }
+                        // continue behind the call on return.
+                        *ip = next;
+                        return Ok(Exit::Call(Frame::new(
+                            Code::Function(cf),
+                            base_pointer,
+                            vm.heap.mark(),
+                        )));
+                    }
                 }
             }
-            OpCode::ReturnVal => {
-                // TODO: Maybe use pop_and_own, but then last_popped does not work
-                let return_value = vm.pop().expect(EMPTY_STACK).clone();
-                // leave function scope
-                vm.pop_frame();
-                // pop just executed compiled function from the stack.
-                vm.pop();
-
-                vm.push(Cow::from(return_value));
+            OpCode::Hash => {
+                let (n_elements, next) = operand_u16(ins, *ip, wide);
+                vm.check_stack(n_elements)?;
+                let hash = vm.build_hash(vm.sp - n_elements, vm.sp);
+                vm.sp -= n_elements;
+                vm.push_object(hash)?;
+                next
             }
-            OpCode::Return => {
-                vm.pop_frame();
-                vm.pop();
-                vm.push(COW_NULL);
+            OpCode::Index => {
+                let (left, index) = vm.pop_2()?;
+                let result = {
+                    let index = vm.heap.object(index).into_owned();
+                    vm.heap.object(left).index(index)
+                };
+                vm.push_object(result)?;
+                *ip + 1
             }
-            _ => panic!(format!("not impl {:?}", oc)),
-        }
-        vm.current_frame().ip += 1;
+            OpCode::CallMethod => {
+                let (name_index, _) = operand_u16(ins, *ip, wide);
+                let (n_args, next) = operand_u8(ins, next_operand(*ip, 2, wide), wide);
+                vm.check_stack(n_args + 1)?;
+                let receiver = vm.stack[vm.sp - n_args - 1];
+                let receiver_type = vm.heap.get_type(receiver);
+                let site = (ins.as_ptr() as usize, *ip);
+                let method = match vm.method_cache.get(&site) {
+                    Some(cache) if cache.receiver_type == receiver_type => {
+                        Ok(cache.method)
+                    }
+                    _ => {
+                        let method = match &vm.constants[name_index] {
+                            Object::String(method_name) => {
+                                lookup_method(&vm.heap.object(receiver), method_name)
+                            }
+                            o => return Err(VMError::InvalidMethodName(o.get_type())),
+                        };
+                        if let Ok(method) = method {
+                            let cache = MethodCache {
+                                receiver_type,
+                                method,
+                            };
+                            vm.method_cache.insert(site, cache);
+                        }
+                        method
+                    }
+                };
+                // the receiver is the first argument
+                let args = vm.owned_objects(vm.sp - n_args - 1, vm.sp);
+                vm.sp -= n_args + 1;
+                let result = match method {
+                    Ok(method) => method(args),
+                    Err(error) => error,
+                };
+                vm.push_object(result)?;
+                next
+            }
+            OpCode::ReturnVal => return Ok(Exit::Return(vm.pop()?)),
+            OpCode::Return => return Ok(Exit::Return(Value::Null)),
+            OpCode::CurrentFunction => {
+                // the function being executed lies just below its locals.
+                let function = vm.stack[base_pointer - 1];
+                vm.push(function)?;
+                *ip + 1
+            }
+            OpCode::GetBuiltin => {
+                let (index, next) = operand_u8(ins, *ip, wide);
+                vm.push(Value::Builtin(index))?;
+                next
+            }
+            OpCode::SetLocal => {
+                let (index, next) = operand_u8(ins, *ip, wide);
+                vm.stack[base_pointer + index] = vm.pop()?;
+                next
+            }
+            OpCode::GetLocal => {
+                let (index, next) = operand_u8(ins, *ip, wide);
+                let local = vm.stack[base_pointer + index];
+                vm.push(local)?;
+                next
+            }
+            OpCode::Zero => {
+                vm.push(Value::Int(0))?;
+                *ip + 1
+            }
+            OpCode::One => {
+                vm.push(Value::Int(1))?;
+                *ip + 1
+            }
+            OpCode::SmallInt => {
+                let (int, next) = operand_u8(ins, *ip, wide);
+                vm.push(Value::Int(int as i64))?;
+                next
+            }
+            OpCode::AddLocals => {
+                let (left, _) = operand_u8(ins, *ip, wide);
+                let (right, next) = operand_u8(ins, next_operand(*ip, 1, wide), wide);
+                let left = vm.stack[base_pointer + left];
+                let right = vm.stack[base_pointer + right];
+                let result = exec_binary(&mut vm.heap, left, right, OpCode::Add)?;
+                vm.push(result)?;
+                next
+            }
+            OpCode::Wide => {
+                wide = true;
+                *ip += 1;
+                continue;
+            }
+        };
+        wide = false;
+        *ip = next;
+    }
+    Ok(Exit::End)
+}
+
+/// Run the call of a hot function as native code. None if the interpreter runs it.
+#[cfg(feature = "jit")]
+fn call_native(
+    vm: &mut VM,
+    globals: &[Option<Value>],
+    cf: &Rc<CompiledFunction>,
+    base_pointer: usize,
+) -> Option<Value> {
+    let args = &vm.stack[base_pointer..vm.sp];
+    vm.jit.call(
+        cf,
+        args,
+        &vm.heap,
+        globals,
+        vm.frames_index + 1,
+        base_pointer,
+    )
+}
+
+#[cfg(not(feature = "jit"))]
+#[inline(always)]
+fn call_native(
+    vm: &mut VM,
+    globals: &[Option<Value>],
+    cf: &Rc<CompiledFunction>,
+    base_pointer: usize,
+) -> Option<Value> {
+    None
+}
+
+/// Offset such that the operand behind the first one, of `width` bytes, is read as if
+/// it was the first operand of an instruction at that offset.
+#[inline(always)]
+fn next_operand(ip: usize, width: usize, wide: bool) -> usize {
+    if wide {
+        ip + 2 * width
+    } else {
+        ip + width
     }
-    Ok(vm.last_popped().clone())
 }
@@ -0,0 +1,752 @@
+//! Compiles the AST to a WebAssembly module.
+//!
+//! The module has no imports. It exports `main`, which runs the program and returns the
+//! address of the value of its last statement, and `memory`, from which that value can
+//! be read with `runtime::read_value`.
+//!
+//! Every function of the program becomes a function of the module that takes its
+//! closure and its arguments, and is called through the table. The locals that inner
+//! functions capture are kept in boxes on the heap, and a closure holds the addresses of
+//! the boxes, such that it sees a later `let` of the variable, like in the evaluator.
+//! The evaluator only sees the variables of the calls that are running, a closure also
+//! keeps the ones of a function that has returned.
+//! Globals are globals of the module; they are late bound, like in the evaluator, so a
+//! function can call a function that is defined behind it.
+use crate::err::CompileError;
+use crate::wasm::runtime::{
+    mem, static_cells, write_cell, FunctionDef, Rt, CELL_SIZE, FALSE, NULL, RUNTIME,
+    TAG_CLOSURE, TAG_INT, TAG_STR, TRUE,
+};
+use monkey::eval::builtins::BUILTIN_LIST;
+use monkey::lexer::token::Span;
+use monkey::parser::ast::{Expression, Statement};
+use std::borrow::Cow;
+use std::collections::{BTreeSet, HashMap};
+use wasm_encoder::{
+    BlockType, CodeSection, ConstExpr, DataSection, ElementSection, Elements, ExportKind,
+    ExportSection, Function, FunctionSection, GlobalSection, GlobalType, Instruction,
+    MemorySection, MemoryType, Module, RefType, TableSection, TableType, TypeSection,
+    ValType,
+};
+
+const PAGE_SIZE: u32 = 1 << 16;
+
+/// Where the value of a variable is kept.
+#[derive(Debug, Clone, Copy)]
+enum Variable {
+    Local(u32),
+    Boxed(u32), // a local with the address of the box of a captured variable
+    Current,    // the function that is being compiled, bound by a let statement
+    Free(u32),
+    Global(u32),
+}
+
+struct FunctionScope {
+    num_params: u32, // the closure and the arguments
+    num_locals: u32,
+    names: HashMap<String, Variable>,
+    // Boxes of the captured variables, also of the ones whose let is not reached yet.
+    boxes: HashMap<String, u32>,
+    free: Vec<String>, // captured variables of enclosing functions
+    name: Option<String>,
+    body: Vec<Instruction<'static>>,
+}
+
+impl FunctionScope {
+    fn new(num_params: u32, name: Option<&str>) -> FunctionScope {
+        FunctionScope {
+            num_params,
+            num_locals: 0,
+            names: HashMap::new(),
+            boxes: HashMap::new(),
+            free: vec![],
+            name: name.map(str::to_string),
+            body: vec![],
+        }
+    }
+}
+
+pub struct WasmCompiler {
+    scopes: Vec<FunctionScope>, // the main program is the first scope
+    functions: Vec<FunctionDef>,
+    types: Vec<(Vec<ValType>, Vec<ValType>)>,
+    globals: HashMap<String, u32>,
+    data: Vec<u8>,
+    // Int and string literals are static cells, added to the data only once.
+    ints: HashMap<i64, i32>,
+    strings: HashMap<String, i32>,
+    span: Span, // location of the statement that is being compiled
+}
+
+/// Compile all statements to the bytes of a module. Like the other compilers,
+/// compilation continues after a statement that fails to compile, such that all errors
+/// are reported.
+pub fn compile_program(program: &[Statement]) -> Result<Vec<u8>, Vec<CompileError>> {
+    let mut com = WasmCompiler {
+        scopes: vec![FunctionScope::new(0, None)],
+        functions: vec![],
+        types: vec![],
+        globals: HashMap::new(),
+        data: static_cells(),
+        ints: HashMap::new(),
+        strings: HashMap::new(),
+        span: Span::default(),
+    };
+    for stmt in program {
+        if let Statement::Let(identifier, ..) = stmt {
+            com.global(identifier);
+        }
+    }
+    let mut errors = vec![];
+    let mut has_value = false;
+    for stmt in program {
+        if has_value {
+            com.emit(Instruction::Drop);
+        }
+        match com.compile_stmt(stmt) {
+            Ok(value) => has_value = value,
+            Err(e) => errors.push(e),
+        }
+    }
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    if !has_value {
+        com.emit(Instruction::I32Const(NULL));
+    }
+    com.emit(Instruction::End);
+    Ok(com.finish())
+}
+
+impl WasmCompiler {
+    fn scope(&mut self) -> &mut FunctionScope {
+        self.scopes.last_mut().unwrap()
+    }
+
+    fn emit(&mut self, ins: Instruction<'static>) {
+        self.scope().body.push(ins);
+    }
+
+    fn call(&mut self, rt: Rt) {
+        self.emit(Instruction::Call(rt.index()));
+    }
+
+    fn new_local(&mut self) -> u32 {
+        let scope = self.scope();
+        scope.num_locals += 1;
+        scope.num_params + scope.num_locals - 1
+    }
+
+    fn type_index(&mut self, params: &[ValType], results: &[ValType]) -> u32 {
+        let ty = (params.to_vec(), results.to_vec());
+        match self.types.iter().position(|t| *t == ty) {
+            Some(i) => i as u32,
+            None => {
+                self.types.push(ty);
+                self.types.len() as u32 - 1
+            }
+        }
+    }
+
+    /// The type of a function of the program with `arity` parameters.
+    fn function_type(&mut self, arity: usize) -> u32 {
+        self.type_index(&vec![ValType::I32; arity + 1], &[ValType::I32])
+    }
+
+    fn global(&mut self, name: &str) -> u32 {
+        // Global 0 is the heap pointer.
+        let next = self.globals.len() as u32 + 1;
+        *self.globals.entry(name.to_string()).or_insert(next)
+    }
+
+    /// Add a cell to the data and return its address.
+    fn add_cell(&mut self, tag: i32, second: i32, payload: i64) -> i32 {
+        let address = self.data.len() as u32;
+        self.data.resize((address + CELL_SIZE) as usize, 0);
+        write_cell(&mut self.data, address, tag, second, payload);
+        address as i32
+    }
+
+    fn int(&mut self, v: i64) -> i32 {
+        if let Some(address) = self.ints.get(&v) {
+            return *address;
+        }
+        let address = self.add_cell(TAG_INT, 0, v);
+        self.ints.insert(v, address);
+        address
+    }
+
+    fn string(&mut self, s: &str) -> i32 {
+        if let Some(address) = self.strings.get(s) {
+            return *address;
+        }
+        // The bytes are padded, such that the cells stay aligned.
+        let bytes = self.data.len();
+        self.data.extend(s.as_bytes());
+        let padded =
+            (self.data.len() + CELL_SIZE as usize - 1) & !(CELL_SIZE as usize - 1);
+        self.data.resize(padded, 0);
+        let address = self.add_cell(TAG_STR, s.len() as i32, bytes as i64);
+        self.strings.insert(s.to_string(), address);
+        address
+    }
+
+    /// The variable that the name refers to in the function at `depth`. A variable of
+    /// an enclosing function becomes a free variable of the functions in between. An
+    /// `inner` function also sees the variables whose let is not reached yet, as it
+    /// usually runs later.
+    fn resolve(&mut self, depth: usize, name: &str, inner: bool) -> Option<Variable> {
+        if depth == 0 {
+            return self.globals.get(name).map(|i| Variable::Global(*i));
+        }
+        let scope = &self.scopes[depth];
+        if let Some(var) = scope.names.get(name) {
+            return Some(*var);
+        }
+        if let Some(boxed) = scope.boxes.get(name).filter(|_| inner) {
+            return Some(Variable::Boxed(*boxed));
+        }
+        if scope.name.as_deref() == Some(name) {
+            return Some(Variable::Current);
+        }
+        if let Some(k) = scope.free.iter().position(|n| n == name) {
+            return Some(Variable::Free(k as u32));
+        }
+        match self.resolve(depth - 1, name, true)? {
+            Variable::Global(i) => Some(Variable::Global(i)),
+            _ => {
+                let scope = &mut self.scopes[depth];
+                scope.free.push(name.to_string());
+                Some(Variable::Free(scope.free.len() as u32 - 1))
+            }
+        }
+    }
+
+    fn load(&mut self, var: Variable) {
+        use Instruction::*;
+        match var {
+            Variable::Local(i) => self.emit(LocalGet(i)),
+            Variable::Current => self.emit(LocalGet(0)),
+            Variable::Boxed(_) | Variable::Free(_) => {
+                self.load_box(var);
+                // A box is empty until the let of its variable.
+                self.emit(I32Load(mem(0)));
+                self.call(Rt::Defined);
+            }
+            Variable::Global(i) => {
+                self.emit(GlobalGet(i));
+                self.call(Rt::Defined);
+            }
+        }
+    }
+
+    /// Leave the address of the box of a captured variable. The function itself is
+    /// never set again, it gets a new box.
+    fn load_box(&mut self, var: Variable) {
+        use Instruction::*;
+        match var {
+            Variable::Boxed(i) => self.emit(LocalGet(i)),
+            Variable::Free(k) => {
+                self.emit(LocalGet(0));
+                self.emit(I32Load(mem(8)));
+                self.emit(I32Load(mem(4 * k as u64)));
+            }
+            var => {
+                let boxed = self.new_box();
+                self.emit(LocalGet(boxed));
+                self.load(var);
+                self.emit(I32Store(mem(0)));
+                self.emit(LocalGet(boxed));
+            }
+        }
+    }
+
+    /// Allocate an empty box and return the local that holds its address. New memory
+    /// is zero, which is the address of no value.
+    fn new_box(&mut self) -> u32 {
+        let boxed = self.new_local();
+        self.emit(Instruction::I32Const(4));
+        self.call(Rt::Alloc);
+        self.emit(Instruction::LocalSet(boxed));
+        boxed
+    }
+
+    /// Compile the statements, such that they leave the value of the last one, or null
+    /// if it is not an expression.
+    fn compile_block(&mut self, stmts: &[Statement]) -> Result<(), CompileError> {
+        let mut has_value = false;
+        for stmt in stmts {
+            if has_value {
+                self.emit(Instruction::Drop);
+            }
+            has_value = self.compile_stmt(stmt)?;
+        }
+        if !has_value {
+            self.emit(Instruction::I32Const(NULL));
+        }
+        Ok(())
+    }
+
+    /// Compile the statement. Returns whether it leaves a value.
+    fn compile_stmt(&mut self, stmt: &Statement) -> Result<bool, CompileError> {
+        // Expressions are reported at the location of the statement they are part of.
+        let outer_span = self.span;
+        if let Some(span) = stmt.span() {
+            self.span = span;
+        }
+        let compiled = self.compile_stmt_inner(stmt);
+        self.span = outer_span;
+        compiled
+    }
+
+    fn compile_stmt_inner(&mut self, stmt: &Statement) -> Result<bool, CompileError> {
+        match stmt {
+            Statement::Expr(expr, _) => self.compile_expr(expr)?,
+            Statement::Block(stmts) => self.compile_block(stmts)?,
+            Statement::Return(expr, _) => {
+                self.compile_expr(expr)?;
+                self.emit(Instruction::Return);
+            }
+            Statement::Let(identifier, expr, _) => {
+                let boxed = match self.scopes.len() {
+                    1 => None,
+                    _ => self.scope().boxes.get(&identifier[..]).copied(),
+                };
+                if let Some(boxed) = boxed {
+                    self.emit(Instruction::LocalGet(boxed));
+                }
+                match expr {
+                    Expression::FunctionLiteral { parameters, body } => {
+                        self.compile_function(parameters, body, Some(identifier))?
+                    }
+                    _ => self.compile_expr(expr)?,
+                }
+                if self.scopes.len() == 1 {
+                    let global = self.global(identifier);
+                    self.emit(Instruction::GlobalSet(global));
+                } else if let Some(boxed) = boxed {
+                    self.emit(Instruction::I32Store(mem(0)));
+                    let var = Variable::Boxed(boxed);
+                    self.scope().names.insert(identifier.to_string(), var);
+                } else {
+                    let local = match self.scope().names.get(&identifier[..]) {
+                        Some(Variable::Local(local)) => *local,
+                        _ => {
+                            let local = self.new_local();
+                            let var = Variable::Local(local);
+                            self.scope().names.insert(identifier.to_string(), var);
+                            local
+                        }
+                    };
+                    self.emit(Instruction::LocalSet(local));
+                }
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Compile the expression, such that it leaves the address of its value.
+    fn compile_expr(&mut self, expr: &Expression) -> Result<(), CompileError> {
+        use Instruction::*;
+        match expr {
+            Expression::IntegerLiteral(v) => {
+                let address = self.int(*v);
+                self.emit(I32Const(address));
+            }
+            Expression::StringLiteral(s) => {
+                let address = self.string(s);
+                self.emit(I32Const(address));
+            }
+            Expression::Bool(v) => self.emit(I32Const(if *v { TRUE } else { FALSE })),
+            Expression::Prefix { operator, expr } => {
+                self.compile_expr(expr)?;
+                match &operator[..] {
+                    "-" => self.call(Rt::Neg),
+                    "!" => self.call(Rt::Not),
+                    op => {
+                        return Err(CompileError::UnknownOperator(
+                            op.to_string(),
+                            self.span,
+                        ))
+                    }
+                }
+            }
+            Expression::Infix {
+                left,
+                operator,
+                right,
+            } => {
+                self.compile_expr(left)?;
+                self.compile_expr(right)?;
+                match &operator[..] {
+                    "+" => self.call(Rt::Add),
+                    "-" => self.call(Rt::Sub),
+                    "*" => self.call(Rt::Mul),
+                    "/" => self.call(Rt::Div),
+                    "<" => self.call(Rt::Lt),
+                    ">" => self.call(Rt::Gt),
+                    "==" => {
+                        self.call(Rt::Eq);
+                        self.call(Rt::NewBool);
+                    }
+                    "!=" => {
+                        self.call(Rt::Eq);
+                        self.emit(I32Eqz);
+                        self.call(Rt::NewBool);
+                    }
+                    op => {
+                        return Err(CompileError::UnknownOperator(
+                            op.to_string(),
+                            self.span,
+                        ))
+                    }
+                }
+            }
+            Expression::IfExpression {
+                condition,
+                consequence,
+                alternative,
+            } => {
+                self.compile_expr(condition)?;
+                self.call(Rt::Truthy);
+                self.emit(If(BlockType::Result(ValType::I32)));
+                self.compile_branch(consequence)?;
+                self.emit(Else);
+                match alternative {
+                    Some(alternative) => self.compile_branch(alternative)?,
+                    None => self.emit(I32Const(NULL)),
+                }
+                self.emit(End);
+            }
+            Expression::Identifier(ident) => {
+                let depth = self.scopes.len() - 1;
+                match self.resolve(depth, ident, false) {
+                    Some(var) => self.load(var),
+                    None if BUILTIN_LIST.iter().any(|b| b.identifier == ident[..]) => {
+                        return Err(CompileError::Unsupported(
+                            format!("builtin function: {}", ident),
+                            self.span,
+                        ))
+                    }
+                    None => {
+                        return Err(CompileError::UndefinedVariable(
+                            ident.to_string(),
+                            self.span,
+                        ))
+                    }
+                }
+            }
+            Expression::FunctionLiteral { parameters, body } => {
+                self.compile_function(parameters, body, None)?;
+            }
+            Expression::CallExpr { function, args } => {
+                // The function is evaluated before its arguments, and is passed as the
+                // first of them.
+                self.compile_expr(function)?;
+                let closure = self.new_local();
+                self.emit(LocalTee(closure));
+                for arg in args.iter() {
+                    self.compile_expr(arg)?;
+                }
+                self.emit(LocalGet(closure));
+                self.call(Rt::ClosureIndex);
+                let type_index = self.function_type(args.len());
+                self.emit(CallIndirect {
+                    type_index,
+                    table_index: 0,
+                });
+            }
+            _ => {
+                return Err(CompileError::Unsupported(
+                    format!("expression: {}", expr),
+                    self.span,
+                ))
+            }
+        }
+        Ok(())
+    }
+
+    /// Compile a branch of an if expression, such that it leaves the value of its last
+    /// expression, or null if it doesn't end with an expression.
+    fn compile_branch(&mut self, block: &Statement) -> Result<(), CompileError> {
+        match block {
+            Statement::Block(stmts) => self.compile_block(stmts),
+            stmt => self.compile_block(std::slice::from_ref(stmt)),
+        }
+    }
+
+    /// Compile a function literal to a function of the module, and create a closure of
+    /// it. If the function is bound by a let statement, `name` is available in its body.
+    fn compile_function(
+        &mut self,
+        parameters: &[Expression],
+        body: &Statement,
+        name: Option<&str>,
+    ) -> Result<(), CompileError> {
+        use Instruction::*;
+        let mut scope = FunctionScope::new(parameters.len() as u32 + 1, name);
+        for (i, param) in parameters.iter().enumerate() {
+            match param {
+                // The last of two parameters with the same name wins.
+                Expression::Identifier(ident) => {
+                    let var = Variable::Local(i as u32 + 1);
+                    scope.names.insert(ident.to_string(), var);
+                }
+                param => {
+                    return Err(CompileError::Unsupported(
+                        format!("parameter: {}", param),
+                        self.span,
+                    ))
+                }
+            }
+        }
+        self.scopes.push(scope);
+        // Captured variables get their boxes when the function starts, and captured
+        // parameters are moved into theirs.
+        for name in captured(parameters, body) {
+            let boxed = self.new_box();
+            if let Some(Variable::Local(param)) = self.scope().names.get(&name).copied() {
+                self.emit(LocalGet(boxed));
+                self.emit(LocalGet(param));
+                self.emit(I32Store(mem(0)));
+                self.scope()
+                    .names
+                    .insert(name.clone(), Variable::Boxed(boxed));
+            }
+            self.scope().boxes.insert(name, boxed);
+        }
+        let result = self.compile_branch(body);
+        self.emit(End);
+        // Always leave the scope, also on errors.
+        let scope = self.scopes.pop().unwrap();
+        result?;
+
+        let index = self.functions.len() as i32;
+        self.functions.push(FunctionDef {
+            params: vec![ValType::I32; scope.num_params as usize],
+            results: vec![ValType::I32],
+            locals: vec![ValType::I32; scope.num_locals as usize],
+            body: scope.body,
+        });
+
+        // Copy the addresses of the boxes. The enclosing function finds them, because
+        // they were resolved through it.
+        let captured = self.new_local();
+        if scope.free.is_empty() {
+            self.emit(I32Const(0));
+        } else {
+            self.emit(I32Const(4 * scope.free.len() as i32));
+            self.call(Rt::Alloc);
+        }
+        self.emit(LocalSet(captured));
+        let depth = self.scopes.len() - 1;
+        for (k, name) in scope.free.iter().enumerate() {
+            self.emit(LocalGet(captured));
+            let var = self
+                .resolve(depth, name, true)
+                .expect("free variables are defined");
+            self.load_box(var);
+            self.emit(I32Store(mem(4 * k as u64)));
+        }
+        let closure = self.new_local();
+        self.emit(I32Const(CELL_SIZE as i32));
+        self.call(Rt::Alloc);
+        self.emit(LocalTee(closure));
+        self.emit(I32Const(TAG_CLOSURE));
+        self.emit(I32Store(mem(0)));
+        self.emit(LocalGet(closure));
+        self.emit(I32Const(index));
+        self.emit(I32Store(mem(4)));
+        self.emit(LocalGet(closure));
+        self.emit(LocalGet(captured));
+        self.emit(I64ExtendI32U);
+        self.emit(I64Store(mem(8)));
+        self.emit(LocalGet(closure));
+        Ok(())
+    }
+
+    /// Encode the module. The runtime comes first, then `main` and the functions of the
+    /// program, which are also the elements of the table.
+    fn finish(mut self) -> Vec<u8> {
+        let main = self.scopes.pop().unwrap();
+        let mut definitions: Vec<FunctionDef> =
+            RUNTIME.iter().map(|rt| rt.definition()).collect();
+        definitions.push(FunctionDef {
+            params: vec![],
+            results: vec![ValType::I32],
+            locals: vec![ValType::I32; main.num_locals as usize],
+            body: main.body,
+        });
+        let first_function = definitions.len() as u32;
+        let num_functions = self.functions.len() as u32;
+        definitions.append(&mut self.functions);
+
+        let mut types = TypeSection::new();
+        let mut functions = FunctionSection::new();
+        let mut code = CodeSection::new();
+        for def in definitions.iter() {
+            functions.function(self.type_index(&def.params, &def.results));
+            let mut function = Function::new(def.locals.iter().map(|ty| (1, *ty)));
+            for ins in def.body.iter() {
+                function.instruction(ins);
+            }
+            code.function(&function);
+        }
+        for (params, results) in self.types.iter() {
+            types
+                .ty()
+                .function(params.iter().copied(), results.iter().copied());
+        }
+
+        let mut tables = TableSection::new();
+        tables.table(TableType {
+            element_type: RefType::FUNCREF,
+            table64: false,
+            minimum: num_functions as u64,
+            maximum: Some(num_functions as u64),
+            shared: false,
+        });
+        let mut elements = ElementSection::new();
+        if num_functions > 0 {
+            let indices: Vec<u32> =
+                (first_function..first_function + num_functions).collect();
+            elements.active(
+                None,
+                &ConstExpr::i32_const(0),
+                Elements::Functions(Cow::Owned(indices)),
+            );
+        }
+
+        let heap_start = self.data.len() as u32;
+        let mut memories = MemorySection::new();
+        memories.memory(MemoryType {
+            minimum: (heap_start / PAGE_SIZE + 1) as u64,
+            maximum: None,
+            memory64: false,
+            shared: false,
+            page_size_log2: None,
+        });
+        let mut globals = GlobalSection::new();
+        let mutable = GlobalType {
+            val_type: ValType::I32,
+            mutable: true,
+            shared: false,
+        };
+        // The heap pointer, and then the globals of the program.
+        globals.global(mutable, &ConstExpr::i32_const(heap_start as i32));
+        for _ in 0..self.globals.len() {
+            // Address 0 marks a global that is not set yet.
+            globals.global(mutable, &ConstExpr::i32_const(0));
+        }
+
+        let mut exports = ExportSection::new();
+        exports.export("main", ExportKind::Func, RUNTIME.len() as u32);
+        exports.export("memory", ExportKind::Memory, 0);
+        let mut data = DataSection::new();
+        data.active(0, &ConstExpr::i32_const(0), self.data.iter().copied());
+
+        let mut module = Module::new();
+        module
+            .section(&types)
+            .section(&functions)
+            .section(&tables)
+            .section(&memories)
+            .section(&globals)
+            .section(&exports)
+            .section(&elements)
+            .section(&code)
+            .section(&data);
+        module.finish()
+    }
+}
+
+/// The parameters and `let` names of a function that functions in its body use. Names
+/// that these functions define themselves are included too, which only costs a box.
+fn captured(parameters: &[Expression], body: &Statement) -> BTreeSet<String> {
+    let mut scan = Scan::default();
+    for param in parameters {
+        if let Expression::Identifier(ident) = param {
+            scan.defined.insert(ident.to_string());
+        }
+    }
+    scan.stmt(body, false);
+    scan.defined.intersection(&scan.used).cloned().collect()
+}
+
+#[derive(Default)]
+struct Scan {
+    defined: BTreeSet<String>, // by the function
+    used: BTreeSet<String>,    // by the functions in it
+}
+
+impl Scan {
+    fn stmt(&mut self, stmt: &Statement, nested: bool) {
+        match stmt {
+            Statement::Let(identifier, expr, _) => {
+                if !nested {
+                    self.defined.insert(identifier.to_string());
+                }
+                self.expr(expr, nested);
+            }
+            Statement::Return(expr, _) | Statement::Expr(expr, _) => {
+                self.expr(expr, nested)
+            }
+            Statement::Block(stmts) => {
+                for stmt in stmts.iter() {
+                    self.stmt(stmt, nested);
+                }
+            }
+        }
+    }
+
+    fn expr(&mut self, expr: &Expression, nested: bool) {
+        match expr {
+            Expression::Identifier(ident) if nested => {
+                self.used.insert(ident.to_string());
+            }
+            Expression::FunctionLiteral { body, .. } => self.stmt(body, true),
+            Expression::Prefix { expr, .. } => self.expr(expr, nested),
+            Expression::Infix { left, right, .. }
+            | Expression::IndexExpr { left, index: right } => {
+                self.expr(left, nested);
+                self.expr(right, nested);
+            }
+            Expression::IfExpression {
+                condition,
+                consequence,
+                alternative,
+            } => {
+                self.expr(condition, nested);
+                self.stmt(consequence, nested);
+                if let Some(alternative) = alternative {
+                    self.stmt(alternative, nested);
+                }
+            }
+            Expression::CallExpr { function, args } => {
+                self.expr(function, nested);
+                self.exprs(args, nested);
+            }
+            Expression::ArrayLiteral(exprs) => self.exprs(exprs, nested),
+            Expression::HashLiteral { keys, values } => {
+                self.exprs(keys, nested);
+                self.exprs(values, nested);
+            }
+            Expression::Method { left, args, .. } => {
+                self.expr(left, nested);
+                self.exprs(args, nested);
+            }
+            Expression::Identifier(_)
+            | Expression::IntegerLiteral(_)
+            | Expression::Bool(_)
+            | Expression::StringLiteral(_)
+            | Expression::Some => {}
+        }
+    }
+
+    fn exprs(&mut self, exprs: &[Expression], nested: bool) {
+        for expr in exprs {
+            self.expr(expr, nested);
+        }
+    }
+}
@@ -0,0 +1,498 @@
+//! The values of a compiled module and the functions that operate on them.
+//!
+//! Every value is the address of a cell of 16 bytes in linear memory: a tag, a second
+//! word and a payload of 8 bytes. Null and the bools are static cells, as are the int
+//! and string literals of the program. Cells that are computed while running are
+//! allocated behind the static data and never freed.
+//!
+//! | tag         | second word         | payload                       |
+//! |-------------|---------------------|-------------------------------|
+//! | TAG_NULL    |                     |                               |
+//! | TAG_INT     |                     | the int                       |
+//! | TAG_BOOL    |                     | 0 or 1                        |
+//! | TAG_STR     | number of bytes     | address of the bytes          |
+//! | TAG_CLOSURE | index in the table  | address of the captured values|
+//!
+//! Operations on values of the wrong type trap, like the errors of the evaluator.
+use monkey::eval::object::Object;
+use std::convert::TryInto;
+use wasm_encoder::{BlockType, Instruction, MemArg, ValType};
+
+pub const TAG_NULL: i32 = 0;
+pub const TAG_INT: i32 = 1;
+pub const TAG_BOOL: i32 = 2;
+pub const TAG_STR: i32 = 3;
+pub const TAG_CLOSURE: i32 = 4;
+
+pub const CELL_SIZE: u32 = 16;
+/// Address 0 is not a value. Globals hold it until they are set.
+pub const NULL: i32 = 16;
+pub const FALSE: i32 = 32;
+pub const TRUE: i32 = 48;
+/// Start of the literals of the program.
+pub const DATA_START: u32 = 64;
+
+/// The global that holds the address of the next free byte.
+pub const HEAP_POINTER: u32 = 0;
+
+/// The functions of the runtime, in the order of their indices in the module.
+#[derive(Debug, Clone, Copy)]
+pub enum Rt {
+    Alloc,        // (size) -> address
+    NewInt,       // (i64) -> int
+    IntValue,     // (int) -> i64
+    NewBool,      // (i32) -> bool
+    Truthy,       // (value) -> i32
+    Add,          // (value, value) -> value, ints or strings
+    Sub,          // (int, int) -> int
+    Mul,          // (int, int) -> int
+    Div,          // (int, int) -> int
+    Lt,           // (int, int) -> bool
+    Gt,           // (int, int) -> bool
+    Eq,           // (value, value) -> i32, ints or bools
+    Not,          // (bool) -> bool
+    Neg,          // (int) -> int
+    Concat,       // (str, str) -> str
+    Copy,         // (destination, source, length)
+    ClosureIndex, // (closure) -> index in the table
+    Defined,      // (value) -> value, traps on a global that is not set
+}
+
+pub const RUNTIME: [Rt; 18] = [
+    Rt::Alloc,
+    Rt::NewInt,
+    Rt::IntValue,
+    Rt::NewBool,
+    Rt::Truthy,
+    Rt::Add,
+    Rt::Sub,
+    Rt::Mul,
+    Rt::Div,
+    Rt::Lt,
+    Rt::Gt,
+    Rt::Eq,
+    Rt::Not,
+    Rt::Neg,
+    Rt::Concat,
+    Rt::Copy,
+    Rt::ClosureIndex,
+    Rt::Defined,
+];
+
+/// A function of the module, before it is encoded.
+pub struct FunctionDef {
+    pub params: Vec<ValType>,
+    pub results: Vec<ValType>,
+    pub locals: Vec<ValType>,
+    pub body: Vec<Instruction<'static>>,
+}
+
+pub fn mem(offset: u64) -> MemArg {
+    MemArg {
+        offset,
+        align: 0,
+        memory_index: 0,
+    }
+}
+
+impl Rt {
+    pub fn index(self) -> u32 {
+        self as u32
+    }
+
+    pub fn definition(self) -> FunctionDef {
+        use Instruction::*;
+        use ValType::{I32, I64};
+
+        let (params, results, locals, body) = match self {
+            Rt::Alloc => (
+                vec![I32],
+                vec![I32],
+                vec![I32, I32], // address, end
+                vec![
+                    GlobalGet(HEAP_POINTER),
+                    LocalTee(1),
+                    LocalGet(0),
+                    I32Const(CELL_SIZE as i32 - 1),
+                    I32Add,
+                    I32Const(-(CELL_SIZE as i32)),
+                    I32And,
+                    I32Add,
+                    LocalTee(2),
+                    MemorySize(0),
+                    I32Const(16),
+                    I32Shl,
+                    I32GtU,
+                    If(BlockType::Empty),
+                    // grow by the missing pages and one more
+                    LocalGet(2),
+                    MemorySize(0),
+                    I32Const(16),
+                    I32Shl,
+                    I32Sub,
+                    I32Const(16),
+                    I32ShrU,
+                    I32Const(1),
+                    I32Add,
+                    MemoryGrow(0),
+                    I32Const(-1),
+                    I32Eq,
+                    If(BlockType::Empty),
+                    Unreachable,
+                    End,
+                    End,
+                    LocalGet(2),
+                    GlobalSet(HEAP_POINTER),
+                    LocalGet(1),
+                    End,
+                ],
+            ),
+            Rt::NewInt => (
+                vec![I64],
+                vec![I32],
+                vec![I32],
+                vec![
+                    I32Const(CELL_SIZE as i32),
+                    Call(Rt::Alloc.index()),
+                    LocalTee(1),
+                    I32Const(TAG_INT),
+                    I32Store(mem(0)),
+                    LocalGet(1),
+                    LocalGet(0),
+                    I64Store(mem(8)),
+                    LocalGet(1),
+                    End,
+                ],
+            ),
+            Rt::IntValue => (
+                vec![I32],
+                vec![I64],
+                vec![],
+                vec![
+                    LocalGet(0),
+                    I32Load(mem(0)),
+                    I32Const(TAG_INT),
+                    I32Ne,
+                    If(BlockType::Empty),
+                    Unreachable,
+                    End,
+                    LocalGet(0),
+                    I64Load(mem(8)),
+                    End,
+                ],
+            ),
+            Rt::NewBool => (
+                vec![I32],
+                vec![I32],
+                vec![],
+                vec![I32Const(TRUE), I32Const(FALSE), LocalGet(0), Select, End],
+            ),
+            Rt::Truthy => (
+                vec![I32],
+                vec![I32],
+                vec![I32], // tag
+                vec![
+                    LocalGet(0),
+                    I32Load(mem(0)),
+                    LocalTee(1),
+                    I32Const(TAG_NULL),
+                    I32Eq,
+                    If(BlockType::Empty),
+                    I32Const(0),
+                    Return,
+                    End,
+                    LocalGet(1),
+                    I32Const(TAG_INT),
+                    I32Eq,
+                    LocalGet(1),
+                    I32Const(TAG_BOOL),
+                    I32Eq,
+                    I32Or,
+                    If(BlockType::Empty),
+                    LocalGet(0),
+                    I64Load(mem(8)),
+                    I64Const(0),
+                    I64Ne,
+                    Return,
+                    End,
+                    I32Const(1),
+                    End,
+                ],
+            ),
+            Rt::Add => (
+                vec![I32, I32],
+                vec![I32],
+                vec![],
+                vec![
+                    LocalGet(0),
+                    I32Load(mem(0)),
+                    I32Const(TAG_STR),
+                    I32Eq,
+                    LocalGet(1),
+                    I32Load(mem(0)),
+                    I32Const(TAG_STR),
+                    I32Eq,
+                    I32And,
+                    If(BlockType::Result(I32)),
+                    LocalGet(0),
+                    LocalGet(1),
+                    Call(Rt::Concat.index()),
+                    Else,
+                    LocalGet(0),
+                    Call(Rt::IntValue.index()),
+                    LocalGet(1),
+                    Call(Rt::IntValue.index()),
+                    I64Add,
+                    Call(Rt::NewInt.index()),
+                    End,
+                    End,
+                ],
+            ),
+            Rt::Sub | Rt::Mul | Rt::Div | Rt::Lt | Rt::Gt => {
+                let (op, result) = match self {
+                    Rt::Sub => (I64Sub, Rt::NewInt),
+                    Rt::Mul => (I64Mul, Rt::NewInt),
+                    // traps on a division by zero
+                    Rt::Div => (I64DivS, Rt::NewInt),
+                    Rt::Lt => (I64LtS, Rt::NewBool),
+                    _ => (I64GtS, Rt::NewBool),
+                };
+                (
+                    vec![I32, I32],
+                    vec![I32],
+                    vec![],
+                    vec![
+                        LocalGet(0),
+                        Call(Rt::IntValue.index()),
+                        LocalGet(1),
+                        Call(Rt::IntValue.index()),
+                        op,
+                        Call(result.index()),
+                        End,
+                    ],
+                )
+            }
+            Rt::Eq => (
+                vec![I32, I32],
+                vec![I32],
+                vec![I32], // tag
+                vec![
+                    LocalGet(0),
+                    I32Load(mem(0)),
+                    LocalTee(2),
+                    LocalGet(1),
+                    I32Load(mem(0)),
+                    I32Ne,
+                    If(BlockType::Empty),
+                    Unreachable,
+                    End,
+                    LocalGet(2),
+                    I32Const(TAG_INT),
+                    I32Ne,
+                    LocalGet(2),
+                    I32Const(TAG_BOOL),
+                    I32Ne,
+                    I32And,
+                    If(BlockType::Empty),
+                    Unreachable,
+                    End,
+                    LocalGet(0),
+                    I64Load(mem(8)),
+                    LocalGet(1),
+                    I64Load(mem(8)),
+                    I64Eq,
+                    End,
+                ],
+            ),
+            Rt::Not => (
+                vec![I32],
+                vec![I32],
+                vec![],
+                vec![
+                    LocalGet(0),
+                    I32Load(mem(0)),
+                    I32Const(TAG_BOOL),
+                    I32Ne,
+                    If(BlockType::Empty),
+                    Unreachable,
+                    End,
+                    LocalGet(0),
+                    I64Load(mem(8)),
+                    I64Eqz,
+                    Call(Rt::NewBool.index()),
+                    End,
+                ],
+            ),
+            Rt::Neg => (
+                vec![I32],
+                vec![I32],
+                vec![],
+                vec![
+                    I64Const(0),
+                    LocalGet(0),
+                    Call(Rt::IntValue.index()),
+                    I64Sub,
+                    Call(Rt::NewInt.index()),
+                    End,
+                ],
+            ),
+            Rt::Concat => (
+                vec![I32, I32],
+                vec![I32],
+                vec![I32, I32, I32, I32], // left length, right length, bytes, cell
+                vec![
+                    LocalGet(0),
+                    I32Load(mem(4)),
+                    LocalSet(2),
+                    LocalGet(1),
+                    I32Load(mem(4)),
+                    LocalSet(3),
+                    LocalGet(2),
+                    LocalGet(3),
+                    I32Add,
+                    Call(Rt::Alloc.index()),
+                    LocalSet(4),
+                    LocalGet(4),
+                    LocalGet(0),
+                    I32Load(mem(8)),
+                    LocalGet(2),
+                    Call(Rt::Copy.index()),
+                    LocalGet(4),
+                    LocalGet(2),
+                    I32Add,
+                    LocalGet(1),
+                    I32Load(mem(8)),
+                    LocalGet(3),
+                    Call(Rt::Copy.index()),
+                    I32Const(CELL_SIZE as i32),
+                    Call(Rt::Alloc.index()),
+                    LocalTee(5),
+                    I32Const(TAG_STR),
+                    I32Store(mem(0)),
+                    LocalGet(5),
+                    LocalGet(2),
+                    LocalGet(3),
+                    I32Add,
+                    I32Store(mem(4)),
+                    LocalGet(5),
+                    LocalGet(4),
+                    I64ExtendI32U,
+                    I64Store(mem(8)),
+                    LocalGet(5),
+                    End,
+                ],
+            ),
+            Rt::Copy => (
+                vec![I32, I32, I32],
+                vec![],
+                vec![],
+                vec![
+                    Block(BlockType::Empty),
+                    Loop(BlockType::Empty),
+                    LocalGet(2),
+                    I32Eqz,
+                    BrIf(1),
+                    LocalGet(0),
+                    LocalGet(1),
+                    I32Load8U(mem(0)),
+                    I32Store8(mem(0)),
+                    LocalGet(0),
+                    I32Const(1),
+                    I32Add,
+                    LocalSet(0),
+                    LocalGet(1),
+                    I32Const(1),
+                    I32Add,
+                    LocalSet(1),
+                    LocalGet(2),
+                    I32Const(1),
+                    I32Sub,
+                    LocalSet(2),
+                    Br(0),
+                    End,
+                    End,
+                    End,
+                ],
+            ),
+            Rt::ClosureIndex => (
+                vec![I32],
+                vec![I32],
+                vec![],
+                vec![
+                    LocalGet(0),
+                    I32Load(mem(0)),
+                    I32Const(TAG_CLOSURE),
+                    I32Ne,
+                    If(BlockType::Empty),
+                    Unreachable,
+                    End,
+                    LocalGet(0),
+                    I32Load(mem(4)),
+                    End,
+                ],
+            ),
+            Rt::Defined => (
+                vec![I32],
+                vec![I32],
+                vec![],
+                vec![
+                    LocalGet(0),
+                    I32Eqz,
+                    If(BlockType::Empty),
+                    Unreachable,
+                    End,
+                    LocalGet(0),
+                    End,
+                ],
+            ),
+        };
+        FunctionDef {
+            params,
+            results,
+            locals,
+            body,
+        }
+    }
+}
+
+/// The static cells of null and the bools, from address 0 up to `DATA_START`.
+pub fn static_cells() -> Vec<u8> {
+    let mut data = vec![0; DATA_START as usize];
+    for (address, tag, payload) in [
+        (NULL, TAG_NULL, 0),
+        (FALSE, TAG_BOOL, 0),
+        (TRUE, TAG_BOOL, 1),
+    ]
+    .iter()
+    {
+        write_cell(&mut data, *address as u32, *tag, 0, *payload);
+    }
+    data
+}
+
+pub fn write_cell(data: &mut [u8], address: u32, tag: i32, second: i32, payload: i64) {
+    let at = address as usize;
+    data[at..at + 4].copy_from_slice(&tag.to_le_bytes());
+    data[at + 4..at + 8].copy_from_slice(&second.to_le_bytes());
+    data[at + 8..at + 16].copy_from_slice(&payload.to_le_bytes());
+}
+
+/// Object of the value at the address in the memory of a module. None for closures,
+/// which are not objects outside of the module.
+pub fn read_value(memory: &[u8], address: u32) -> Option<Object> {
+    let at = address as usize;
+    let word = |at: usize| i32::from_le_bytes(memory[at..at + 4].try_into().unwrap());
+    let payload = i64::from_le_bytes(memory[at + 8..at + 16].try_into().unwrap());
+    match word(at) {
+        TAG_NULL => Some(Object::Null),
+        TAG_INT => Some(Object::Int(payload)),
+        TAG_BOOL => Some(Object::Bool(payload != 0)),
+        TAG_STR => {
+            let len = word(at + 4) as usize;
+            let start = payload as usize;
+            let bytes = &memory[start..start + len];
+            Some(Object::from(String::from_utf8_lossy(bytes).into_owned()))
+        }
+        _ => None,
+    }
+}
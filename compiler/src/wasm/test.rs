@@ -0,0 +1,162 @@
+#![cfg(test)]
+use super::runtime::read_value;
+use crate::err::CompileError;
+use crate::utils::{compile_wasm, parse};
+use monkey::eval::environment::Environment;
+use monkey::eval::evaluator::eval_program;
+use monkey::eval::object::Object;
+use wasmi::{Engine, Linker, Module, Store};
+
+/// Run the module that the program compiles to. Err on a trap. The value of a closure
+/// is not an object, it is listed as `fn`.
+fn run_wasm(input: &str) -> Result<String, String> {
+    let bytes = compile_wasm(input).unwrap();
+    let engine = Engine::default();
+    let module = Module::new(&engine, &bytes[..]).unwrap();
+    let mut store = Store::new(&engine, ());
+    let linker = <Linker<()>>::new(&engine);
+    let instance = linker
+        .instantiate(&mut store, &module)
+        .unwrap()
+        .start(&mut store)
+        .unwrap();
+    let main = instance.get_typed_func::<(), i32>(&store, "main").unwrap();
+    let address = main.call(&mut store, ()).map_err(|e| format!("{}", e))?;
+    let memory = instance.get_memory(&store, "memory").unwrap();
+    Ok(match read_value(memory.data(&store), address as u32) {
+        Some(obj) => format!("{}", obj),
+        None => "fn".to_string(),
+    })
+}
+
+fn run_eval(input: &str) -> Result<String, String> {
+    let program = parse(input).unwrap();
+    match eval_program(&program, &Environment::new()) {
+        Object::Error(e) => Err(e),
+        Object::Function(_) => Ok("fn".to_string()),
+        // a let statement, which is null in the module
+        Object::Ignore => Ok(format!("{}", Object::Null)),
+        obj => Ok(format!("{}", obj)),
+    }
+}
+
+/// The module must give the result of the evaluator, or trap where it gives an error.
+fn run_both(input: &str) {
+    match (run_wasm(input), run_eval(input)) {
+        (Ok(wasm), Ok(eval)) => assert_eq!(wasm, eval, "{}", input),
+        (Err(_), Err(_)) => {}
+        (wasm, eval) => panic!("{}: {:?} != {:?}", input, wasm, eval),
+    }
+}
+
+#[test]
+fn test_same_results() {
+    let inputs = [
+        "1 + 2 * 3 - 4 / 2",
+        "-(1 - 3) * 2",
+        "1 < 2 == true",
+        "3 > 4 != !false",
+        "!true == false",
+        "if (1 > 2) { 10 } else { 20 }",
+        "if (0) { 10 }",
+        "if (1) { 10 }",
+        r#"if ("") { 1 } else { 2 }"#,
+        "let x = 5; x * x",
+        "let x = 5;",
+        r#""mon" + "key""#,
+        r#"let s = "a"; s + s + "b" + s"#,
+        r#""""#,
+        "let f = fn(a, b) { a - b }; f(10, 3)",
+        "fn(x) { x }",
+        "fn(x) { return x * 2; 100 }(4)",
+        "return 5; 10",
+        "let one = fn() { 1 }; let two = fn() { one() + one() }; two()",
+        "let fib = fn(n) { if (n < 2) { n } else { fib(n - 1) + fib(n - 2) } }; fib(15)",
+        "let apply = fn(f, x) { f(f(x)) }; apply(fn(x) { x * 3 }, 2)",
+        "let f = fn(n) { let g = fn(k) { if (k == 0) { 0 } else { n + g(k - 1) } }; g(n) }; f(4)",
+        "let f = fn() { g() }; let g = fn() { 7 }; f()",
+        "let f = fn(x) { if (x > 0) { let y = x * 2; y } }; f(3) + f(4)",
+        "let f = fn(x) { if (x > 0) { let y = x * 2; } }; f(3)",
+        "let f = fn(x, x) { x }; f(1, 2)",
+        r#"let greet = fn(name) { "hello " + name }; greet("monkey")"#,
+        "let n = 0; let f = fn() { n }; let n = 1; f()",
+        "let x = 1; if (true) { let x = 2; } x",
+        "let f = fn() { let x = 1; let g = fn() { x }; let x = 2; g() }; f()",
+        "let f = fn(x) { let g = fn() { x }; let x = x + 1; g() }; f(1)",
+        "let x = 1; let f = fn() { let g = fn() { x }; let x = 2; g() }; f()",
+        "let f = fn() { let a = fn() { b() }; let b = fn() { 1 }; a() }; f()",
+        "let f = fn(n) { let g = fn() { fn() { n } }; let n = n * 2; g()() }; f(3)",
+        "let f = fn() { let g = fn() { f }; g() }; f()",
+    ];
+    for input in inputs.iter() {
+        run_both(input);
+    }
+}
+
+#[test]
+fn test_closures_outlive_their_function() {
+    // The evaluator only looks up variables in the calls that are running, so it has no
+    // result for these. The closures of the module keep the variables they capture.
+    let inout = [
+        (
+            "let adder = fn(x) { fn(y) { x + y } }; let addTwo = adder(2); addTwo(3) + adder(10)(1)",
+            "16",
+        ),
+        ("let f = fn(a) { fn(b) { fn(c) { a * b + c } } }; f(2)(3)(4)", "10"),
+    ];
+    for (input, output) in inout.iter() {
+        assert_eq!(run_wasm(input), Ok(output.to_string()), "{}", input);
+    }
+}
+
+#[test]
+fn test_same_errors() {
+    let inputs = [
+        "1 + true",
+        "-true",
+        "!1",
+        r#""a" - "b""#,
+        r#""a" == "a""#,
+        r#"1 + "a""#,
+        "true > false",
+        "let x = 1; x()",
+        "let f = fn() { g }; let y = f(); let g = 1; y",
+        "let f = fn(x) { x + true }; f(1); 5",
+        "let f = fn() { let g = fn() { y }; let a = g(); let y = 1; a }; f()",
+    ];
+    for input in inputs.iter() {
+        run_both(input);
+    }
+}
+
+#[test]
+fn test_traps() {
+    // The evaluator panics on these.
+    assert!(run_wasm("1 / 0").is_err());
+    assert!(run_wasm("let f = fn(a, b) { a }; f(1)").is_err());
+}
+
+#[test]
+fn test_memory_grows() {
+    let input = r#"
+        let repeat = fn(s, n) { if (n == 0) { "" } else { s + repeat(s, n - 1) } };
+        let f = fn(n) { if (n == 0) { 0 } else { let s = repeat("monkey", 100); f(n - 1) } };
+        f(10); repeat("ab", 3)
+    "#;
+    assert_eq!(run_wasm(input), Ok(r#""ababab""#.to_string()));
+}
+
+#[test]
+fn test_compile_errors() {
+    let errors = compile_wasm("let a = [1, 2]; let b = c; len(a)").unwrap_err();
+    let messages: Vec<String> = errors.iter().map(|e| e.as_str()).collect();
+    assert_eq!(
+        messages,
+        [
+            "cannot compile expression: [1, 2]",
+            "undefined variable: c",
+            "cannot compile builtin function: len",
+        ]
+    );
+    assert!(matches!(errors[1], CompileError::UndefinedVariable(..)));
+}
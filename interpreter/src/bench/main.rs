@@ -29,3 +29,48 @@ fn fibonacci(bench: &mut Bencher) {
 
     bench.iter(|| eval_program(&program_ast, &mut env))
 }
+
+#[bench]
+fn deep_recursion(bench: &mut Bencher) {
+    let input =
+        "let count = fn(n) { if (n == 0) { 0 } else { 1 + count(n - 1) } }; count(200);";
+    let mut env = Environment::new();
+    let mut lex = Lexer::new(&input);
+    let mut par = Parser::new(&mut lex);
+    let program_ast = par.parse_program().unwrap();
+
+    bench.iter(|| eval_program(&program_ast, &mut env))
+}
+
+fn array(len: usize) -> String {
+    let values: Vec<String> = (0..len).map(|i| i.to_string()).collect();
+    format!("[{}]", values.join(", "))
+}
+
+#[bench]
+fn array_reads(bench: &mut Bencher) {
+    let input = format!(
+        "let a = {}; let sum = fn(i) {{ if (i == 0) {{ 0 }} else {{ a[i - 1] + sum(i - 1) }} }}; sum(200);",
+        array(1000)
+    );
+    let mut env = Environment::new();
+    let mut lex = Lexer::new(&input);
+    let mut par = Parser::new(&mut lex);
+    let program_ast = par.parse_program().unwrap();
+
+    bench.iter(|| eval_program(&program_ast, &mut env))
+}
+
+#[bench]
+fn array_arguments(bench: &mut Bencher) {
+    let input = format!(
+        "let f = fn(a, n) {{ if (n == 0) {{ len(a) }} else {{ f(a, n - 1) }} }}; f({}, 200);",
+        array(1000)
+    );
+    let mut env = Environment::new();
+    let mut lex = Lexer::new(&input);
+    let mut par = Parser::new(&mut lex);
+    let program_ast = par.parse_program().unwrap();
+
+    bench.iter(|| eval_program(&program_ast, &mut env))
+}
@@ -1,5 +1,6 @@
 use crate::eval::environment::Env;
 use crate::eval::object::Object;
+use std::cell::RefCell;
 use std::collections::HashMap;
 use std::io::Write;
 
@@ -11,6 +12,18 @@ lazy_static! {
         m.insert("insert".to_string(), insert as BuiltinFn);
         m
     };
+    /// The builtins ordered by name, such that they can be referred to by index.
+    pub static ref BUILTIN_LIST: Vec<Builtin> = {
+        let mut names = BUILTINS.keys().collect::<Vec<&String>>();
+        names.sort();
+        names
+            .into_iter()
+            .map(|name| Builtin {
+                identifier: name.to_string(),
+                function: BUILTINS[name],
+            })
+            .collect()
+    };
 }
 
 pub type BuiltinFn = fn(Vec<Object>) -> Object;
@@ -36,21 +49,41 @@ pub fn len(args: Vec<Object>) -> Object {
     }
 }
 
+thread_local! {
+    // The output of print, while it is captured instead of written to stdout.
+    static CAPTURED: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// Run `f` and return what it printed, instead of writing that to stdout.
+pub fn capture_output<T>(f: impl FnOnce() -> T) -> (T, String) {
+    let outer = CAPTURED.with(|c| c.replace(Some(String::new())));
+    let result = f();
+    let output = CAPTURED.with(|c| c.replace(outer)).unwrap_or_default();
+    (result, output)
+}
+
 pub fn print(args: Vec<Object>) -> Object {
+    let mut line = String::new();
     for (i, o) in args.iter().enumerate() {
         if i > 0 {
-            print!(" ")
+            line.push(' ');
         }
         if o.get_type() == "str" {
             let s = format!("{}", o);
 
-            print!("{}", (s[1..s.len() - 1]).to_string())
+            line.push_str(&s[1..s.len() - 1])
         } else {
-            print!("{}", o);
+            line.push_str(&format!("{}", o));
         }
     }
-    print!("\n");
-    std::io::stdout().flush();
+    line.push('\n');
+    CAPTURED.with(|c| match &mut *c.borrow_mut() {
+        Some(output) => output.push_str(&line),
+        None => {
+            print!("{}", line);
+            std::io::stdout().flush();
+        }
+    });
     Object::Ignore
 }
 
@@ -68,3 +101,30 @@ pub fn insert(args: Vec<Object>) -> Object {
         _ => Object::new_error("not supported"),
     }
 }
+
+/// Call a method on an object. Shared by the evaluator and the vm.
+pub fn call_method(left: Object, method_name: &str, mut args: Vec<Object>) -> Object {
+    match lookup_method(&left, method_name) {
+        Ok(method) => {
+            args.insert(0, left);
+            method(args)
+        }
+        Err(error) => error,
+    }
+}
+
+/// Resolve the method of the receiver's type, which is called with the receiver as
+/// first argument. The method only depends on the type, so it can be cached for
+/// receivers of the same type.
+pub fn lookup_method(receiver: &Object, method_name: &str) -> Result<BuiltinFn, Object> {
+    match receiver {
+        Object::Hash(_) => match method_name {
+            "insert" => Ok(insert),
+            _ => Err(Object::Error("method not found".to_string())),
+        },
+        _ => Err(Object::Error(format!(
+            "method not found on {}",
+            receiver.get_type()
+        ))),
+    }
+}
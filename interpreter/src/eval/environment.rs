@@ -1,32 +1,50 @@
 use crate::eval::object::Object;
+use crate::parser::ast::Name;
 use fnv::FnvHashMap as HashMap;
 use std::cell::RefCell;
 use std::rc::Rc;
 
 pub type Env = Rc<RefCell<Environment>>;
 
+/// The variables of the program or of a function call, in the slots the resolver
+/// assigned to them. Slots of variables that are not defined yet are empty.
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub struct Environment {
-    // Cannot borrow identifier from the ast.
-    // In the repl the ast does not live long enough.
-    // Goes out of scope every evaluation.
-    store: HashMap<String, Object>,
+    // The variables keep their names, as the functions that are called look up the
+    // names they don't define in the environments of their callers.
+    slots: Vec<Option<(Rc<str>, Object)>>,
     // Box not needed as Rc<T> already allocates on the heap.
     outer: Option<Env>,
+    // Slots of the global names. Kept with the outermost environment, such that
+    // programs evaluated after each other, like in the repl, share their globals.
+    globals: HashMap<String, usize>,
+    // The outer environment and slot that define a name, once it has been looked up. A
+    // name is only defined in an environment while it is the innermost one in use, so
+    // names that are defined later in the outer environments cannot shadow a cached one.
+    resolved: RefCell<HashMap<String, (Env, usize)>>,
 }
 
 impl Environment {
     pub fn new() -> Rc<RefCell<Environment>> {
-        let store: HashMap<String, Object> = HashMap::default();
-        let env = Environment { store, outer: None };
+        let env = Environment {
+            slots: vec![],
+            outer: None,
+            globals: HashMap::default(),
+            resolved: RefCell::default(),
+        };
         Rc::new(RefCell::new(env))
     }
-    pub fn set(&mut self, identifier: &str, value: Object) {
-        self.store.insert(identifier.to_string(), value);
+
+    pub fn set(&mut self, identifier: &Name, value: Object) {
+        let slot = identifier.slot().expect("unresolved name");
+        if slot >= self.slots.len() {
+            self.slots.resize(slot + 1, None);
+        }
+        self.slots[slot] = Some((identifier.shared(), value));
     }
 
-    pub fn get(&self, identifier: &str) -> Option<Object> {
-        let q = self.store.get(identifier);
+    pub fn get(&self, identifier: &Name) -> Option<Object> {
+        let q = identifier.slot().and_then(|slot| self.value(slot));
 
         match q {
             Some(obj) => Some(obj.clone()),
@@ -34,20 +52,61 @@ impl Environment {
         }
     }
 
+    fn value(&self, slot: usize) -> Option<&Object> {
+        match self.slots.get(slot) {
+            Some(Some((_, obj))) => Some(obj),
+            _ => None,
+        }
+    }
+
     fn get_from_outer(&self, identifier: &str) -> Option<Object> {
-        let outer_env = match &self.outer {
-            None => return None,
-            Some(env) => env.borrow(),
+        let (holder, slot) = self.resolve(identifier)?;
+        let holder = holder.borrow();
+        holder.value(slot).cloned()
+    }
+
+    /// The outer environment that defines the name, and the slot of the name in it.
+    fn resolve(&self, identifier: &str) -> Option<(Env, usize)> {
+        if let Some((holder, slot)) = self.resolved.borrow().get(identifier) {
+            return Some((Rc::clone(holder), *slot));
+        }
+        let outer = self.outer.as_ref()?;
+        let slot = outer.borrow().defined_slot(identifier);
+        let (holder, slot) = match slot {
+            Some(slot) => (Rc::clone(outer), slot),
+            None => outer.borrow().resolve(identifier)?,
         };
-        outer_env.get(identifier)
+        self.resolved
+            .borrow_mut()
+            .insert(identifier.to_string(), (Rc::clone(&holder), slot));
+        Some((holder, slot))
+    }
+
+    /// Slot of the name, if it is defined in this environment.
+    fn defined_slot(&self, identifier: &str) -> Option<usize> {
+        let slot = match self.outer {
+            None => *self.globals.get(identifier)?,
+            // A function has few variables.
+            Some(_) => self.slots.iter().position(
+                |var| matches!(var, Some((name, _)) if &**name == identifier),
+            )?,
+        };
+        self.value(slot).map(|_| slot)
+    }
+
+    /// Slot of the global name, which is added if it is new.
+    pub fn global_slot(&mut self, name: &str) -> usize {
+        let next = self.globals.len();
+        *self.globals.entry(name.to_string()).or_insert(next)
     }
 }
 
 pub fn new_enclosed_environment(outer: &Env) -> Env {
-    let store: HashMap<String, Object> = HashMap::default();
     let env = Environment {
-        store,
+        slots: vec![],
         outer: Some(Rc::clone(outer)),
+        globals: HashMap::default(),
+        resolved: RefCell::default(),
     };
     Rc::new(RefCell::new(env))
 }
@@ -3,11 +3,13 @@ use crate::eval::{
     builtins::{len, Builtin, BuiltinFn, BUILTINS},
     environment::{new_enclosed_environment, Env},
     object::{Function, Object},
+    resolver::resolve_program,
 };
-use crate::parser::ast::{Expression, Program, Statement};
+use crate::parser::ast::{Expression, Name, Program, Statement};
 
 /// Run all statements and return last
 pub fn eval_program(program_ast: &Program, env: &Env) -> Object {
+    resolve_program(program_ast, &mut env.borrow_mut());
     let mut stmts_executed = vec![];
 
     for stmt in program_ast {
@@ -42,10 +44,10 @@ fn eval_block_stmt(block: &[Statement], env: &Env) -> Object {
 
 fn eval_stmt(stmt: &Statement, env: &Env) -> Object {
     match stmt {
-        Statement::Expr(expr) => eval_expr(expr, env),
+        Statement::Expr(expr, _) => eval_expr(expr, env),
         Statement::Block(stmts) => eval_block_stmt(stmts, env),
-        Statement::Return(expr) => Object::new_return_val(eval_expr(expr, env)),
-        Statement::Let(ident, expr) => eval_let_stmt(ident, expr, env),
+        Statement::Return(expr, _) => Object::new_return_val(eval_expr(expr, env)),
+        Statement::Let(ident, expr, _) => eval_let_stmt(ident, expr, env),
         _ => Object::Null,
     }
 }
@@ -80,7 +82,7 @@ fn eval_expr(expr: &Expression, env: &Env) -> Object {
             function: fn_literal,
             args,
         } => eval_call_expr(fn_literal, args, env),
-        Expression::StringLiteral(s) => Object::String(s.clone()),
+        Expression::StringLiteral(s) => Object::from(&s[..]),
         Expression::ArrayLiteral(expressions) => eval_array_literal(expressions, env),
         Expression::IndexExpr { left, index } => eval_index_expr(left, index, env),
         Expression::HashLiteral { keys, values } => eval_hash_literal(keys, values, env),
@@ -93,7 +95,8 @@ fn eval_expr(expr: &Expression, env: &Env) -> Object {
     }
 }
 
-fn eval_prefix_expr(operator: &str, right: &Object) -> Object {
+/// Apply a prefix operator. Shared with the runtime of transpiled programs.
+pub fn eval_prefix_expr(operator: &str, right: &Object) -> Object {
     match operator {
         "!" => eval_bang_operator_expr(right),
         "-" => eval_minus_prefix_expr(right),
@@ -115,7 +118,8 @@ fn eval_minus_prefix_expr(right: &Object) -> Object {
     }
 }
 
-fn eval_infix_expr(operator: &str, left: &Object, right: &Object) -> Object {
+/// Apply an infix operator. Shared with the runtime of transpiled programs.
+pub fn eval_infix_expr(operator: &str, left: &Object, right: &Object) -> Object {
     match (left, right) {
         (Object::Int(l), Object::Int(r)) => eval_int_infix_expr(operator, l, r),
         (Object::Bool(l), Object::Bool(r)) => eval_bool_infix_expr(operator, l, r),
@@ -153,7 +157,7 @@ fn eval_bool_infix_expr(operator: &str, left: &bool, right: &bool) -> Object {
 
 fn eval_str_infix_expr(operator: &str, left: &str, right: &str) -> Object {
     match operator {
-        "+" => Object::String(format!("{}{}", left, right)),
+        "+" => Object::from(format!("{}{}", left, right)),
         op => Object::new_error(&format!("unknown operator: str {} str", op)),
     }
 }
@@ -184,7 +188,7 @@ pub fn is_truthy(condition: &Object) -> bool {
     }
 }
 
-fn eval_let_stmt(identifier: &str, expr: &Expression, env: &Env) -> Object {
+fn eval_let_stmt(identifier: &Name, expr: &Expression, env: &Env) -> Object {
     let evaluated = eval_expr(expr, env);
     if let Object::Error(_) = evaluated {
         return evaluated;
@@ -195,7 +199,7 @@ fn eval_let_stmt(identifier: &str, expr: &Expression, env: &Env) -> Object {
     Object::Ignore
 }
 
-fn eval_identifier(identifier: &str, env: &Env) -> Object {
+fn eval_identifier(identifier: &Name, env: &Env) -> Object {
     let env = env.borrow();
 
     let val = env.get(identifier);
@@ -203,12 +207,12 @@ fn eval_identifier(identifier: &str, env: &Env) -> Object {
     if val.is_some() {
         return val.unwrap();
     }
-    let builtin = BUILTINS.get(identifier);
+    let builtin = BUILTINS.get(&identifier[..]);
 
     if builtin.is_none() {
         return Object::new_error(&format!("identifier not found a: {}", identifier));
     }
-    Object::new_builtin(identifier, *builtin.unwrap())
+    Object::new_builtin(&identifier[..], *builtin.unwrap())
 }
 
 fn eval_call_expr(function: &Expression, args: &[Expression], env: &Env) -> Object {
@@ -249,7 +253,6 @@ fn eval_expressions(exprs: &[Expression], env: &Env) -> Vec<Object> {
 }
 
 fn apply_function(f: &Function, args: Vec<Object>, env: &Env) -> Object {
-    //    if let Object::Function(f) = &func {
     let env = create_function_env(f, args, env);
     let evaluated = eval_stmt(&f.body, &env);
 
@@ -283,29 +286,7 @@ fn eval_index_expr(left: &Expression, index: &Expression, env: &Env) -> Object {
     let index = eval_expr(index, env);
 
     let obj = eval_expr(left, env);
-    match &obj {
-        Object::Hash(map) => obj.get_hash_value(index),
-        Object::Array(arr) => index_array(obj, index),
-        _ => {
-            return Object::new_error(&format!(
-                "index operator `{}` not supported on: {}",
-                index.get_type(),
-                obj.get_type()
-            ))
-        }
-    }
-}
-
-fn index_array(array: Object, index: Object) -> Object {
-    match index {
-        // Already know that object is an array.
-        Object::Int(i) => array.index_array(i),
-        _ => Object::new_error(&format!(
-            "index operator `{}` not supported on: {}",
-            index.get_type(),
-            array.get_type()
-        )),
-    }
+    obj.index(index)
 }
 
 fn eval_hash_literal(keys: &[Expression], values: &[Expression], env: &Env) -> Object {
@@ -351,16 +332,11 @@ fn call_hash_methods(
         _ => return Object::Error("not a valid method name".to_string()),
     };
 
-    let mut args = eval_expressions(args, env);
+    let args = eval_expressions(args, env);
     if args.len() == 1 {
         if let Object::Error(_) = args[0] {
             return args[0].clone();
         }
     }
-    args.insert(0, left);
-
-    match method_name {
-        "insert" => builtins::insert(args),
-        _ => Object::Error("method not found".to_string()),
-    }
+    builtins::call_method(left, method_name, args)
 }
@@ -1,21 +1,90 @@
 use crate::eval::builtins::{Builtin, BuiltinFn};
 use crate::eval::environment::Env;
 use crate::format;
+use crate::lexer::token::Span;
 use crate::parser::ast::{Expression, Statement};
 use std::borrow::Cow;
 use std::cell::RefCell;
 use std::collections::HashMap;
 use std::fmt;
 use std::hash::{Hash, Hasher};
+use std::ops::Range;
 use std::rc::Rc;
 
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub struct Function {
-    pub parameters: Vec<Expression>, // Identifier
-    pub body: Statement,             // Blockstmt
+    pub parameters: Rc<Vec<Expression>>, // Identifier, shared with the function literal
+    pub body: Rc<Statement>,             // Blockstmt
     env: Env,
 }
 
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct CompiledFunction {
+    pub instructions: Vec<u8>,
+    pub num_locals: usize,
+    pub num_parameters: usize,
+    pub line_table: LineTable,
+}
+
+/// Maps the instruction offsets of compiled code back to the source they were compiled
+/// from. An entry holds from its offset up to the offset of the next entry.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct LineTable {
+    entries: Vec<(usize, Span)>, // (instruction offset, location in the source)
+}
+
+impl LineTable {
+    pub fn new() -> LineTable {
+        LineTable::default()
+    }
+
+    /// Record that the instructions from `offset` on are compiled from `span`.
+    /// Offsets must be added in increasing order.
+    pub fn add(&mut self, offset: usize, span: Span) {
+        if self.entries.last().map(|(_, s)| *s) != Some(span) {
+            self.entries.push((offset, span));
+        }
+    }
+
+    /// Forget the locations of the instructions from `offset` on.
+    pub fn truncate(&mut self, offset: usize) {
+        self.entries.retain(|(o, _)| *o < offset);
+    }
+
+    /// Location in the source of the instruction at `offset`.
+    pub fn span_at(&self, offset: usize) -> Option<Span> {
+        let idx = self.entries.partition_point(|(o, _)| *o <= offset);
+        idx.checked_sub(1).map(|i| self.entries[i].1)
+    }
+
+    pub fn line_at(&self, offset: usize) -> Option<usize> {
+        self.span_at(offset).map(|span| span.line)
+    }
+
+    /// The offset ranges and their locations for instructions of length `len`.
+    pub fn ranges(&self, len: usize) -> impl Iterator<Item = (Range<usize>, Span)> + '_ {
+        self.entries
+            .iter()
+            .enumerate()
+            .map(move |(i, (start, span))| {
+                let end = self.entries.get(i + 1).map_or(len, |(o, _)| *o);
+                (*start..end, *span)
+            })
+    }
+
+    pub fn entries(&self) -> &[(usize, Span)] {
+        &self.entries
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
 #[derive(Debug, Clone, Eq)]
 pub enum Object {
     Int(i64),
@@ -24,11 +93,12 @@ pub enum Object {
     ReturnValue(Box<Object>),
     Error(String),
     Function(Function),
-    String(String),
+    // Strings and arrays are immutable, so copies of them share their contents.
+    String(Rc<str>),
     Builtin(Builtin),
-    Array(Box<Vec<Object>>),
+    Array(Rc<Vec<Object>>),
     Hash(Rc<RefCell<HashMap<Object, Object>>>),
-    CompiledFunction(Vec<u8>),
+    CompiledFunction(Rc<CompiledFunction>), // shared by the constants pool and the vm
     Ignore,
 }
 
@@ -79,13 +149,13 @@ impl Object {
     }
 
     pub fn new_function(
-        parameters: &Vec<Expression>,
-        body: &Statement,
+        parameters: &Rc<Vec<Expression>>,
+        body: &Rc<Statement>,
         env: &Env,
     ) -> Object {
         Object::Function(Function {
-            parameters: parameters.clone(),
-            body: body.clone(),
+            parameters: Rc::clone(parameters),
+            body: Rc::clone(body),
             env: Rc::clone(env),
         })
     }
@@ -98,8 +168,21 @@ impl Object {
         Object::Builtin(builtin)
     }
 
+    pub fn new_compiled_function(
+        instructions: Vec<u8>,
+        num_locals: usize,
+        num_parameters: usize,
+    ) -> Object {
+        Object::CompiledFunction(Rc::new(CompiledFunction {
+            instructions,
+            num_locals,
+            num_parameters,
+            line_table: LineTable::new(),
+        }))
+    }
+
     pub fn new_array(values: Vec<Object>) -> Object {
-        Object::Array(Box::new(values))
+        Object::Array(Rc::new(values))
     }
 
     pub fn index_array(&self, index: i64) -> Object {
@@ -129,6 +212,19 @@ impl Object {
         };
         a[i].clone()
     }
+    /// Index an array or a hash. Shared by the evaluator and the vm.
+    pub fn index(&self, index: Object) -> Object {
+        match (self, &index) {
+            (Object::Hash(_), _) => self.get_hash_value(index),
+            (Object::Array(_), Object::Int(i)) => self.index_array(*i),
+            _ => Object::new_error(&format!(
+                "index operator `{}` not supported on: {}",
+                index.get_type(),
+                self.get_type()
+            )),
+        }
+    }
+
     pub fn new_hash(keys: Vec<Object>, values: Vec<Object>) -> Object {
         let mut map = HashMap::new();
         for (k, v) in keys.into_iter().zip(values) {
@@ -235,12 +331,12 @@ impl From<bool> for Object {
 
 impl From<&str> for Object {
     fn from(s: &str) -> Object {
-        Object::String(s.to_string())
+        Object::String(Rc::from(s))
     }
 }
 
 impl From<String> for Object {
     fn from(s: String) -> Object {
-        Object::String(s)
+        Object::String(Rc::from(s))
     }
 }
@@ -0,0 +1,203 @@
+//! Resolves identifiers to the slots that hold their values, such that the evaluator
+//! doesn't have to look up their names.
+//!
+//! A function sees the variables of the code that calls it, so only the parameters and
+//! `let` names of the function itself have a slot in the environment of its call. The
+//! code outside of functions has the globals, which are resolved also when they are
+//! never defined, in which case the evaluator looks for a builtin of that name. Other
+//! names are looked up in the environments of the callers.
+//!
+//! A slot is empty until its variable is defined, e.g. before the `let` of the name or
+//! in a call with fewer arguments than parameters. The name is then looked up in the
+//! callers too.
+use crate::eval::environment::Environment;
+use crate::parser::ast::{Expression, Name, Program, Statement};
+use fnv::FnvHashMap as HashMap;
+
+/// The names defined in a function and their slots.
+#[derive(Default)]
+struct Scope {
+    names: HashMap<String, usize>,
+}
+
+impl Scope {
+    fn define(&mut self, name: &str) {
+        let next = self.names.len();
+        self.names.entry(name.to_string()).or_insert(next);
+    }
+
+    /// Define the names of the `let` statements in `stmt`, but not the ones of the
+    /// functions in it.
+    fn define_stmt(&mut self, stmt: &Statement) {
+        match stmt {
+            Statement::Let(name, expr, _) => {
+                self.define(name);
+                self.define_expr(expr);
+            }
+            Statement::Return(expr, _) | Statement::Expr(expr, _) => {
+                self.define_expr(expr)
+            }
+            Statement::Block(stmts) => {
+                for stmt in stmts.iter() {
+                    self.define_stmt(stmt);
+                }
+            }
+        }
+    }
+
+    fn define_expr(&mut self, expr: &Expression) {
+        match expr {
+            Expression::Prefix { expr, .. } => self.define_expr(expr),
+            Expression::Infix { left, right, .. }
+            | Expression::IndexExpr { left, index: right } => {
+                self.define_expr(left);
+                self.define_expr(right);
+            }
+            Expression::IfExpression {
+                condition,
+                consequence,
+                alternative,
+            } => {
+                self.define_expr(condition);
+                self.define_stmt(consequence);
+                if let Some(alternative) = alternative {
+                    self.define_stmt(alternative);
+                }
+            }
+            Expression::CallExpr { function, args } => {
+                self.define_expr(function);
+                self.define_exprs(args);
+            }
+            Expression::ArrayLiteral(exprs) => self.define_exprs(exprs),
+            Expression::HashLiteral { keys, values } => {
+                self.define_exprs(keys);
+                self.define_exprs(values);
+            }
+            Expression::Method { left, args, .. } => {
+                self.define_expr(left);
+                self.define_exprs(args);
+            }
+            Expression::FunctionLiteral { .. }
+            | Expression::Identifier(_)
+            | Expression::IntegerLiteral(_)
+            | Expression::Bool(_)
+            | Expression::StringLiteral(_)
+            | Expression::Some => {}
+        }
+    }
+
+    fn define_exprs(&mut self, exprs: &[Expression]) {
+        for expr in exprs {
+            self.define_expr(expr);
+        }
+    }
+}
+
+struct Resolver<'env> {
+    globals: &'env mut Environment,
+    scope: Option<Scope>, // the function around the code that is being resolved
+}
+
+/// Resolve the program, which is evaluated in the global environment `env`.
+pub fn resolve_program(program: &Program, env: &mut Environment) {
+    let mut resolver = Resolver {
+        globals: env,
+        scope: None,
+    };
+    for stmt in program {
+        resolver.resolve_stmt(stmt);
+    }
+}
+
+impl Resolver<'_> {
+    fn resolve_name(&mut self, name: &Name) {
+        let slot = match &self.scope {
+            Some(scope) => scope.names.get(&name[..]).copied(),
+            None => Some(self.globals.global_slot(name)),
+        };
+        if let Some(slot) = slot {
+            name.resolve(slot);
+        }
+    }
+
+    fn resolve_stmt(&mut self, stmt: &Statement) {
+        match stmt {
+            Statement::Let(name, expr, _) => {
+                self.resolve_expr(expr);
+                self.resolve_name(name);
+            }
+            Statement::Return(expr, _) | Statement::Expr(expr, _) => {
+                self.resolve_expr(expr)
+            }
+            Statement::Block(stmts) => {
+                for stmt in stmts.iter() {
+                    self.resolve_stmt(stmt);
+                }
+            }
+        }
+    }
+
+    fn resolve_expr(&mut self, expr: &Expression) {
+        match expr {
+            Expression::Identifier(name) => self.resolve_name(name),
+            Expression::Prefix { expr, .. } => self.resolve_expr(expr),
+            Expression::Infix { left, right, .. } => {
+                self.resolve_expr(left);
+                self.resolve_expr(right);
+            }
+            Expression::IfExpression {
+                condition,
+                consequence,
+                alternative,
+            } => {
+                self.resolve_expr(condition);
+                self.resolve_stmt(consequence);
+                if let Some(alternative) = alternative {
+                    self.resolve_stmt(alternative);
+                }
+            }
+            Expression::FunctionLiteral { parameters, body } => {
+                // Parameters with the same name share a slot.
+                let mut scope = Scope::default();
+                for param in parameters.iter() {
+                    if let Expression::Identifier(name) = param {
+                        scope.define(name);
+                    }
+                }
+                scope.define_stmt(body);
+                let outer = self.scope.replace(scope);
+                self.resolve_exprs(parameters);
+                self.resolve_stmt(body);
+                self.scope = outer;
+            }
+            Expression::CallExpr { function, args } => {
+                self.resolve_expr(function);
+                self.resolve_exprs(args);
+            }
+            Expression::ArrayLiteral(exprs) => self.resolve_exprs(exprs),
+            Expression::IndexExpr { left, index } => {
+                self.resolve_expr(left);
+                self.resolve_expr(index);
+            }
+            Expression::HashLiteral { keys, values } => {
+                self.resolve_exprs(keys);
+                self.resolve_exprs(values);
+            }
+            // The method name is not a variable.
+            Expression::Method { left, args, .. } => {
+                self.resolve_expr(left);
+                self.resolve_exprs(args);
+            }
+            Expression::IntegerLiteral(_)
+            | Expression::Bool(_)
+            | Expression::StringLiteral(_)
+            | Expression::Some => {}
+        }
+    }
+
+    fn resolve_exprs(&mut self, exprs: &[Expression]) {
+        for expr in exprs {
+            self.resolve_expr(expr);
+        }
+    }
+}
@@ -1,9 +1,10 @@
-use crate::lexer::token::{Token, TokenType, KEYWORDS};
+use crate::lexer::token::{Span, Token, TokenType, KEYWORDS};
 
 pub struct Lexer<'a> {
     input: &'a [u8],
     position: usize,
     ch: u8,
+    line: usize,
 }
 
 impl<'a> Lexer<'a> {
@@ -12,6 +13,7 @@ impl<'a> Lexer<'a> {
             input: input.as_bytes(),
             position: 0,
             ch: 0,
+            line: 1,
         };
 
         lex.ch = lex.input[lex.position];
@@ -23,6 +25,9 @@ impl<'a> Lexer<'a> {
     }
 
     fn read_next_char(&mut self) {
+        if self.ch == b'\n' {
+            self.line += 1;
+        }
         // Reads the next character w.r.t. current position.
         if self.read_position() >= self.input.len() {
             self.ch = 0
@@ -33,9 +38,17 @@ impl<'a> Lexer<'a> {
     }
 
     pub fn next_token(&mut self) -> Token {
+        self.skip_whitespace();
+        let start = self.position;
+        let line = self.line;
+        let mut token = self.read_token();
+        token.span = Span::new(start, self.position, line);
+        token
+    }
+
+    fn read_token(&mut self) -> Token {
         use TokenType::*;
 
-        self.skip_whitespace();
         let token = match self.ch as char {
             '=' => {
                 if self.peek_next_char() as char == '=' {
@@ -142,6 +155,7 @@ fn new_token(token_type: TokenType, ch: Literal) -> Token {
     Token {
         type_: token_type,
         literal,
+        span: Span::default(),
     }
 }
 
@@ -230,11 +244,29 @@ mod test {
 
         let mut lex = Lexer::new(input);
         for (type_, literal) in valid.iter() {
-            let t = Token {
-                type_: *type_,
-                literal: literal.to_string(),
-            };
-            assert_eq!(lex.next_token(), t)
+            let t = lex.next_token();
+            assert_eq!(t.type_, *type_);
+            assert_eq!(t.literal, *literal);
+        }
+    }
+
+    #[test]
+    fn test_span() {
+        let input = "let five = 5;\nfive + \"ten\";";
+        let valid = [
+            Span::new(0, 3, 1),
+            Span::new(4, 8, 1),
+            Span::new(9, 10, 1),
+            Span::new(11, 12, 1),
+            Span::new(12, 13, 1),
+            Span::new(14, 18, 2),
+            Span::new(19, 20, 2),
+            Span::new(21, 26, 2),
+            Span::new(26, 27, 2),
+        ];
+        let mut lex = Lexer::new(input);
+        for span in valid.iter() {
+            assert_eq!(lex.next_token().span, *span);
         }
     }
 }
@@ -36,10 +36,25 @@ pub enum TokenType {
     Dot,      // .
 }
 
+/// Location in the source code. `start` and `end` are byte offsets, `line` starts at 1.
+#[derive(Debug, Clone, Copy, Default, PartialOrd, PartialEq, Eq, Hash)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+    pub line: usize,
+}
+
+impl Span {
+    pub fn new(start: usize, end: usize, line: usize) -> Span {
+        Span { start, end, line }
+    }
+}
+
 #[derive(Debug, PartialEq, Clone)]
 pub struct Token {
     pub type_: TokenType,
     pub literal: String,
+    pub span: Span,
 }
 use TokenType::*;
 lazy_static! {
@@ -19,6 +19,7 @@ pub mod eval {
     pub mod environment;
     pub mod evaluator;
     pub mod object;
+    pub mod resolver;
 }
 pub mod parser {
     pub mod ast;
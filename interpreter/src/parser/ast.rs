@@ -1,16 +1,88 @@
 use super::parser::ParseResult;
 use crate::format;
-use crate::lexer::token::Token;
+use crate::lexer::token::{Span, Token};
+use std::cell::Cell;
+use std::cmp::Ordering;
 use std::collections::HashMap;
 use std::fmt;
+use std::ops::Deref;
+use std::rc::Rc;
 
 pub type Program = Vec<Statement>;
 
+/// An identifier in the source. The resolver stores the slot of the variable if it is
+/// one of the function the identifier is in, or a global in code outside of functions.
+#[derive(Debug, Clone)]
+pub struct Name {
+    name: Rc<str>,
+    slot: Cell<Option<usize>>,
+}
+
+impl Name {
+    /// Slot of the variable, if the name is resolved.
+    pub fn slot(&self) -> Option<usize> {
+        self.slot.get()
+    }
+
+    pub fn resolve(&self, slot: usize) {
+        self.slot.set(Some(slot));
+    }
+
+    /// The name, shared with the identifier.
+    pub fn shared(&self) -> Rc<str> {
+        Rc::clone(&self.name)
+    }
+}
+
+impl From<String> for Name {
+    fn from(name: String) -> Name {
+        Name {
+            name: name.into(),
+            slot: Cell::new(None),
+        }
+    }
+}
+
+impl From<&str> for Name {
+    fn from(name: &str) -> Name {
+        Name::from(name.to_string())
+    }
+}
+
+impl Deref for Name {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.name
+    }
+}
+
+impl fmt::Display for Name {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.name)
+    }
+}
+
+// Names are the same regardless of how they are resolved.
+impl PartialEq for Name {
+    fn eq(&self, other: &Name) -> bool {
+        self.name == other.name
+    }
+}
+
+impl Eq for Name {}
+
+impl PartialOrd for Name {
+    fn partial_cmp(&self, other: &Name) -> Option<Ordering> {
+        self.name.partial_cmp(&other.name)
+    }
+}
+
 #[derive(Debug, PartialOrd, PartialEq, Clone, Eq)]
 pub enum Statement {
-    Let(String, Expression), // identifier, expr
-    Return(Expression),
-    Expr(Expression),
+    Let(Name, Expression, Span), // identifier, expr
+    Return(Expression, Span),
+    Expr(Expression, Span),
     Block(Box<Vec<Statement>>), // other statements
 }
 
@@ -18,14 +90,24 @@ impl Statement {
     pub fn new_block(statements: Vec<Statement>) -> ParseResult<Statement> {
         Ok(Statement::Block(Box::new(statements)))
     }
+
+    /// Location of the statement in the source. Blocks don't have a location.
+    pub fn span(&self) -> Option<Span> {
+        match self {
+            Statement::Let(_, _, span)
+            | Statement::Return(_, span)
+            | Statement::Expr(_, span) => Some(*span),
+            Statement::Block(_) => None,
+        }
+    }
 }
 
 impl std::fmt::Display for Statement {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Statement::Let(ident, e) => write!(f, "let {} = {};", ident, e),
-            Statement::Return(e) => write!(f, "return {}", e),
-            Statement::Expr(e) => write!(f, "{}", e),
+            Statement::Let(ident, e, _) => write!(f, "let {} = {};", ident, e),
+            Statement::Return(e, _) => write!(f, "return {}", e),
+            Statement::Expr(e, _) => write!(f, "{}", e),
             Statement::Block(stmts) => f.write_str(&format::fmt_block(stmts)),
             _ => f.write_str("not implemented yet"),
         }
@@ -34,7 +116,7 @@ impl std::fmt::Display for Statement {
 
 #[derive(Debug, PartialOrd, PartialEq, Clone, Eq)]
 pub enum Expression {
-    Identifier(String),
+    Identifier(Name),
     IntegerLiteral(i64),
     Prefix {
         operator: String,
@@ -52,8 +134,8 @@ pub enum Expression {
         alternative: Option<Box<Statement>>,
     },
     FunctionLiteral {
-        parameters: Box<Vec<Expression>>, // expression::identifier
-        body: Box<Statement>,             // statement::block
+        parameters: Rc<Vec<Expression>>, // expression::identifier
+        body: Rc<Statement>,             // statement::block
     },
     CallExpr {
         function: Box<Expression>, // FunctionLiteral
@@ -127,7 +209,7 @@ impl fmt::Display for Expression {
 
 impl Expression {
     pub fn new_identifier(tkn: &Token) -> ParseResult<Expression> {
-        Ok(Expression::Identifier(tkn.literal.to_string()))
+        Ok(Expression::Identifier(Name::from(tkn.literal.to_string())))
     }
 
     pub fn new_integer_literal(tkn: &Token) -> ParseResult<Expression> {
@@ -179,8 +261,8 @@ impl Expression {
         body: Statement,
     ) -> ParseResult<Expression> {
         let expr = Expression::FunctionLiteral {
-            parameters: Box::new(params),
-            body: Box::new(body),
+            parameters: Rc::new(params),
+            body: Rc::new(body),
         };
         Ok(expr)
     }
@@ -2,7 +2,7 @@ use crate::err::ParserError;
 use crate::err::ParserError::Expected;
 use crate::eval::object::Object;
 use crate::lexer::lexer::Lexer;
-use crate::lexer::token::{Token, TokenType};
+use crate::lexer::token::{Span, Token, TokenType};
 use crate::parser::ast::*;
 use std::collections::{HashMap, HashSet};
 
@@ -159,11 +159,17 @@ impl<'a> Parser<'a> {
         &self.current_token.literal
     }
 
+    /// Span from the `start` token up to and including the current token.
+    fn span_from(&self, start: Span) -> Span {
+        Span::new(start.start, self.current_token.span.end, start.line)
+    }
+
     fn parse_let_stmnt(&mut self) -> ParseResult<Statement> {
+        let start = self.current_token.span;
         if !self.expect_and_consume_token(TokenType::Identifier) {
             return Err(ParserError::IdentifierExpected);
         };
-        let ident = self.current_literal().to_string();
+        let ident = Name::from(self.current_literal());
 
         if !self.expect_and_consume_token(TokenType::Assign) {
             return Err(ParserError::AssignmentExpected(
@@ -174,30 +180,32 @@ impl<'a> Parser<'a> {
 
         let value = self.parse_expr(Precedence::Lowest)?;
 
-        let stmt = Statement::Let(ident, value);
         self.expect_and_consume_token(TokenType::Semicolon);
+        let stmt = Statement::Let(ident, value, self.span_from(start));
 
         Ok(stmt)
     }
 
     fn parse_return_stmnt(&mut self) -> ParseResult<Statement> {
+        let start = self.current_token.span;
         self.next_token();
 
         let return_val = self.parse_expr(Precedence::Lowest)?;
         self.expect_and_consume_token(TokenType::Semicolon);
-        let stmt = Statement::Return(return_val);
+        let stmt = Statement::Return(return_val, self.span_from(start));
         Ok(stmt)
     }
 
     /// The heart of the parser
     /// Read chapter 2.8 for an explanation.
     fn parse_expression_stmnt(&mut self) -> ParseResult<Statement> {
+        let start = self.current_token.span;
         let expr = self.parse_expr(Precedence::Lowest)?;
 
         while self.peek_tkn_eq(TokenType::Semicolon) {
             self.next_token()
         }
-        let stmt = Statement::Expr(expr);
+        let stmt = Statement::Expr(expr, self.span_from(start));
         Ok(stmt)
     }
 
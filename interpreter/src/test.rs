@@ -1,6 +1,7 @@
 use crate::err::ParserError;
 use crate::eval::{environment::Environment, object::Object};
 use crate::lexer::lexer::Lexer;
+use crate::lexer::token::Span;
 use crate::parser::ast::*;
 use crate::parser::parser::*;
 
@@ -41,7 +42,7 @@ mod parser_test {
         let input = "foobar;";
         let parsed = parse_program(&input);
         assert_eq!(
-            Statement::Expr(Expression::Identifier("foobar".to_string())),
+            Statement::Expr(Expression::Identifier("foobar".into()), Span::new(0, 7, 1)),
             parsed.unwrap()[0]
         );
     }
@@ -51,7 +52,7 @@ mod parser_test {
         let input = "5;";
         let parsed = parse_program(&input);
         assert_eq!(
-            Statement::Expr(Expression::IntegerLiteral(5 as i64)),
+            Statement::Expr(Expression::IntegerLiteral(5 as i64), Span::new(0, 2, 1)),
             parsed.unwrap()[0]
         );
     }
@@ -61,10 +62,13 @@ mod parser_test {
         let input = "-5;";
         let parsed = parse_program(&input);
         assert_eq!(
-            Statement::Expr(Expression::Prefix {
-                operator: "-".to_string(),
-                expr: Box::new(Expression::IntegerLiteral(5 as i64))
-            }),
+            Statement::Expr(
+                Expression::Prefix {
+                    operator: "-".to_string(),
+                    expr: Box::new(Expression::IntegerLiteral(5 as i64))
+                },
+                Span::new(0, 3, 1)
+            ),
             parsed.unwrap()[0]
         );
     }
@@ -171,6 +175,28 @@ mod parser_test {
         );
     }
 
+    #[test]
+    fn test_stmt_span() {
+        let input = "let a = 1;
+return a;
+a + 1
+if (a) { a }";
+        let parsed = parse_program(&input).unwrap();
+        let spans = parsed
+            .iter()
+            .map(|s| s.span().unwrap())
+            .collect::<Vec<Span>>();
+        assert_eq!(
+            spans,
+            [
+                Span::new(0, 10, 1),
+                Span::new(11, 20, 2),
+                Span::new(21, 26, 3),
+                Span::new(27, 39, 4)
+            ]
+        );
+    }
+
     #[test]
     fn test_str_lit() {
         let input = r#" "foo" "#;
@@ -183,6 +209,7 @@ mod parser_test {
 mod eval_test {
     use super::*;
     use crate::eval::evaluator::eval_program;
+    use std::rc::Rc;
 
     fn evaluated(input: &str) -> Object {
         let parsed = parse_program(input);
@@ -308,6 +335,46 @@ mod eval_test {
         }
     }
 
+    #[test]
+    fn test_lookup_after_definition() {
+        // The second call of f sees the x that g defined after the first call.
+        let input = "let x = 1;
+let f = fn() { x };
+let g = fn() { let a = f(); let x = 2; a + f() };
+g()";
+        assert_eq!(evaluated(input), Object::Int(3));
+
+        let input = "let f = fn(n) { if (n == 0) { limit } else { f(n - 1) } };
+let limit = 7;
+[f(3), f(5)]";
+        assert_eq!(format!("{}", evaluated(input)), "[7, 7]");
+    }
+
+    #[test]
+    fn test_slots() {
+        let inputs = [
+            "let f = fn(x) { let g = fn() { fn() { x } }; g()() }; f(4)",
+            "let x = 1; let f = fn(x) { let x = x + 1; x }; f(5) + x",
+            "let f = fn(x, x) { x }; f(1, 2)",
+            "let f = fn(x, x) { x }; f(1)",
+            "let x = 3; let f = fn(x) { x }; f()",
+            "let f = fn() { y }; let g = fn(y) { f() }; g(4)",
+            "let count = fn(n) { if (n == 0) { 0 } else { 1 + count(n - 1) } }; count(10)",
+            "let f = fn() { let a = fn() { b() }; let b = fn() { 1 }; a() }; f()",
+            "let f = fn() { let a = fn() { b }; if (true) { let b = 3 }; a() }; f()",
+            "let x = 5; let f = fn() { let y = x; let x = 1; y + x }; f()",
+        ];
+        let outputs = [4, 7, 2, 1, 3, 4, 10, 1, 3, 6];
+        for (input, output) in inputs.iter().zip(&outputs) {
+            assert_eq!(evaluated(input), Object::Int(*output), "{}", input)
+        }
+        // A function only sees the variables of its callers.
+        assert_eq!(
+            evaluated("let make = fn(x) { fn() { x } }; make(2)()"),
+            Object::new_error("identifier not found a: x")
+        );
+    }
+
     #[test]
     fn test_str_lit_eval() {
         let inputs = [
@@ -319,7 +386,7 @@ mod eval_test {
         let outputs = ["foo", "foo bar ham", "fooham", "fooham"];
         for (input, output) in inputs.iter().zip(&outputs) {
             let ev = evaluated(&input);
-            assert_eq!(ev, Object::String(output.to_string()))
+            assert_eq!(ev, Object::from(*output))
         }
     }
 
@@ -361,6 +428,15 @@ mod eval_test {
             let ev = evaluated(&input);
             assert_eq!(ev, Object::Int(*output))
         }
+
+        // Reading a variable doesn't copy the array.
+        match evaluated("let a = [1, 2, 3]; [a, a]") {
+            Object::Array(values) => match (&values[0], &values[1]) {
+                (Object::Array(a), Object::Array(b)) => assert!(Rc::ptr_eq(a, b)),
+                o => panic!("expected arrays, got {:?}", o),
+            },
+            o => panic!("expected an array, got {}", o),
+        }
     }
 
     #[test]
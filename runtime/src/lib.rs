@@ -0,0 +1,208 @@
+//! The runtime of Monkey programs that are transpiled to Rust with `monkey transpile`.
+//!
+//! Values are the objects of the evaluator and the functions of the program, which are
+//! Rust closures. The operators are the ones of the evaluator, such that a transpiled
+//! program computes the same results. Errors are returned as an `Err` that holds an
+//! `Object::Error`, so they can be passed on with `?`.
+//!
+//! Arrays, hashes and builtins only hold objects, so a function cannot be stored in
+//! them or be passed to a builtin.
+use monkey::eval::builtins::{call_method, BUILTINS};
+use monkey::eval::evaluator::{eval_infix_expr, eval_prefix_expr, is_truthy};
+pub use monkey::eval::object::Object;
+use std::cell::RefCell;
+use std::fmt;
+use std::rc::Rc;
+
+mod test;
+
+pub type Result = std::result::Result<Value, Object>;
+
+/// Body of a function. It is called with the function itself, such that it can call
+/// itself, and with the arguments.
+type Body = dyn Fn(&Value, Vec<Value>) -> Result;
+
+#[derive(Clone)]
+pub enum Value {
+    Object(Object),
+    Function(Function),
+}
+
+/// A function of the program.
+#[derive(Clone)]
+pub struct Function {
+    num_parameters: usize,
+    body: Rc<Body>,
+}
+
+impl From<Object> for Value {
+    fn from(obj: Object) -> Value {
+        Value::Object(obj)
+    }
+}
+
+impl Value {
+    /// The object of the value, or an error for a function.
+    pub fn into_object(self) -> std::result::Result<Object, Object> {
+        match self {
+            Value::Object(obj) => Ok(obj),
+            Value::Function(_) => Err(Object::new_error(
+                "a function of a transpiled program is not an object",
+            )),
+        }
+    }
+
+    /// The object that the operators of the evaluator see. The evaluator has no
+    /// operators on functions and reports them as null, so they are passed as null.
+    fn operand(&self) -> &Object {
+        match self {
+            Value::Object(obj) => obj,
+            Value::Function(_) => &Object::Null,
+        }
+    }
+}
+
+impl fmt::Debug for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Object(obj) => f.debug_tuple("Object").field(obj).finish(),
+            Value::Function(function) => f
+                .debug_struct("Function")
+                .field("num_parameters", &function.num_parameters)
+                .finish(),
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Object(obj) => write!(f, "{}", obj),
+            Value::Function(_) => f.write_str("fn"),
+        }
+    }
+}
+
+pub const NULL: Value = Value::Object(Object::Null);
+/// The value of a let statement, which the evaluator ignores.
+pub const IGNORE: Value = Value::Object(Object::Ignore);
+
+pub fn int(v: i64) -> Value {
+    Value::Object(Object::Int(v))
+}
+
+pub fn boolean(v: bool) -> Value {
+    Value::Object(Object::Bool(v))
+}
+
+pub fn string(s: &str) -> Value {
+    Value::Object(Object::from(s))
+}
+
+fn check(obj: Object) -> Result {
+    match obj {
+        Object::Error(_) => Err(obj),
+        obj => Ok(Value::Object(obj)),
+    }
+}
+
+fn objects(values: Vec<Value>) -> std::result::Result<Vec<Object>, Object> {
+    values.into_iter().map(Value::into_object).collect()
+}
+
+pub fn prefix(operator: &str, right: &Value) -> Result {
+    check(eval_prefix_expr(operator, right.operand()))
+}
+
+pub fn infix(operator: &str, left: &Value, right: &Value) -> Result {
+    check(eval_infix_expr(operator, left.operand(), right.operand()))
+}
+
+pub fn truthy(condition: &Value) -> bool {
+    match condition {
+        Value::Object(obj) => is_truthy(obj),
+        Value::Function(_) => true,
+    }
+}
+
+/// A function that is called with itself and at least `num_parameters` arguments.
+pub fn function<F>(num_parameters: usize, body: F) -> Value
+where
+    F: Fn(&Value, Vec<Value>) -> Result + 'static,
+{
+    Value::Function(Function {
+        num_parameters,
+        body: Rc::new(body),
+    })
+}
+
+pub fn call(function: &Value, args: Vec<Value>) -> Result {
+    match function {
+        Value::Function(f) if args.len() < f.num_parameters => {
+            Err(Object::new_error(&format!(
+                "wrong number of arguments: want={}, got={}",
+                f.num_parameters,
+                args.len()
+            )))
+        }
+        Value::Function(f) => (f.body)(function, args),
+        Value::Object(Object::Builtin(builtin)) => {
+            check((builtin.function)(objects(args)?))
+        }
+        _ => Err(Object::new_error("function not defined")),
+    }
+}
+
+pub fn builtin(name: &str) -> Value {
+    Value::Object(Object::new_builtin(name, BUILTINS[name]))
+}
+
+pub fn array(values: Vec<Value>) -> Result {
+    Ok(Value::Object(Object::new_array(objects(values)?)))
+}
+
+pub fn hash(keys: Vec<Value>, values: Vec<Value>) -> Result {
+    Ok(Value::Object(Object::new_hash(
+        objects(keys)?,
+        objects(values)?,
+    )))
+}
+
+pub fn index(left: &Value, index: Value) -> Result {
+    check(left.operand().index(index.into_object()?))
+}
+
+pub fn method(left: Value, name: &str, args: Vec<Value>) -> Result {
+    check(call_method(left.into_object()?, name, objects(args)?))
+}
+
+/// The globals of a program. Functions share them, so they see the globals that are set
+/// after the functions are created.
+#[derive(Debug, Clone)]
+pub struct Globals {
+    names: &'static [&'static str],
+    values: Rc<RefCell<Vec<Option<Value>>>>,
+}
+
+impl Globals {
+    pub fn new(names: &'static [&'static str]) -> Globals {
+        Globals {
+            names,
+            values: Rc::new(RefCell::new(vec![None; names.len()])),
+        }
+    }
+
+    pub fn get(&self, index: usize) -> Result {
+        match &self.values.borrow()[index] {
+            Some(value) => Ok(value.clone()),
+            None => Err(Object::new_error(&format!(
+                "identifier not found a: {}",
+                self.names[index]
+            ))),
+        }
+    }
+
+    pub fn set(&self, index: usize, value: Value) {
+        self.values.borrow_mut()[index] = Some(value);
+    }
+}
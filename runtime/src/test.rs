@@ -0,0 +1,109 @@
+#![cfg(test)]
+use super::*;
+
+fn fmt_result(result: Result) -> std::result::Result<String, String> {
+    match result {
+        Ok(value) => Ok(format!("{}", value)),
+        Err(e) => Err(format!("{}", e)),
+    }
+}
+
+#[test]
+fn test_operators() {
+    assert_eq!(
+        fmt_result(infix("+", &int(1), &int(2))),
+        Ok("3".to_string())
+    );
+    assert_eq!(
+        fmt_result(infix("+", &string("mon"), &string("key"))),
+        Ok(r#""monkey""#.to_string())
+    );
+    assert_eq!(
+        fmt_result(prefix("!", &boolean(true))),
+        Ok("false".to_string())
+    );
+    assert_eq!(
+        fmt_result(infix("+", &int(1), &boolean(true))),
+        Err("Error: type mismatch: int + bool".to_string())
+    );
+    // like in the evaluator, zero is falsy and the empty string is truthy
+    assert!(!truthy(&int(0)));
+    assert!(truthy(&string("")));
+    assert!(!truthy(&NULL));
+}
+
+#[test]
+fn test_functions() {
+    // n! with the function calling itself
+    let factorial = function(1, |this, args| {
+        let n = args[0].clone();
+        if truthy(&infix("<", &n, &int(2))?) {
+            return Ok(int(1));
+        }
+        let m = call(this, vec![infix("-", &n, &int(1))?])?;
+        infix("*", &n, &m)
+    });
+    assert_eq!(
+        fmt_result(call(&factorial, vec![int(5)])),
+        Ok("120".to_string())
+    );
+    assert_eq!(format!("{}", factorial), "fn");
+    assert!(truthy(&factorial));
+    assert_eq!(
+        fmt_result(call(&factorial, vec![])),
+        Err("Error: wrong number of arguments: want=1, got=0".to_string())
+    );
+    assert_eq!(
+        fmt_result(call(&int(1), vec![])),
+        Err("Error: function not defined".to_string())
+    );
+}
+
+#[test]
+fn test_builtins() {
+    let len = builtin("len");
+    assert_eq!(
+        fmt_result(call(&len, vec![string("four")])),
+        Ok("4".to_string())
+    );
+    assert!(call(&len, vec![int(1)]).is_err());
+
+    let map = hash(vec![int(1)], vec![string("one")]).unwrap();
+    method(map.clone(), "insert", vec![int(2), string("two")]).unwrap();
+    assert_eq!(fmt_result(index(&map, int(2))), Ok(r#""two""#.to_string()));
+    let arr = array(vec![int(1), int(2)]).unwrap();
+    assert_eq!(fmt_result(index(&arr, int(-1))), Ok("2".to_string()));
+    assert!(index(&arr, int(2)).is_err());
+}
+
+#[test]
+fn test_functions_are_not_objects() {
+    let f = function(0, |_, _| Ok(NULL));
+    let message = "Error: a function of a transpiled program is not an object";
+    assert_eq!(fmt_result(array(vec![f.clone()])), Err(message.to_string()));
+    assert_eq!(
+        fmt_result(call(&builtin("len"), vec![f.clone()])),
+        Err(message.to_string())
+    );
+    // The operators of the evaluator see a function as null.
+    assert_eq!(
+        fmt_result(infix("==", &f, &f)),
+        Err("Error: type mismatch: null == null".to_string())
+    );
+}
+
+#[test]
+fn test_globals() {
+    static NAMES: [&str; 2] = ["a", "b"];
+    let globals = Globals::new(&NAMES);
+    globals.set(0, int(1));
+    // functions share the globals
+    let shared = globals.clone();
+    shared.set(1, int(2));
+    assert_eq!(fmt_result(globals.get(1)), Ok("2".to_string()));
+    assert_eq!(fmt_result(globals.get(0)), Ok("1".to_string()));
+    assert_eq!(
+        fmt_result(Globals::new(&NAMES).get(1)),
+        Err("Error: identifier not found a: b".to_string())
+    );
+}
@@ -0,0 +1,42 @@
+//! Transpiles the programs of the corpus in `corpus/` to `$OUT_DIR/corpus.rs`, with a
+//! module per program and the list `PROGRAMS` of their names and `run` functions.
+use compiler::utils::transpile;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const CORPUS: &str = "../corpus";
+
+fn main() {
+    println!("cargo:rerun-if-changed={}", CORPUS);
+    let mut programs: Vec<PathBuf> = fs::read_dir(CORPUS)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "mnk"))
+        .collect();
+    programs.sort();
+
+    let mut out = String::new();
+    let mut list = vec![];
+    for (i, path) in programs.iter().enumerate() {
+        let name = path.file_stem().unwrap().to_string_lossy();
+        let source = fs::read_to_string(path).unwrap();
+        let module = match transpile(&source) {
+            Ok(module) => module,
+            Err(errors) => {
+                let messages: Vec<String> = errors.iter().map(|e| e.as_str()).collect();
+                panic!("{} does not transpile: {}", name, messages.join(", "))
+            }
+        };
+        out.push_str(&format!("\n// {}\npub mod program_{} {{\n", name, i));
+        out.push_str(&module);
+        out.push_str("}\n");
+        list.push(format!("    ({:?}, program_{}::run),\n", name, i));
+    }
+    out.push_str(&format!(
+        "\npub const PROGRAMS: [(&str, Run); {}] = [\n{}];\n",
+        list.len(),
+        list.concat()
+    ));
+    let dest = Path::new(&std::env::var("OUT_DIR").unwrap()).join("corpus.rs");
+    fs::write(dest, out).unwrap();
+}
@@ -0,0 +1,10 @@
+//! Runs the programs of the corpus, which `build.rs` transpiles to Rust, on the runtime.
+//! They must print the same and give the same result as on the evaluator, which is
+//! recorded in the `.out` files of the corpus.
+#![cfg(test)]
+mod corpus {
+    /// The `run` function of a transpiled program.
+    pub type Run = fn() -> runtime::Result;
+    include!(concat!(env!("OUT_DIR"), "/corpus.rs"));
+}
+mod test;
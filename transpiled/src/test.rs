@@ -0,0 +1,36 @@
+#![cfg(test)]
+use crate::corpus::{Run, PROGRAMS};
+use monkey::eval::builtins::capture_output;
+use std::fs;
+use std::panic::catch_unwind;
+
+const CORPUS: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/../corpus");
+
+/// What the program prints, followed by `=> ` and its result, `=> error` or `=> panic`,
+/// like in the `.out` files.
+fn run(program: Run) -> String {
+    let (outcome, printed) = capture_output(|| catch_unwind(program));
+    match outcome {
+        Ok(Ok(value)) => format!("{}=> {}\n", printed, value),
+        Ok(Err(_)) => format!("{}=> error\n", printed),
+        Err(_) => format!("{}=> panic\n", printed),
+    }
+}
+
+#[test]
+fn test_corpus() {
+    assert!(!PROGRAMS.is_empty(), "no programs in {}", CORPUS);
+    // All differences are reported at once.
+    let mut failures = vec![];
+    for (name, program) in PROGRAMS.iter() {
+        let expected = fs::read_to_string(format!("{}/{}.out", CORPUS, name)).unwrap();
+        let transpiled = run(*program);
+        if transpiled != expected {
+            failures.push(format!(
+                "{}: the transpiled program gives\n{}where the evaluator gives\n{}",
+                name, transpiled, expected
+            ));
+        }
+    }
+    assert!(failures.is_empty(), "\n{}", failures.join("\n"));
+}